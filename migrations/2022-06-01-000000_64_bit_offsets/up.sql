@@ -0,0 +1,33 @@
+-- SQLite can't change the type of a column, so the table is rebuilt with 64 bit offsets and totals
+-- and the existing rows are copied over
+CREATE TABLE http_subdownload_64
+(
+    id        INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    url       TEXT    NOT NULL,
+
+    "offset"  BIGINT  NOT NULL,
+    total     BIGINT  NOT NULL,
+
+    file_path TEXT    NOT NULL,
+
+    FOREIGN KEY (file_path) REFERENCES file_path (path) ON DELETE CASCADE,
+    FOREIGN KEY (url) REFERENCES url (full_text) ON DELETE CASCADE
+);
+
+INSERT INTO http_subdownload_64 (id, url, "offset", total, file_path)
+SELECT id, url, "offset", total, file_path
+FROM http_subdownload;
+
+-- dropping a table doesn't fire its triggers, so the urls and file paths stay
+DROP TABLE http_subdownload;
+ALTER TABLE http_subdownload_64 RENAME TO http_subdownload;
+
+-- the trigger went away with the old table
+CREATE TRIGGER delete_url_and_file_path_not_referenced_by_http_subdownload_trigger
+    AFTER DELETE
+    ON http_subdownload
+BEGIN
+    DELETE FROM url WHERE url.full_text NOT IN (SELECT url FROM http_subdownload);
+    DELETE FROM file_path WHERE file_path.path NOT IN (SELECT file_path FROM http_subdownload);
+END;
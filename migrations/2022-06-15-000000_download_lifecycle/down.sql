@@ -0,0 +1,51 @@
+CREATE TABLE http_subdownload_flat
+(
+    id        INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    url       TEXT    NOT NULL,
+
+    "offset"  BIGINT  NOT NULL,
+    total     BIGINT  NOT NULL,
+
+    file_path TEXT    NOT NULL,
+
+    FOREIGN KEY (file_path) REFERENCES file_path (path) ON DELETE CASCADE,
+    FOREIGN KEY (url) REFERENCES url (full_text) ON DELETE CASCADE
+);
+
+INSERT INTO http_subdownload_flat (id, url, "offset", total, file_path)
+SELECT http_subdownload.id, http_download.url, http_subdownload."offset", http_subdownload.total,
+       http_download.file_path
+FROM http_subdownload
+         JOIN http_download ON http_download.id = http_subdownload.download_id;
+
+DROP TABLE http_subdownload;
+DROP TABLE http_download;
+ALTER TABLE http_subdownload_flat RENAME TO http_subdownload;
+
+CREATE TRIGGER delete_url_and_file_path_not_referenced_by_http_subdownload_trigger
+    AFTER DELETE
+    ON http_subdownload
+BEGIN
+    DELETE FROM url WHERE url.full_text NOT IN (SELECT url FROM http_subdownload);
+    DELETE FROM file_path WHERE file_path.path NOT IN (SELECT file_path FROM http_subdownload);
+END;
+
+CREATE TABLE IF NOT EXISTS http_download
+(
+    id       INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    uri      TEXT    NOT NULL,
+    -- I might come to regret this, consider using text to prevent weird parsing errors
+    progress DOUBLE    NOT NULL,
+    path     TEXT    NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sub_http_download
+(
+    id        INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    parent_id INTEGER NOT NULL,
+    offset    INTEGER NOT NULL,
+    uri       TEXT    NOT NULL,
+    progress  DOUBLE    NOT NULL,
+    FOREIGN KEY (parent_id) REFERENCES http_download (id) ON DELETE CASCADE
+);
@@ -0,0 +1,64 @@
+-- the tables of the first attempt at a parent download were never used
+DROP TABLE IF EXISTS sub_http_download;
+DROP TABLE IF EXISTS http_download;
+
+CREATE TABLE http_download
+(
+    id           INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    url          TEXT    NOT NULL,
+    file_path    TEXT    NOT NULL,
+
+    -- one of queued, probing, running, paused, verifying, completed, failed or cancelled
+    state        TEXT    NOT NULL,
+    -- unknown until the server told us
+    total_size   BIGINT,
+    -- seconds since the unix epoch
+    created_at   BIGINT  NOT NULL,
+    completed_at BIGINT,
+    error        TEXT,
+    -- the options of the download as JSON
+    options      TEXT    NOT NULL DEFAULT '{}',
+
+    FOREIGN KEY (file_path) REFERENCES file_path (path) ON DELETE CASCADE,
+    FOREIGN KEY (url) REFERENCES url (full_text) ON DELETE CASCADE
+);
+
+-- every file being downloaded so far becomes a running download, the subdownloads always reach the
+-- end of the file so the furthest one tells us its size
+INSERT INTO http_download (url, file_path, state, total_size, created_at)
+SELECT url, file_path, 'running', MAX("offset" + total), CAST(strftime('%s', 'now') AS BIGINT)
+FROM http_subdownload
+GROUP BY url, file_path;
+
+-- the url and file path now belong to the download, the subdownloads only point to it
+CREATE TABLE http_subdownload_owned
+(
+    id          INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+
+    download_id INTEGER NOT NULL,
+
+    "offset"    BIGINT  NOT NULL,
+    total       BIGINT  NOT NULL,
+
+    FOREIGN KEY (download_id) REFERENCES http_download (id) ON DELETE CASCADE
+);
+
+INSERT INTO http_subdownload_owned (id, download_id, "offset", total)
+SELECT http_subdownload.id, http_download.id, http_subdownload."offset", http_subdownload.total
+FROM http_subdownload
+         JOIN http_download
+              ON http_download.url = http_subdownload.url
+                  AND http_download.file_path = http_subdownload.file_path;
+
+-- the trigger goes away with the old table
+DROP TABLE http_subdownload;
+ALTER TABLE http_subdownload_owned RENAME TO http_subdownload;
+
+CREATE TRIGGER delete_url_and_file_path_not_referenced_by_http_download_trigger
+    AFTER DELETE
+    ON http_download
+BEGIN
+    DELETE FROM url WHERE url.full_text NOT IN (SELECT url FROM http_download);
+    DELETE FROM file_path WHERE file_path.path NOT IN (SELECT file_path FROM http_download);
+END;
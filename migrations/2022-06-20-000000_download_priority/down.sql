@@ -0,0 +1 @@
+ALTER TABLE http_download DROP COLUMN priority;
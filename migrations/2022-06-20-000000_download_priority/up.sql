@@ -0,0 +1,2 @@
+-- queued downloads start highest priority first, then oldest first
+ALTER TABLE http_download ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+DROP INDEX http_download_history;
+
+ALTER TABLE http_download DROP COLUMN checksum;
+ALTER TABLE http_download DROP COLUMN started_at;
@@ -0,0 +1,6 @@
+-- when the download last started from scratch, so the history can tell how long it took
+ALTER TABLE http_download ADD COLUMN started_at BIGINT;
+-- sha-256 of the finished file, hex encoded
+ALTER TABLE http_download ADD COLUMN checksum TEXT;
+
+CREATE INDEX http_download_history ON http_download (state, completed_at);
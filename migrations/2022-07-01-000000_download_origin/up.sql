@@ -0,0 +1,2 @@
+-- which request source asked for the download, e.g. the api or a watched folder
+ALTER TABLE http_download ADD COLUMN origin TEXT;
@@ -0,0 +1,8 @@
+-- the items of subscribed feeds that were already enqueued, so they are only downloaded once
+CREATE TABLE feed_item
+(
+    feed     TEXT   NOT NULL,
+    item     TEXT   NOT NULL,
+    added_at BIGINT NOT NULL,
+    PRIMARY KEY (feed, item)
+);
@@ -0,0 +1,8 @@
+-- the version of each monitored url that was last downloaded, as told by the server
+CREATE TABLE monitored_url
+(
+    url           TEXT NOT NULL PRIMARY KEY,
+    etag          TEXT,
+    last_modified TEXT,
+    length        BIGINT
+);
@@ -0,0 +1,984 @@
+//! A subset of the JSON-RPC interface of aria2, so the tools written for it (AriaNg, browser
+//! extensions, scripts) can drive sulfur too. They know downloads by a gid, which is the id of
+//! the download as 16 hex digits.
+
+use super::v1::{events::EVENT_INTERVAL, status_of, DownloadStatus};
+use crate::{
+    http::SharedHttpDownloader,
+    request::http::{file_name_of, DownloadOptions, HttpRequest},
+    speed::SharedSpeeds,
+    store::{DownloadRecord, DownloadState, SharedDownloadStore, StoreError},
+};
+use axum::{
+    body::Bytes,
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+    Extension, Json,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+use url::Url;
+
+/// The aria2 release whose interface we follow, clients look at it to tell what they can call
+const ARIA2_VERSION: &str = "1.36.0";
+
+const METHODS: &[&str] = &[
+    "aria2.addUri",
+    "aria2.remove",
+    "aria2.forceRemove",
+    "aria2.pause",
+    "aria2.forcePause",
+    "aria2.pauseAll",
+    "aria2.forcePauseAll",
+    "aria2.unpause",
+    "aria2.unpauseAll",
+    "aria2.tellStatus",
+    "aria2.getUris",
+    "aria2.getFiles",
+    "aria2.tellActive",
+    "aria2.tellWaiting",
+    "aria2.tellStopped",
+    "aria2.changePosition",
+    "aria2.getOption",
+    "aria2.getGlobalOption",
+    "aria2.changeGlobalOption",
+    "aria2.getGlobalStat",
+    "aria2.purgeDownloadResult",
+    "aria2.removeDownloadResult",
+    "aria2.getVersion",
+    "aria2.saveSession",
+    "system.multicall",
+    "system.listMethods",
+    "system.listNotifications",
+];
+
+const NOTIFICATIONS: &[&str] = &[
+    "aria2.onDownloadStart",
+    "aria2.onDownloadPause",
+    "aria2.onDownloadStop",
+    "aria2.onDownloadComplete",
+    "aria2.onDownloadError",
+];
+
+/// The error of a call, the codes are the ones of JSON-RPC with aria2 using 1 for anything that
+/// went wrong with a download
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    const PARSE_ERROR: i64 = -32700;
+    const INVALID_REQUEST: i64 = -32600;
+    const METHOD_NOT_FOUND: i64 = -32601;
+    const INVALID_PARAMS: i64 = -32602;
+
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn failure(message: impl Into<String>) -> Self {
+        Self::new(1, message)
+    }
+
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(Self::INVALID_PARAMS, message)
+    }
+}
+
+impl From<StoreError> for RpcError {
+    fn from(e: StoreError) -> Self {
+        RpcError::failure(e.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+    #[serde(default)]
+    id: Value,
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+    }
+}
+
+fn gid_of(download_id: i32) -> String {
+    format!("{:016x}", download_id)
+}
+
+fn id_of(gid: &str) -> Result<i32, RpcError> {
+    i32::from_str_radix(gid, 16).map_err(|_| RpcError::failure(format!("GID {} is not valid", gid)))
+}
+
+/// The positional parameters of a call, the ones left out at the end take their default
+struct Params(Vec<Value>);
+
+impl Params {
+    fn get<T: DeserializeOwned>(&self, index: usize) -> Result<Option<T>, RpcError> {
+        match self.0.get(index) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => serde_json::from_value(value.clone())
+                .map(Some)
+                .map_err(|e| RpcError::invalid_params(format!("parameter {}: {}", index + 1, e))),
+        }
+    }
+
+    fn gid(&self, index: usize) -> Result<i32, RpcError> {
+        let gid: String = self
+            .get(index)?
+            .ok_or_else(|| RpcError::invalid_params("a gid is required"))?;
+
+        id_of(&gid)
+    }
+
+    /// Which fields of a status the caller wants, all of them if it doesn't say
+    fn keys(&self, index: usize) -> Result<Vec<String>, RpcError> {
+        Ok(self.get(index)?.unwrap_or_default())
+    }
+}
+
+/// aria2 takes either a single value or a list of them for options that can be repeated
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// The options of aria2 we have an equivalent for, the others are ignored. aria2 gives every
+/// value as a string.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct Aria2Options {
+    /// Relative to the download directory unless it is absolute
+    dir: Option<PathBuf>,
+    /// The file name, the one of the url if not given
+    out: Option<PathBuf>,
+    /// How many connections the download is split over
+    split: Option<String>,
+    /// `Name: value`
+    header: Option<OneOrMany>,
+    /// `sha-256=<hex digest>`, the only kind we can check
+    checksum: Option<String>,
+}
+
+/// `1048576`, `1024K` or `1M` as bytes
+fn parse_size(text: &str) -> Result<u64, RpcError> {
+    let text = text.trim();
+    let (number, unit) = match text.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&text[..i], 1024),
+        Some((i, 'M' | 'm')) => (&text[..i], 1024 * 1024),
+        _ => (text, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| {
+            RpcError::invalid_params(format!("{:?} isn't a size like 1024, 512K or 2M", text))
+        })
+}
+
+/// Where a window of `num` items starting at `offset` falls in `items`. A negative offset counts
+/// from the end, and lists the items from the back.
+fn page<T>(mut items: Vec<T>, offset: i64, num: usize) -> Vec<T> {
+    let offset = match offset {
+        offset if offset < 0 => {
+            items.reverse();
+            (offset.unsigned_abs() - 1) as usize
+        }
+        offset => offset as usize,
+    };
+
+    items.into_iter().skip(offset).take(num).collect()
+}
+
+fn is_active(state: DownloadState) -> bool {
+    matches!(
+        state,
+        DownloadState::Probing | DownloadState::Running | DownloadState::Verifying
+    )
+}
+
+/// What aria2 calls the state
+fn status_name(state: DownloadState) -> &'static str {
+    match state {
+        DownloadState::Queued => "waiting",
+        DownloadState::Probing | DownloadState::Running | DownloadState::Verifying => "active",
+        DownloadState::Paused => "paused",
+        DownloadState::Completed => "complete",
+        DownloadState::Failed => "error",
+        DownloadState::Cancelled => "removed",
+    }
+}
+
+/// What WebSocket clients are told when a download ends up in `state`
+fn notification_of(state: DownloadState) -> Option<&'static str> {
+    match state {
+        DownloadState::Probing | DownloadState::Running => Some("aria2.onDownloadStart"),
+        DownloadState::Paused => Some("aria2.onDownloadPause"),
+        DownloadState::Completed => Some("aria2.onDownloadComplete"),
+        DownloadState::Failed => Some("aria2.onDownloadError"),
+        DownloadState::Cancelled => Some("aria2.onDownloadStop"),
+        DownloadState::Queued | DownloadState::Verifying => None,
+    }
+}
+
+/// The notifications for every download that changed state between two looks
+fn notifications(
+    before: &BTreeMap<i32, DownloadState>,
+    after: &BTreeMap<i32, DownloadState>,
+) -> Vec<Value> {
+    after
+        .iter()
+        .filter(|(id, state)| match before.get(id) {
+            Some(previous) if previous == *state => false,
+            // probing, running and verifying all belong to the one start
+            Some(previous) => !(is_active(*previous) && is_active(**state)),
+            None => true,
+        })
+        .filter_map(|(id, state)| {
+            notification_of(*state).map(|method| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": method,
+                    "params": [{ "gid": gid_of(*id) }],
+                })
+            })
+        })
+        .collect()
+}
+
+pub struct Aria2 {
+    store: SharedDownloadStore,
+    downloader: SharedHttpDownloader,
+    download_dir: PathBuf,
+    secret: Option<String>,
+    speeds: SharedSpeeds,
+}
+
+pub type SharedAria2 = Arc<Aria2>;
+
+impl Aria2 {
+    pub fn new(
+        store: SharedDownloadStore,
+        downloader: SharedHttpDownloader,
+        download_dir: PathBuf,
+        secret: Option<String>,
+        speeds: SharedSpeeds,
+    ) -> Self {
+        Self {
+            store,
+            downloader,
+            download_dir,
+            secret,
+            speeds,
+        }
+    }
+
+    /// Answer a request body, a single call or a batch of them
+    pub async fn handle(&self, body: &[u8]) -> Value {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Array(calls)) => {
+                let mut responses = Vec::with_capacity(calls.len());
+                for call in calls {
+                    responses.push(self.answer(call).await);
+                }
+                Value::Array(responses)
+            }
+            Ok(call) => self.answer(call).await,
+            Err(e) => response(
+                Value::Null,
+                Err(RpcError::new(RpcError::PARSE_ERROR, e.to_string())),
+            ),
+        }
+    }
+
+    async fn answer(&self, call: Value) -> Value {
+        match serde_json::from_value::<RpcRequest>(call) {
+            Ok(request) => response(request.id, self.call(&request.method, request.params).await),
+            Err(e) => response(
+                Value::Null,
+                Err(RpcError::new(RpcError::INVALID_REQUEST, e.to_string())),
+            ),
+        }
+    }
+
+    async fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, RpcError> {
+        match method {
+            "system.multicall" => self.multicall(params).await,
+            _ => self.call_one(method, params).await,
+        }
+    }
+
+    /// Every call of a multicall carries its own secret, the results are in a list of their own
+    /// and the errors are not
+    async fn multicall(&self, params: Vec<Value>) -> Result<Value, RpcError> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Call {
+            method_name: String,
+            #[serde(default)]
+            params: Vec<Value>,
+        }
+
+        let calls: Vec<Call> = Params(params)
+            .get(0)?
+            .ok_or_else(|| RpcError::invalid_params("a list of calls is required"))?;
+        let mut results = Vec::with_capacity(calls.len());
+        for call in calls {
+            let result = match call.method_name.as_str() {
+                "system.multicall" => {
+                    Err(RpcError::failure("Recursive system.multicall forbidden"))
+                }
+                method => self.call_one(method, call.params).await,
+            };
+            results.push(match result {
+                Ok(result) => json!([result]),
+                Err(error) => json!(error),
+            });
+        }
+
+        Ok(Value::Array(results))
+    }
+
+    async fn call_one(&self, method: &str, params: Vec<Value>) -> Result<Value, RpcError> {
+        match method {
+            "system.listMethods" => return Ok(json!(METHODS)),
+            "system.listNotifications" => return Ok(json!(NOTIFICATIONS)),
+            _ => {}
+        }
+        let params = self.authorize(params)?;
+
+        match method {
+            "aria2.addUri" => self.add_uri(&params).await,
+            "aria2.remove" | "aria2.forceRemove" => {
+                let id = params.gid(0)?;
+                self.downloader.cancel(id).await?;
+                Ok(json!(gid_of(id)))
+            }
+            "aria2.pause" | "aria2.forcePause" => {
+                let id = params.gid(0)?;
+                self.downloader.pause(id).await?;
+                Ok(json!(gid_of(id)))
+            }
+            "aria2.unpause" => {
+                let id = params.gid(0)?;
+                self.downloader.resume(id).await?;
+                Ok(json!(gid_of(id)))
+            }
+            "aria2.pauseAll" | "aria2.forcePauseAll" => {
+                for record in self.store.downloads().await? {
+                    if record.state == DownloadState::Queued || is_active(record.state) {
+                        self.downloader.pause(record.id).await?;
+                    }
+                }
+                Ok(json!("OK"))
+            }
+            "aria2.unpauseAll" => {
+                for record in self.store.downloads().await? {
+                    if record.state == DownloadState::Paused {
+                        self.downloader.resume(record.id).await?;
+                    }
+                }
+                Ok(json!("OK"))
+            }
+            "aria2.tellStatus" => {
+                let record = self.store.download(params.gid(0)?).await?;
+                self.status(record, &params.keys(1)?).await
+            }
+            "aria2.getUris" => {
+                let record = self.store.download(params.gid(0)?).await?;
+                Ok(json!([{ "uri": record.url, "status": "used" }]))
+            }
+            "aria2.getFiles" => {
+                let record = self.store.download(params.gid(0)?).await?;
+                let status = status_of(&self.store, &self.speeds, record).await?;
+                Ok(json!([file_of(&status)]))
+            }
+            "aria2.tellActive" => {
+                let active = self
+                    .store
+                    .downloads()
+                    .await?
+                    .into_iter()
+                    .filter(|record| is_active(record.state))
+                    .collect();
+                self.statuses(active, &params.keys(0)?).await
+            }
+            "aria2.tellWaiting" => {
+                // the queue in the order it will start in, then the paused ones
+                let mut waiting = self.downloader.queue().queued().await?;
+                waiting.extend(
+                    self.store
+                        .downloads()
+                        .await?
+                        .into_iter()
+                        .filter(|record| record.state == DownloadState::Paused),
+                );
+                let (offset, num) = self.window(&params)?;
+                self.statuses(page(waiting, offset, num), &params.keys(2)?)
+                    .await
+            }
+            "aria2.tellStopped" => {
+                let stopped = self
+                    .store
+                    .downloads()
+                    .await?
+                    .into_iter()
+                    .filter(|record| record.state.is_finished())
+                    .collect();
+                let (offset, num) = self.window(&params)?;
+                self.statuses(page(stopped, offset, num), &params.keys(2)?)
+                    .await
+            }
+            "aria2.changePosition" => self.change_position(&params).await,
+            "aria2.getOption" => {
+                let record = self.store.download(params.gid(0)?).await?;
+                Ok(options_of(&record))
+            }
+            "aria2.getGlobalOption" => {
+                let limits = self.downloader.limits().await;
+                Ok(json!({
+                    "dir": self.download_dir,
+                    "max-concurrent-downloads": limits.max_concurrent.to_string(),
+                    "max-overall-download-limit": limits.rate.unwrap_or_default().to_string(),
+                }))
+            }
+            "aria2.changeGlobalOption" => self.change_global_option(&params).await,
+            "aria2.getGlobalStat" => self.global_stat().await,
+            "aria2.removeDownloadResult" => {
+                let record = self.store.download(params.gid(0)?).await?;
+                if !record.state.is_finished() {
+                    return Err(RpcError::failure(format!(
+                        "Could not remove download result of GID#{}",
+                        gid_of(record.id)
+                    )));
+                }
+                self.store.remove_download(record.id).await?;
+                Ok(json!("OK"))
+            }
+            "aria2.purgeDownloadResult" => {
+                for record in self.store.downloads().await? {
+                    if record.state.is_finished() {
+                        self.store.remove_download(record.id).await?;
+                    }
+                }
+                Ok(json!("OK"))
+            }
+            "aria2.getVersion" => Ok(json!({
+                "version": ARIA2_VERSION,
+                "enabledFeatures": ["HTTPS"],
+            })),
+            // everything is written down as it happens
+            "aria2.saveSession" => Ok(json!("OK")),
+            method => Err(RpcError::new(
+                RpcError::METHOD_NOT_FOUND,
+                format!("No such method: {}", method),
+            )),
+        }
+    }
+
+    /// Check the `token:<secret>` clients send as the first parameter, and take it out of the
+    /// way of the others
+    fn authorize(&self, mut params: Vec<Value>) -> Result<Params, RpcError> {
+        let token = match params.first().and_then(Value::as_str) {
+            Some(first) => first.strip_prefix("token:").map(str::to_string),
+            None => None,
+        };
+        if token.is_some() {
+            params.remove(0);
+        }
+
+        match &self.secret {
+            Some(secret) if token.as_ref() != Some(secret) => {
+                Err(RpcError::failure("Unauthorized"))
+            }
+            _ => Ok(Params(params)),
+        }
+    }
+
+    /// The offset and number of items asked for by the tell methods
+    fn window(&self, params: &Params) -> Result<(i64, usize), RpcError> {
+        let offset = params.get(0)?.unwrap_or_default();
+        let num = params.get(1)?.unwrap_or(usize::MAX);
+
+        Ok((offset, num))
+    }
+
+    async fn add_uri(&self, params: &Params) -> Result<Value, RpcError> {
+        let uris: Vec<Url> = params.get(0)?.unwrap_or_default();
+        // aria2 takes the others as mirrors of the same file, we only download from the first
+        let url = uris
+            .into_iter()
+            .next()
+            .ok_or_else(|| RpcError::invalid_params("at least one uri is required"))?;
+        let options: Aria2Options = params.get(1)?.unwrap_or_default();
+        let position: Option<usize> = params.get(2)?;
+
+        let request = self.request_of(url, options)?;
+        let queue = self.downloader.queue();
+        let id = queue.enqueue(&request).await?;
+        if let Some(position) = position {
+            queue.move_to(id, position).await?;
+        }
+
+        Ok(json!(gid_of(id)))
+    }
+
+    fn request_of(&self, url: Url, options: Aria2Options) -> Result<HttpRequest, RpcError> {
+        let dir = match options.dir {
+            Some(dir) => self.download_dir.join(dir),
+            None => self.download_dir.clone(),
+        };
+        let name = match options.out {
+            Some(out) => out,
+            None => file_name_of(&url).map(PathBuf::from).ok_or_else(|| {
+                RpcError::failure(format!(
+                    "Can't tell what to call the file of {}, give it an out option",
+                    url
+                ))
+            })?,
+        };
+
+        let connections = options
+            .split
+            .map(|split| split.parse::<u32>())
+            .transpose()
+            .map_err(|e| RpcError::invalid_params(format!("split: {}", e)))?;
+        let headers = match options.header {
+            None => vec![],
+            Some(OneOrMany::One(header)) => vec![header],
+            Some(OneOrMany::Many(headers)) => headers,
+        };
+        let headers = headers
+            .iter()
+            .map(|header| {
+                header
+                    .split_once(':')
+                    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| {
+                        RpcError::invalid_params(format!(
+                            "{} isn't a header like \"Name: value\"",
+                            header
+                        ))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        let checksum = options
+            .checksum
+            .map(|checksum| {
+                checksum
+                    .strip_prefix("sha-256=")
+                    .map(str::to_string)
+                    .ok_or_else(|| RpcError::failure("Only sha-256 checksums can be checked"))
+            })
+            .transpose()?;
+
+        Ok(HttpRequest {
+            url,
+            path: dir.join(name),
+            options: DownloadOptions {
+                connections,
+                headers,
+                checksum,
+                ..DownloadOptions::default()
+            },
+            origin: Some("aria2".to_string()),
+        })
+    }
+
+    /// Move a queued download, `how` says whether `pos` is from the front, the current position
+    /// or the back
+    async fn change_position(&self, params: &Params) -> Result<Value, RpcError> {
+        let id = params.gid(0)?;
+        let pos: i64 = params.get(1)?.unwrap_or_default();
+        let how: String = params.get(2)?.unwrap_or_default();
+
+        let queue = self.downloader.queue();
+        let queued = queue.queued().await?;
+        let current = queued
+            .iter()
+            .position(|record| record.id == id)
+            .ok_or_else(|| {
+                RpcError::failure(format!("GID#{} not found in the waiting queue", gid_of(id)))
+            })?;
+        let position = match how.as_str() {
+            "POS_SET" => pos,
+            "POS_CUR" => current as i64 + pos,
+            "POS_END" => queued.len() as i64 - 1 + pos,
+            how => {
+                return Err(RpcError::invalid_params(format!(
+                    "{:?} isn't one of POS_SET, POS_CUR or POS_END",
+                    how
+                )))
+            }
+        };
+
+        Ok(json!(queue.move_to(id, position.max(0) as usize).await?))
+    }
+
+    async fn change_global_option(&self, params: &Params) -> Result<Value, RpcError> {
+        let options: Map<String, Value> = params.get(0)?.unwrap_or_default();
+        let text_of = |name: &str| options.get(name).and_then(Value::as_str);
+
+        let mut limits = self.downloader.limits().await;
+        if let Some(max) = text_of("max-concurrent-downloads") {
+            limits.max_concurrent = match max.parse() {
+                Ok(max) if max > 0 => max,
+                _ => {
+                    return Err(RpcError::invalid_params(
+                        "max-concurrent-downloads has to be a number above 0",
+                    ))
+                }
+            };
+        }
+        if let Some(rate) = text_of("max-overall-download-limit") {
+            // 0 is how aria2 says unlimited
+            limits.rate = Some(parse_size(rate)?).filter(|rate| *rate > 0);
+        }
+        self.downloader.set_limits(limits).await;
+
+        Ok(json!("OK"))
+    }
+
+    async fn global_stat(&self) -> Result<Value, RpcError> {
+        let (mut speed, mut active, mut waiting, mut stopped) = (0, 0, 0, 0);
+        for record in self.store.downloads().await? {
+            match record.state {
+                state if is_active(state) => {
+                    active += 1;
+                    let status = status_of(&self.store, &self.speeds, record).await?;
+                    speed += status.speed.unwrap_or_default();
+                }
+                DownloadState::Queued | DownloadState::Paused => waiting += 1,
+                _ => stopped += 1,
+            }
+        }
+
+        Ok(json!({
+            "downloadSpeed": speed.to_string(),
+            "uploadSpeed": "0",
+            "numActive": active.to_string(),
+            "numWaiting": waiting.to_string(),
+            "numStopped": stopped.to_string(),
+            "numStoppedTotal": stopped.to_string(),
+        }))
+    }
+
+    async fn statuses(
+        &self,
+        records: Vec<DownloadRecord>,
+        keys: &[String],
+    ) -> Result<Value, RpcError> {
+        let mut statuses = Vec::with_capacity(records.len());
+        for record in records {
+            statuses.push(self.status(record, keys).await?);
+        }
+
+        Ok(Value::Array(statuses))
+    }
+
+    /// What aria2 would say about the download, only the `keys` asked for unless there are none
+    async fn status(&self, record: DownloadRecord, keys: &[String]) -> Result<Value, RpcError> {
+        let connections = match record.state {
+            DownloadState::Running => self
+                .store
+                .segments(record.id)
+                .await?
+                .iter()
+                .filter(|segment| segment.total > 0)
+                .count(),
+            _ => 0,
+        };
+        let status = status_of(&self.store, &self.speeds, record).await?;
+        let speed = status.speed.unwrap_or_default();
+        let record = &status.record;
+
+        let mut fields = json!({
+            "gid": gid_of(record.id),
+            "status": status_name(record.state),
+            "totalLength": record.total_size.unwrap_or_default().to_string(),
+            "completedLength": status.downloaded.unwrap_or_default().to_string(),
+            "uploadLength": "0",
+            "downloadSpeed": speed.to_string(),
+            "uploadSpeed": "0",
+            "connections": connections.to_string(),
+            "dir": record.file_path.parent().unwrap_or(&self.download_dir),
+            "files": [file_of(&status)],
+        });
+        if let Some(error) = &record.error {
+            fields["errorCode"] = json!("1");
+            fields["errorMessage"] = json!(error);
+        }
+
+        if let (Value::Object(fields), false) = (&mut fields, keys.is_empty()) {
+            fields.retain(|key, _| keys.contains(key));
+        }
+        Ok(fields)
+    }
+
+    /// Answer calls and tell about the downloads that changed state until the client goes away
+    pub async fn serve(self: Arc<Self>, socket: WebSocket) {
+        let (mut sender, mut receiver) = socket.split();
+        let mut ticks = tokio::time::interval(EVENT_INTERVAL);
+        let mut states: Option<BTreeMap<i32, DownloadState>> = None;
+
+        loop {
+            let messages = tokio::select! {
+                message = receiver.next() => match message {
+                    Some(Ok(Message::Text(text))) => vec![self.handle(text.as_bytes()).await],
+                    // pings are answered for us
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => vec![],
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                },
+                _ = ticks.tick() => match self.store.downloads().await {
+                    Ok(downloads) => {
+                        let now = downloads.iter().map(|record| (record.id, record.state)).collect();
+                        // the ones that were there when the client came aren't news
+                        let messages = match &states {
+                            Some(before) => notifications(before, &now),
+                            None => vec![],
+                        };
+                        states = Some(now);
+                        messages
+                    }
+                    Err(e) => {
+                        tracing::warn!("failed to look for changed downloads: {}", e);
+                        vec![]
+                    }
+                },
+            };
+
+            for message in messages {
+                if sender
+                    .send(Message::Text(message.to_string()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The single file of a download, aria2 can have more
+fn file_of(status: &DownloadStatus) -> Value {
+    let record = &status.record;
+
+    json!({
+        "index": "1",
+        "path": record.file_path,
+        "length": record.total_size.unwrap_or_default().to_string(),
+        "completedLength": status.downloaded.unwrap_or_default().to_string(),
+        "selected": "true",
+        "uris": [{ "uri": record.url, "status": "used" }],
+    })
+}
+
+/// The options of the download, as aria2 would have them
+fn options_of(record: &DownloadRecord) -> Value {
+    let mut options = Map::new();
+    if let Some(dir) = record.file_path.parent() {
+        options.insert("dir".to_string(), json!(dir));
+    }
+    if let Some(out) = record.file_path.file_name() {
+        options.insert("out".to_string(), json!(out.to_string_lossy()));
+    }
+    if let Some(connections) = record.options.connections {
+        options.insert("split".to_string(), json!(connections.to_string()));
+    }
+    if !record.options.headers.is_empty() {
+        let headers: Vec<_> = record
+            .options
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect();
+        options.insert("header".to_string(), json!(headers));
+    }
+    if let Some(checksum) = &record.options.checksum {
+        options.insert(
+            "checksum".to_string(),
+            json!(format!("sha-256={}", checksum)),
+        );
+    }
+
+    Value::Object(options)
+}
+
+/// Maps to POST /jsonrpc
+pub async fn rpc(Extension(aria2): Extension<SharedAria2>, body: Bytes) -> Json<Value> {
+    Json(aria2.handle(&body).await)
+}
+
+/// Maps to GET /jsonrpc, the same calls over a WebSocket along with notifications about the
+/// downloads
+pub async fn websocket(
+    Extension(aria2): Extension<SharedAria2>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| aria2.serve(socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::HttpDownloader, store::memory::MemoryStore};
+    use pretty_assertions::assert_eq;
+
+    fn aria2(secret: Option<&str>) -> Aria2 {
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let (downloader, _sources) = HttpDownloader::with_request_sources(store.clone());
+
+        Aria2::new(
+            store,
+            Arc::new(downloader),
+            PathBuf::from("/downloads"),
+            secret.map(str::to_string),
+            SharedSpeeds::default(),
+        )
+    }
+
+    async fn call(aria2: &Aria2, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": "1", "method": method, "params": params });
+        let response = aria2.handle(request.to_string().as_bytes()).await;
+
+        match response.get("error") {
+            Some(error) => panic!("{} failed: {}", method, error),
+            None => response["result"].clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_are_added_and_controlled_like_in_aria2() {
+        let aria2 = aria2(None);
+        let options = json!({
+            "dir": "isos",
+            "split": "4",
+            "header": ["Cookie: session=1"],
+            "checksum": "sha-256=abcd",
+            "max-tries": "5",
+        });
+
+        let gid = call(
+            &aria2,
+            "aria2.addUri",
+            json!([["https://example.com/a.iso"], options]),
+        )
+        .await;
+        assert_eq!(gid, json!("0000000000000001"));
+
+        let status = call(&aria2, "aria2.tellStatus", json!([gid, ["status", "dir"]])).await;
+        assert_eq!(
+            status,
+            json!({ "status": "waiting", "dir": "/downloads/isos" })
+        );
+        let options = call(&aria2, "aria2.getOption", json!([gid])).await;
+        assert_eq!(options["split"], "4");
+        assert_eq!(options["header"], json!(["Cookie: session=1"]));
+        assert_eq!(options["checksum"], "sha-256=abcd");
+
+        call(&aria2, "aria2.pause", json!([gid])).await;
+        let waiting = call(&aria2, "aria2.tellWaiting", json!([0, 10, ["status"]])).await;
+        assert_eq!(waiting, json!([{ "status": "paused" }]));
+
+        call(&aria2, "aria2.unpause", json!([gid])).await;
+        call(&aria2, "aria2.remove", json!([gid])).await;
+        let stopped = call(
+            &aria2,
+            "aria2.tellStopped",
+            json!([0, 10, ["gid", "status"]]),
+        )
+        .await;
+        assert_eq!(stopped, json!([{ "gid": gid, "status": "removed" }]));
+
+        let stat = call(&aria2, "aria2.getGlobalStat", json!([])).await;
+        assert_eq!(
+            (stat["numWaiting"].clone(), stat["numStopped"].clone()),
+            (json!("0"), json!("1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_need_the_secret() {
+        let aria2 = aria2(Some("hunter2"));
+
+        let denied = aria2
+            .handle(br#"{"id": 1, "method": "aria2.getGlobalStat", "params": []}"#)
+            .await;
+        assert_eq!(
+            denied["error"],
+            json!({ "code": 1, "message": "Unauthorized" })
+        );
+
+        let batch = aria2
+            .handle(
+                br#"[
+                    {"id": 1, "method": "aria2.getVersion", "params": ["token:hunter2"]},
+                    {"id": 2, "method": "system.multicall", "params": [[
+                        {"methodName": "aria2.getGlobalOption", "params": ["token:hunter2"]},
+                        {"methodName": "aria2.getGlobalOption", "params": ["token:wrong"]}
+                    ]]}
+                ]"#,
+            )
+            .await;
+        assert_eq!(batch[0]["result"]["version"], ARIA2_VERSION);
+        assert_eq!(batch[1]["result"][0][0]["dir"], "/downloads");
+        assert_eq!(batch[1]["result"][1]["message"], "Unauthorized");
+
+        let garbage = aria2.handle(b"{").await;
+        assert_eq!(garbage["error"]["code"], RpcError::PARSE_ERROR);
+    }
+
+    #[test]
+    fn notifications_follow_the_state() {
+        use DownloadState::*;
+
+        let before = BTreeMap::from([(1, Queued), (2, Probing), (3, Running)]);
+        let after = BTreeMap::from([(1, Probing), (2, Running), (3, Completed), (4, Failed)]);
+        let methods: Vec<_> = notifications(&before, &after)
+            .iter()
+            .map(|notification| {
+                let method = notification["method"].as_str().unwrap().to_string();
+                (method, notification["params"][0]["gid"].clone())
+            })
+            .collect();
+
+        assert_eq!(
+            methods,
+            vec![
+                ("aria2.onDownloadStart".to_string(), json!(gid_of(1))),
+                ("aria2.onDownloadComplete".to_string(), json!(gid_of(3))),
+                ("aria2.onDownloadError".to_string(), json!(gid_of(4))),
+            ]
+        );
+    }
+
+    #[test]
+    fn sizes_and_pages_read_like_in_aria2() {
+        assert_eq!(parse_size("1M").unwrap(), 1024 * 1024);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert!(parse_size("fast").is_err());
+        assert!(parse_size(&format!("{}M", u64::MAX)).is_err());
+
+        assert_eq!(page(vec![1, 2, 3, 4], 1, 2), vec![2, 3]);
+        assert_eq!(page(vec![1, 2, 3, 4], -1, 2), vec![4, 3]);
+        assert_eq!(page(vec![1, 2, 3, 4], i64::MIN, 2), Vec::<i32>::new());
+    }
+}
@@ -1 +1,3 @@
-pub mod v1;
\ No newline at end of file
+pub mod aria2;
+pub mod ui;
+pub mod v1;
@@ -0,0 +1,9 @@
+use axum::response::Html;
+
+/// The web UI, a single page talking to the v1 API
+const INDEX: &str = include_str!("ui/index.html");
+
+/// Maps to GET /
+pub async fn index() -> Html<&'static str> {
+    Html(INDEX)
+}
@@ -0,0 +1,228 @@
+use super::{status_of, DownloadStatus};
+use crate::{
+    speed::SharedSpeeds,
+    store::{DownloadRecord, DownloadState, SharedDownloadStore, StoreError},
+};
+use axum::{
+    response::sse::{Event, KeepAlive, Sse},
+    Extension,
+};
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, convert::Infallible, time::Duration};
+
+/// How often the store is looked at for changes, about as often as the progress is written down
+pub const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Something that happened to a download
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum DownloadEvent {
+    /// A new download, or one that changed state or made progress
+    Changed(Box<DownloadStatus>),
+    /// A download the store no longer has, e.g. it was pruned from the history
+    Removed { id: i32 },
+}
+
+impl DownloadEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DownloadEvent::Changed(_) => "download",
+            DownloadEvent::Removed { .. } => "removed",
+        }
+    }
+}
+
+/// Compares the downloads to how they were the last time we looked. Only running downloads make
+/// progress, so the status of the others is only looked up again when they change.
+#[derive(Debug)]
+pub struct ChangeTracker {
+    /// Every download as of the last look, along with the status sent for it
+    seen: HashMap<i32, (DownloadRecord, Value)>,
+    speeds: SharedSpeeds,
+}
+
+impl ChangeTracker {
+    pub fn new(speeds: SharedSpeeds) -> Self {
+        Self {
+            seen: HashMap::new(),
+            speeds,
+        }
+    }
+
+    /// What happened since the last call, every download the first time
+    pub async fn changes(
+        &mut self,
+        store: &SharedDownloadStore,
+    ) -> Result<Vec<DownloadEvent>, StoreError> {
+        let mut events = vec![];
+        let mut seen = HashMap::new();
+
+        for record in store.downloads().await? {
+            let id = record.id;
+            match self.seen.remove(&id) {
+                Some(last) if last.0 == record && record.state != DownloadState::Running => {
+                    seen.insert(id, last);
+                }
+                last => {
+                    let status = status_of(store, &self.speeds, record.clone()).await?;
+                    let value = serde_json::to_value(&status)
+                        .map_err(|e| StoreError::Unsupported(e.to_string()))?;
+
+                    if last.map(|(_, value)| value).as_ref() != Some(&value) {
+                        events.push(DownloadEvent::Changed(Box::new(status)));
+                    }
+                    seen.insert(id, (record, value));
+                }
+            }
+        }
+        // whatever wasn't taken out above is gone
+        events.extend(self.seen.keys().map(|&id| DownloadEvent::Removed { id }));
+
+        self.seen = seen;
+        Ok(events)
+    }
+}
+
+/// Every change to the downloads as it is noticed, starting with all of them
+pub fn download_events(
+    store: SharedDownloadStore,
+    speeds: SharedSpeeds,
+    interval: Duration,
+) -> impl Stream<Item = Result<DownloadEvent, StoreError>> {
+    let ticks = tokio::time::interval(interval);
+
+    stream::unfold(
+        (store, ChangeTracker::new(speeds), ticks),
+        |(store, mut tracker, mut ticks)| async move {
+            ticks.tick().await;
+            let events = match tracker.changes(&store).await {
+                Ok(events) => events.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+
+            Some((stream::iter(events), (store, tracker, ticks)))
+        },
+    )
+    .flatten()
+}
+
+/// Maps to GET /api/v1/events, a server-sent event stream of `download` events carrying the
+/// status of a download whenever it changes and `removed` events carrying the id of a download
+/// that is gone
+pub async fn events(
+    Extension(store): Extension<SharedDownloadStore>,
+    Extension(speeds): Extension<SharedSpeeds>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = download_events(store, speeds, EVENT_INTERVAL).map(|event| {
+        let event = match event {
+            Ok(event) => Event::default().event(event.name()).json_data(&event),
+            Err(e) => Ok(Event::default()
+                .event("error")
+                .data(json!({ "error": e.to_string() }).to_string())),
+        };
+
+        Ok(event.unwrap_or_else(|e| Event::default().event("error").data(e.to_string())))
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http::DownloadContext, request::http::DownloadOptions, store::memory::MemoryStore,
+    };
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+    use url::Url;
+
+    fn names(events: &[DownloadEvent]) -> Vec<(&'static str, Value)> {
+        events
+            .iter()
+            .map(|event| {
+                (
+                    event.name(),
+                    serde_json::to_value(event).unwrap()["id"].clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn only_changes_are_reported() -> color_eyre::Result<()> {
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let record = DownloadRecord::new(
+            Url::parse("https://example.com/file.iso")?,
+            "/tmp/file.iso".into(),
+            DownloadState::Queued,
+            DownloadOptions::default(),
+        );
+        let (first, _) = store.create_download(&record, &[]).await?;
+        let (second, _) = store.create_download(&record, &[]).await?;
+        let mut tracker = ChangeTracker::new(SharedSpeeds::default());
+
+        assert_eq!(
+            names(&tracker.changes(&store).await?),
+            vec![("download", json!(first)), ("download", json!(second))]
+        );
+        assert_eq!(names(&tracker.changes(&store).await?), vec![]);
+
+        store.set_state(second, DownloadState::Paused, None).await?;
+        assert_eq!(
+            names(&tracker.changes(&store).await?),
+            vec![("download", json!(second))]
+        );
+
+        store.remove_download(first).await?;
+        assert_eq!(
+            names(&tracker.changes(&store).await?),
+            vec![("removed", json!(first))]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_running_downloads_are_looked_at_until_they_change() -> color_eyre::Result<()> {
+        use crate::store::tests::{download, record};
+
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let url = "https://example.com/file.iso";
+        let mut segments = vec![];
+        for _ in 0..2 {
+            let (id, _) = store
+                .create_download(&record(url, DownloadState::Queued), &[])
+                .await?;
+            let ids = store
+                .add_segments(id, 100, &[download(url, 0, 100)])
+                .await?;
+            segments.push(DownloadContext {
+                id: ids[0],
+                download_id: id,
+                ..download(url, 50, 50)
+            });
+        }
+        let (running, paused) = (segments[0].download_id, segments[1].download_id);
+        store.set_state(paused, DownloadState::Paused, None).await?;
+        let mut tracker = ChangeTracker::new(SharedSpeeds::default());
+        tracker.changes(&store).await?;
+
+        // the progress of a paused download doesn't move, it isn't even looked at
+        store.update_downloads(&segments).await?;
+        assert_eq!(
+            names(&tracker.changes(&store).await?),
+            vec![("download", json!(running))]
+        );
+
+        store.set_state(paused, DownloadState::Queued, None).await?;
+        assert_eq!(
+            names(&tracker.changes(&store).await?),
+            vec![("download", json!(paused))]
+        );
+
+        Ok(())
+    }
+}
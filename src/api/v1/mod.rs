@@ -2,12 +2,32 @@
 
 
 
-use axum::extract::Query;
+pub mod events;
 
-use serde::Deserialize;
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+
+use serde::{Deserialize, Serialize};
+
+use std::path::{Component, PathBuf};
 
 use url::{Url};
 
+use crate::{
+    disk::{FilesystemStatus, SharedDiskSpaceMonitor},
+    export::{self as transfer, Export},
+    history::{HistoryEntry, HistoryFilter},
+    http::{DownloadContext, Limits, SharedHttpDownloader},
+    queue::DownloadQueue,
+    request::http::{file_name_of, DownloadOptions, HttpRequest},
+    speed::{SharedSpeeds, Speeds},
+    store::{DownloadRecord, DownloadState, SharedDownloadStore, StoreError},
+};
+
 
 
 
@@ -36,3 +56,385 @@ pub struct DownloadResponse {
 pub struct DownloadReq {
     url: Url,
 }
+
+/// Maps to /api/v1/disks, lists the filesystems downloads write to and whether they are paused
+/// because they ran out of space
+pub async fn disks(
+    Extension(disk_monitor): Extension<SharedDiskSpaceMonitor>,
+) -> Json<Vec<FilesystemStatus>> {
+    Json(disk_monitor.status())
+}
+
+impl IntoResponse for StoreError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            StoreError::NotFound => StatusCode::NOT_FOUND,
+            StoreError::InvalidTransition { .. } => StatusCode::CONFLICT,
+            // the download or document we were given, not us
+            StoreError::Unsupported(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// Maps to GET /api/v1/queue, lists the queued downloads in the order they will start
+pub async fn queue(
+    Extension(queue): Extension<DownloadQueue>,
+) -> Result<Json<Vec<DownloadRecord>>, StoreError> {
+    Ok(Json(queue.queued().await?))
+}
+
+#[derive(Deserialize)]
+pub struct QueueOrder {
+    ids: Vec<i32>,
+}
+
+/// Maps to PUT /api/v1/queue, moves the given downloads to the front of the queue in the given
+/// order
+pub async fn reorder_queue(
+    Extension(queue): Extension<DownloadQueue>,
+    Json(order): Json<QueueOrder>,
+) -> Result<Json<Vec<DownloadRecord>>, StoreError> {
+    queue.reorder(&order.ids).await?;
+
+    Ok(Json(queue.queued().await?))
+}
+
+/// Maps to POST /api/v1/queue/:id/front
+pub async fn move_to_front(
+    Extension(queue): Extension<DownloadQueue>,
+    Path(download_id): Path<i32>,
+) -> Result<Json<Vec<DownloadRecord>>, StoreError> {
+    queue.move_to_front(download_id).await?;
+
+    Ok(Json(queue.queued().await?))
+}
+
+#[derive(Deserialize)]
+pub struct QueuePriority {
+    priority: i32,
+}
+
+/// Maps to PUT /api/v1/queue/:id/priority, higher priorities start first
+pub async fn set_priority(
+    Extension(queue): Extension<DownloadQueue>,
+    Path(download_id): Path<i32>,
+    Json(priority): Json<QueuePriority>,
+) -> Result<Json<Vec<DownloadRecord>>, StoreError> {
+    queue.set_priority(download_id, priority.priority).await?;
+
+    Ok(Json(queue.queued().await?))
+}
+
+/// Maps to GET /api/v1/history, lists the completed and failed downloads, the most recent first.
+/// Takes the fields of `HistoryFilter` as query parameters, e.g. `?state=failed&limit=10`
+pub async fn history(
+    Extension(store): Extension<SharedDownloadStore>,
+    Query(filter): Query<HistoryFilter>,
+) -> Result<Json<Vec<HistoryEntry>>, StoreError> {
+    let history = store.history(&filter).await?;
+
+    Ok(Json(history.into_iter().map(HistoryEntry::from).collect()))
+}
+
+/// Maps to GET /api/v1/export, every download as a document `POST /api/v1/import` takes back
+pub async fn export(
+    Extension(store): Extension<SharedDownloadStore>,
+) -> Result<Json<Export>, StoreError> {
+    Ok(Json(transfer::export(&*store).await?))
+}
+
+#[derive(Serialize)]
+pub struct Imported {
+    ids: Vec<i32>,
+}
+
+/// Maps to POST /api/v1/import, recreates the downloads of an export and returns their new ids
+pub async fn import(
+    Extension(store): Extension<SharedDownloadStore>,
+    Extension(queue): Extension<DownloadQueue>,
+    Json(export): Json<Export>,
+) -> Result<Json<Imported>, StoreError> {
+    let ids = transfer::import(&*store, &export).await?;
+
+    // whatever was queued may start right away
+    queue.notify();
+
+    Ok(Json(Imported { ids }))
+}
+
+/// Where downloads requested with a relative path, or none at all, go
+#[derive(Debug, Clone)]
+pub struct DownloadDir(pub PathBuf);
+
+#[derive(Deserialize)]
+pub struct NewDownload {
+    url: Url,
+    /// Relative to the download directory and staying inside of it, the file name of the url in
+    /// the download directory if not given
+    path: Option<PathBuf>,
+    #[serde(default)]
+    options: DownloadOptions,
+    /// Queued downloads with a higher priority start first
+    #[serde(default)]
+    priority: i32,
+}
+
+#[derive(Serialize)]
+pub struct Created {
+    id: i32,
+}
+
+/// `path` in `download_dir` with the `.` and `..` resolved, as long as it names a file in there.
+/// Anyone who can reach the API can ask for a download, so an absolute path or one that climbs
+/// out with `..` is refused.
+fn inside(download_dir: &std::path::Path, path: &std::path::Path) -> Result<PathBuf, StoreError> {
+    let refused = || {
+        StoreError::Unsupported(format!("{:?} isn't a file in the download directory", path))
+    };
+
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(refused());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(refused()),
+        }
+    }
+
+    match relative.as_os_str().is_empty() {
+        true => Err(refused()),
+        false => Ok(download_dir.join(relative)),
+    }
+}
+
+/// Maps to POST /api/v1/downloads, queues a download and returns its id
+pub async fn create_download(
+    Extension(queue): Extension<DownloadQueue>,
+    Extension(DownloadDir(download_dir)): Extension<DownloadDir>,
+    Json(download): Json<NewDownload>,
+) -> Result<(StatusCode, Json<Created>), StoreError> {
+    let path = match download.path {
+        Some(path) => path,
+        None => file_name_of(&download.url)
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                StoreError::Unsupported(format!(
+                    "can't tell what to call the file of {}, give it a path",
+                    download.url
+                ))
+            })?,
+    };
+    let path = inside(&download_dir, &path)?;
+
+    let request = HttpRequest {
+        url: download.url,
+        path,
+        options: download.options,
+        origin: Some("api".to_string()),
+    };
+    let id = queue
+        .enqueue_with_priority(&request, download.priority)
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(Created { id })))
+}
+
+/// A download along with how far along it is
+#[derive(Serialize)]
+pub struct DownloadStatus {
+    #[serde(flatten)]
+    pub(crate) record: DownloadRecord,
+    /// Bytes on disk as of the last checkpoint, unknown until the download has been probed
+    pub(crate) downloaded: Option<u64>,
+    /// Bytes per second while it is running
+    pub(crate) speed: Option<u64>,
+}
+
+pub(crate) async fn status_of(
+    store: &SharedDownloadStore,
+    speeds: &Speeds,
+    record: DownloadRecord,
+) -> Result<DownloadStatus, StoreError> {
+    let downloaded = match (record.state, record.total_size) {
+        (_, None) => None,
+        (DownloadState::Completed, total) => total,
+        (_, Some(total)) => {
+            let left: u64 = store
+                .segments(record.id)
+                .await?
+                .iter()
+                .map(|segment| segment.total)
+                .sum();
+            Some(total.saturating_sub(left))
+        }
+    };
+
+    let speed = speeds.of(&record, downloaded);
+
+    Ok(DownloadStatus {
+        record,
+        downloaded,
+        speed,
+    })
+}
+
+/// Maps to GET /api/v1/downloads, every download the store knows about, the oldest first
+pub async fn downloads(
+    Extension(store): Extension<SharedDownloadStore>,
+    Extension(speeds): Extension<SharedSpeeds>,
+) -> Result<Json<Vec<DownloadStatus>>, StoreError> {
+    let mut statuses = vec![];
+    for record in store.downloads().await? {
+        statuses.push(status_of(&store, &speeds, record).await?);
+    }
+
+    Ok(Json(statuses))
+}
+
+/// Maps to GET /api/v1/downloads/:id
+pub async fn download(
+    Extension(store): Extension<SharedDownloadStore>,
+    Extension(speeds): Extension<SharedSpeeds>,
+    Path(download_id): Path<i32>,
+) -> Result<Json<DownloadStatus>, StoreError> {
+    let record = store.download(download_id).await?;
+
+    Ok(Json(status_of(&store, &speeds, record).await?))
+}
+
+/// A part of a download fetched over its own connection
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Segment {
+    id: i32,
+    /// The first byte of the file it covers
+    start: u64,
+    /// The next byte it will download
+    offset: u64,
+    /// The byte after the last one it covers
+    end: u64,
+}
+
+/// Where each segment of a download starts and ends and how far along it is. The segments split
+/// the file without gaps, so each starts where the one before it ends.
+fn segments_of(mut segments: Vec<DownloadContext>) -> Vec<Segment> {
+    segments.sort_by_key(|segment| segment.offset);
+
+    let mut start = 0;
+    segments
+        .into_iter()
+        .map(|segment| {
+            let end = segment.offset + segment.total;
+            let segment = Segment {
+                id: segment.id,
+                start,
+                offset: segment.offset,
+                end,
+            };
+            start = end;
+            segment
+        })
+        .collect()
+}
+
+/// Maps to GET /api/v1/downloads/:id/segments, empty until the download has been probed and once
+/// it is finished
+pub async fn segments(
+    Extension(store): Extension<SharedDownloadStore>,
+    Path(download_id): Path<i32>,
+) -> Result<Json<Vec<Segment>>, StoreError> {
+    // a 404 for downloads we don't know about rather than no segments
+    store.download(download_id).await?;
+
+    Ok(Json(segments_of(store.segments(download_id).await?)))
+}
+
+/// Maps to POST /api/v1/downloads/:id/pause
+pub async fn pause(
+    Extension(store): Extension<SharedDownloadStore>,
+    Extension(speeds): Extension<SharedSpeeds>,
+    Extension(downloader): Extension<SharedHttpDownloader>,
+    Path(download_id): Path<i32>,
+) -> Result<Json<DownloadStatus>, StoreError> {
+    downloader.pause(download_id).await?;
+
+    download(Extension(store), Extension(speeds), Path(download_id)).await
+}
+
+/// Maps to POST /api/v1/downloads/:id/resume, queues a paused or failed download again
+pub async fn resume(
+    Extension(store): Extension<SharedDownloadStore>,
+    Extension(speeds): Extension<SharedSpeeds>,
+    Extension(downloader): Extension<SharedHttpDownloader>,
+    Path(download_id): Path<i32>,
+) -> Result<Json<DownloadStatus>, StoreError> {
+    downloader.resume(download_id).await?;
+
+    download(Extension(store), Extension(speeds), Path(download_id)).await
+}
+
+/// Maps to POST /api/v1/downloads/:id/cancel, a running download is cancelled once it stopped
+pub async fn cancel(
+    Extension(store): Extension<SharedDownloadStore>,
+    Extension(speeds): Extension<SharedSpeeds>,
+    Extension(downloader): Extension<SharedHttpDownloader>,
+    Path(download_id): Path<i32>,
+) -> Result<Json<DownloadStatus>, StoreError> {
+    downloader.cancel(download_id).await?;
+
+    download(Extension(store), Extension(speeds), Path(download_id)).await
+}
+
+/// Maps to GET /api/v1/limits
+pub async fn limits(Extension(downloader): Extension<SharedHttpDownloader>) -> Json<Limits> {
+    Json(downloader.limits().await)
+}
+
+/// Maps to PUT /api/v1/limits, takes effect right away
+pub async fn set_limits(
+    Extension(downloader): Extension<SharedHttpDownloader>,
+    Json(limits): Json<Limits>,
+) -> Result<Json<Limits>, (StatusCode, String)> {
+    if limits.max_concurrent == 0 || limits.rate == Some(0) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "the limits have to be more than 0, leave the rate out for no limit".to_string(),
+        ));
+    }
+    downloader.set_limits(limits).await;
+
+    Ok(Json(downloader.limits().await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn downloads_stay_inside_the_download_directory() {
+        let download_dir = std::path::Path::new("/srv/downloads");
+        let inside = |path: &str| inside(download_dir, std::path::Path::new(path)).ok();
+
+        assert_eq!(
+            inside("isos/ubuntu.iso"),
+            Some(PathBuf::from("/srv/downloads/isos/ubuntu.iso"))
+        );
+        assert_eq!(
+            inside("./isos/../ubuntu.iso"),
+            Some(PathBuf::from("/srv/downloads/ubuntu.iso"))
+        );
+        assert_eq!(inside("/etc/cron.d/job"), None);
+        assert_eq!(inside("../.bashrc"), None);
+        assert_eq!(inside("isos/../../.bashrc"), None);
+        assert_eq!(inside("isos/.."), None);
+        assert_eq!(inside(""), None);
+    }
+}
@@ -0,0 +1,426 @@
+//! Talks to a running sulfur daemon through its API
+
+use chrono::NaiveTime;
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{bail, eyre};
+use hyper::{body, client::HttpConnector, header, Body, Client, Method, Request};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+use url::Url;
+
+#[path = "../../format.rs"]
+mod format;
+mod tui;
+
+use format::{format_bytes, parse_header};
+
+/// Control a sulfur daemon
+#[derive(Debug, Parser)]
+#[clap(name = "sulfur-ctl", version)]
+struct Cli {
+    /// Where the daemon listens
+    #[clap(long, env = "SULFUR_SERVER", default_value = "http://127.0.0.1:6969")]
+    server: Url,
+    /// Print what the daemon answers as JSON instead of for humans
+    #[clap(long, global = true)]
+    json: bool,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Queue a download
+    Add {
+        url: Url,
+        /// Where the file goes, relative to the download directory of the daemon and staying inside
+        /// of it. The file name of the url in the download directory if not given.
+        #[clap(long, short = 'o')]
+        path: Option<PathBuf>,
+        /// How many connections the download is split over
+        #[clap(long)]
+        connections: Option<u32>,
+        /// The part of the day the download may run in, e.g. 22:00-06:00
+        #[clap(long, parse(try_from_str = parse_window))]
+        window: Option<(NaiveTime, NaiveTime)>,
+        /// Sent along with every request of the download, e.g. "Cookie: session=1234"
+        #[clap(long = "header", short = 'H', parse(try_from_str = parse_header))]
+        headers: Vec<(String, String)>,
+        /// The hex encoded sha-256 the finished file must have
+        #[clap(long)]
+        checksum: Option<String>,
+        /// Queued downloads with a higher priority start first
+        #[clap(long)]
+        priority: Option<i32>,
+    },
+    /// List every download
+    List {
+        /// Only the downloads in this state, e.g. running or failed
+        #[clap(long)]
+        state: Option<String>,
+    },
+    /// Show everything about a download
+    Show { id: i32 },
+    /// Pause a download, it keeps its progress
+    Pause { id: i32 },
+    /// Queue a paused or failed download again
+    Resume { id: i32 },
+    /// Give up on a download and throw away what it downloaded
+    Cancel { id: i32 },
+    /// Follow the progress of a download until it is finished
+    Watch {
+        id: i32,
+        /// Seconds between two looks
+        #[clap(long, default_value = "1")]
+        interval: u64,
+    },
+    /// Show the limits shared by every download, or change them
+    Limits {
+        /// Bytes per second whenever the bandwidth schedule doesn't say otherwise
+        #[clap(long, conflicts_with = "unlimited")]
+        rate: Option<u64>,
+        /// Remove the rate limit
+        #[clap(long)]
+        unlimited: bool,
+        /// How many downloads run at once
+        #[clap(long)]
+        max_concurrent: Option<usize>,
+    },
+    /// A dashboard of every download, to follow and control them from the terminal
+    Tui {
+        /// Seconds between two looks
+        #[clap(long, default_value = "1")]
+        interval: u64,
+    },
+}
+
+/// `22:00-06:00`, or with seconds
+fn parse_window(text: &str) -> Result<(NaiveTime, NaiveTime), String> {
+    let time = |text: &str| {
+        NaiveTime::parse_from_str(text, "%H:%M:%S")
+            .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M"))
+            .map_err(|e| format!("{} isn't a time of day: {}", text, e))
+    };
+    let (start, end) = text
+        .split_once('-')
+        .ok_or_else(|| format!("{} isn't a window like 22:00-06:00", text))?;
+
+    Ok((time(start.trim())?, time(end.trim())?))
+}
+
+/// How far along a download is, e.g. `1.5 MiB / 3.0 MiB (50%)`
+fn progress_of(download: &Value) -> String {
+    let downloaded = download["downloaded"].as_u64();
+    let total = download["total_size"].as_u64();
+
+    match (downloaded, total) {
+        (Some(downloaded), Some(total)) if total > 0 => format!(
+            "{} / {} ({}%)",
+            format_bytes(downloaded),
+            format_bytes(total),
+            downloaded * 100 / total
+        ),
+        (_, Some(total)) => format_bytes(total),
+        _ => "-".to_string(),
+    }
+}
+
+fn text_of(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => "-".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// One line per download
+fn print_downloads(downloads: &[Value]) {
+    let row = |id: &str, state: &str, progress: &str, url: &str| {
+        println!("{:>5}  {:<10}  {:<28}  {}", id, state, progress, url)
+    };
+
+    row("ID", "STATE", "PROGRESS", "URL");
+    for download in downloads {
+        row(
+            &text_of(&download["id"]),
+            &text_of(&download["state"]),
+            &progress_of(download),
+            &text_of(&download["url"]),
+        );
+    }
+}
+
+/// Every field of the download that has something to say
+fn print_download(download: &Value) {
+    let fields = [
+        ("id", "Id"),
+        ("url", "Url"),
+        ("file_path", "Path"),
+        ("state", "State"),
+        ("error", "Error"),
+        ("priority", "Priority"),
+        ("origin", "Origin"),
+        ("checksum", "Checksum"),
+    ];
+    for (field, name) in fields {
+        if !download[field].is_null() {
+            println!("{:<10} {}", format!("{}:", name), text_of(&download[field]));
+        }
+    }
+    println!("{:<10} {}", "Progress:", progress_of(download));
+
+    let options = &download["options"];
+    if let Some(connections) = options["connections"].as_u64() {
+        println!("{:<10} {}", "Splits:", connections);
+    }
+    if options["window"].is_object() {
+        println!(
+            "{:<10} {} to {}",
+            "Window:",
+            text_of(&options["window"]["start"]),
+            text_of(&options["window"]["end"])
+        );
+    }
+    if let Some(headers) = options["headers"].as_object() {
+        for (name, value) in headers {
+            println!("{:<10} {}: {}", "Header:", name, text_of(value));
+        }
+    }
+}
+
+fn print_limits(limits: &Value) {
+    let rate = match limits["rate"].as_u64() {
+        Some(rate) => format!("{}/s", format_bytes(rate)),
+        None => "unlimited".to_string(),
+    };
+
+    println!("Rate:           {}", rate);
+    println!("Max concurrent: {}", text_of(&limits["max_concurrent"]));
+}
+
+fn is_finished(download: &Value) -> bool {
+    matches!(
+        download["state"].as_str(),
+        Some("completed" | "failed" | "cancelled")
+    )
+}
+
+struct Daemon {
+    client: Client<HttpConnector>,
+    server: Url,
+}
+
+impl Daemon {
+    /// Send `body` to `path` of the API and return what it answers with, an error if it didn't
+    /// like the request
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> color_eyre::Result<Value> {
+        let url = self.server.join(path)?;
+        let request = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .header(header::CONTENT_TYPE, "application/json");
+        let request = match body {
+            Some(body) => request.body(Body::from(serde_json::to_vec(&body)?))?,
+            None => request.body(Body::empty())?,
+        };
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| eyre!("Failed to reach the daemon at {}: {}", self.server, e))?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body()).await?;
+
+        if !status.is_success() {
+            bail!("{}: {}", status, String::from_utf8_lossy(&bytes));
+        }
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    async fn get(&self, path: &str) -> color_eyre::Result<Value> {
+        self.call(Method::GET, path, None).await
+    }
+
+    /// Pause, resume or cancel a download
+    async fn act(&self, id: i32, action: &str) -> color_eyre::Result<Value> {
+        let path = format!("api/v1/downloads/{}/{}", id, action);
+
+        self.call(Method::POST, &path, None).await
+    }
+}
+
+#[tokio::main]
+async fn main() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+    let cli = Cli::parse();
+    let daemon = Daemon {
+        client: Client::new(),
+        server: cli.server,
+    };
+    let print = |value: &Value, human: fn(&Value)| match cli.json {
+        true => println!("{}", value),
+        false => human(value),
+    };
+
+    match cli.command {
+        Command::Add {
+            url,
+            path,
+            connections,
+            window,
+            headers,
+            checksum,
+            priority,
+        } => {
+            let headers: BTreeMap<_, _> = headers.into_iter().collect();
+            let window = window.map(|(start, end)| json!({ "start": start, "end": end }));
+            let request = json!({
+                "url": url,
+                "path": path,
+                "priority": priority.unwrap_or_default(),
+                "options": {
+                    "connections": connections,
+                    "window": window,
+                    "headers": headers,
+                    "checksum": checksum,
+                },
+            });
+            let created = daemon
+                .call(Method::POST, "api/v1/downloads", Some(request))
+                .await?;
+            let id = text_of(&created["id"]);
+
+            let download = daemon.get(&format!("api/v1/downloads/{}", id)).await?;
+            print(&download, print_download);
+        }
+        Command::List { state } => {
+            let downloads = daemon.get("api/v1/downloads").await?;
+            let downloads: Vec<_> = downloads
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|download| {
+                    state
+                        .as_deref()
+                        .map_or(true, |state| download["state"].as_str() == Some(state))
+                })
+                .cloned()
+                .collect();
+
+            match cli.json {
+                true => println!("{}", Value::Array(downloads)),
+                false => print_downloads(&downloads),
+            }
+        }
+        Command::Show { id } => {
+            let download = daemon.get(&format!("api/v1/downloads/{}", id)).await?;
+            print(&download, print_download);
+        }
+        Command::Pause { id } => print(&daemon.act(id, "pause").await?, print_download),
+        Command::Resume { id } => print(&daemon.act(id, "resume").await?, print_download),
+        Command::Cancel { id } => print(&daemon.act(id, "cancel").await?, print_download),
+        Command::Watch { id, interval } => {
+            loop {
+                let download = daemon.get(&format!("api/v1/downloads/{}", id)).await?;
+
+                match cli.json {
+                    true => println!("{}", download),
+                    false => {
+                        let speed = match download["speed"].as_u64() {
+                            Some(speed) => format!("{}/s", format_bytes(speed)),
+                            None => "-".to_string(),
+                        };
+                        println!(
+                            "{:<10}  {:<28}  {}",
+                            text_of(&download["state"]),
+                            progress_of(&download),
+                            speed
+                        );
+                    }
+                }
+
+                if is_finished(&download) {
+                    if download["state"] != "completed" {
+                        bail!(
+                            "Download {} is {}: {}",
+                            id,
+                            text_of(&download["state"]),
+                            text_of(&download["error"])
+                        );
+                    }
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+            }
+        }
+        Command::Limits {
+            rate,
+            unlimited,
+            max_concurrent,
+        } => {
+            let mut limits = daemon.get("api/v1/limits").await?;
+
+            if rate.is_some() || unlimited || max_concurrent.is_some() {
+                if let Some(rate) = rate {
+                    limits["rate"] = json!(rate);
+                }
+                if unlimited {
+                    limits["rate"] = Value::Null;
+                }
+                if let Some(max_concurrent) = max_concurrent {
+                    limits["max_concurrent"] = json!(max_concurrent);
+                }
+                limits = daemon
+                    .call(Method::PUT, "api/v1/limits", Some(limits))
+                    .await?;
+            }
+
+            print(&limits, print_limits);
+        }
+        Command::Tui { interval } => {
+            tui::run(&daemon, Duration::from_secs(interval.max(1))).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn options_are_parsed() {
+        assert_eq!(
+            parse_window("22:00-06:30:15").unwrap(),
+            (
+                NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(6, 30, 15).unwrap()
+            )
+        );
+        assert!(parse_window("22:00").is_err());
+        assert_eq!(
+            parse_header("Cookie: session=1:2").unwrap(),
+            ("Cookie".to_string(), "session=1:2".to_string())
+        );
+    }
+
+    #[test]
+    fn progress_is_readable() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(
+            progress_of(&json!({ "downloaded": 1536, "total_size": 3072 })),
+            "1.5 KiB / 3.0 KiB (50%)"
+        );
+        assert_eq!(progress_of(&json!({ "total_size": null })), "-");
+    }
+}
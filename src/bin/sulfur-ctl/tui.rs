@@ -0,0 +1,393 @@
+//! A dashboard of every download the daemon knows about, which can also control them
+
+use crate::{format_bytes, progress_of, text_of, Daemon};
+use color_eyre::eyre::bail;
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::StreamExt;
+use hyper::Method;
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Cell, LineGauge, Paragraph, Row, Table, TableState},
+    Frame, Terminal,
+};
+use serde_json::{json, Value};
+use std::{
+    io::{stdout, Stdout},
+    time::Duration,
+};
+use url::Url;
+
+/// Puts the terminal back the way we found it, however we leave
+struct Screen(Terminal<CrosstermBackend<Stdout>>);
+
+impl Screen {
+    fn open() -> color_eyre::Result<Self> {
+        enable_raw_mode()?;
+        execute!(stdout(), EnterAlternateScreen)?;
+
+        Ok(Self(Terminal::new(CrosstermBackend::new(stdout()))?))
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0.backend_mut(), LeaveAlternateScreen);
+        let _ = self.0.show_cursor();
+    }
+}
+
+/// `3725` seconds as `1h02m`
+fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// How long until the download is done at its current speed
+fn eta_of(download: &Value, speed: Option<u64>) -> String {
+    let left = download["total_size"]
+        .as_u64()
+        .zip(download["downloaded"].as_u64())
+        .map(|(total, downloaded)| total.saturating_sub(downloaded));
+
+    match (left, speed) {
+        (Some(0), _) => "-".to_string(),
+        (Some(left), Some(speed)) if speed > 0 => format_duration(left / speed),
+        _ => "-".to_string(),
+    }
+}
+
+/// What the keys do right now
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Browsing,
+    /// Typing the url of a new download
+    Adding(String),
+    /// Asked whether the selected download really should be cancelled
+    Cancelling(i64),
+}
+
+struct App {
+    downloads: Vec<Value>,
+    /// Of the selected download
+    segments: Vec<Value>,
+    table: TableState,
+    mode: Mode,
+    /// What went wrong last, or what was done
+    message: Option<String>,
+}
+
+impl App {
+    fn new() -> Self {
+        Self {
+            downloads: vec![],
+            segments: vec![],
+            table: TableState::default(),
+            mode: Mode::Browsing,
+            message: None,
+        }
+    }
+
+    fn selected(&self) -> Option<&Value> {
+        self.table.selected().and_then(|i| self.downloads.get(i))
+    }
+
+    fn selected_id(&self) -> Option<i64> {
+        self.selected().and_then(|download| download["id"].as_i64())
+    }
+
+    fn select(&mut self, index: usize) {
+        match self.downloads.len() {
+            0 => self.table.select(None),
+            len => self.table.select(Some(index.min(len - 1))),
+        }
+    }
+
+    async fn refresh(&mut self, daemon: &Daemon) {
+        if let Err(e) = self.try_refresh(daemon).await {
+            self.message = Some(e.to_string());
+        }
+    }
+
+    async fn try_refresh(&mut self, daemon: &Daemon) -> color_eyre::Result<()> {
+        let selected = self.selected_id();
+        let downloads = daemon.get("api/v1/downloads").await?;
+        self.downloads = downloads.as_array().cloned().unwrap_or_default();
+
+        // stay on the same download even if others came or went
+        let index = selected
+            .and_then(|id| self.downloads.iter().position(|d| d["id"] == id))
+            .unwrap_or_else(|| self.table.selected().unwrap_or_default());
+        self.select(index);
+
+        self.segments = match self.selected_id() {
+            Some(id) => {
+                let segments = daemon
+                    .get(&format!("api/v1/downloads/{}/segments", id))
+                    .await?;
+                segments.as_array().cloned().unwrap_or_default()
+            }
+            None => vec![],
+        };
+
+        Ok(())
+    }
+
+    /// Do what the key says, returns whether we're done
+    async fn handle(&mut self, key: KeyEvent, daemon: &Daemon) -> bool {
+        let result = match std::mem::replace(&mut self.mode, Mode::Browsing) {
+            Mode::Browsing => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return true,
+                KeyCode::Down | KeyCode::Char('j') => {
+                    self.select(self.table.selected().map_or(0, |i| i + 1));
+                    Ok(())
+                }
+                KeyCode::Up | KeyCode::Char('k') => {
+                    self.select(self.table.selected().unwrap_or_default().saturating_sub(1));
+                    Ok(())
+                }
+                KeyCode::Char('a') => {
+                    self.mode = Mode::Adding(String::new());
+                    Ok(())
+                }
+                KeyCode::Char('c') => {
+                    if let Some(id) = self.selected_id() {
+                        self.mode = Mode::Cancelling(id);
+                    }
+                    Ok(())
+                }
+                KeyCode::Char('p') => self.act(daemon, "pause").await,
+                KeyCode::Char('r') => self.act(daemon, "resume").await,
+                KeyCode::Char('+') => self.reprioritise(daemon, 1).await,
+                KeyCode::Char('-') => self.reprioritise(daemon, -1).await,
+                _ => Ok(()),
+            },
+            Mode::Adding(mut url) => match key.code {
+                KeyCode::Enter => self.add(daemon, &url).await,
+                KeyCode::Esc => Ok(()),
+                KeyCode::Backspace => {
+                    url.pop();
+                    self.mode = Mode::Adding(url);
+                    Ok(())
+                }
+                KeyCode::Char(c) => {
+                    url.push(c);
+                    self.mode = Mode::Adding(url);
+                    Ok(())
+                }
+                _ => {
+                    self.mode = Mode::Adding(url);
+                    Ok(())
+                }
+            },
+            Mode::Cancelling(id) => match key.code {
+                KeyCode::Char('y') => {
+                    let path = format!("api/v1/downloads/{}/cancel", id);
+                    daemon.call(Method::POST, &path, None).await.map(|_| ())
+                }
+                _ => Ok(()),
+            },
+        };
+
+        match result {
+            Ok(()) => self.refresh(daemon).await,
+            Err(e) => self.message = Some(e.to_string()),
+        }
+
+        false
+    }
+
+    /// Pause or resume the selected download
+    async fn act(&mut self, daemon: &Daemon, action: &str) -> color_eyre::Result<()> {
+        if let Some(id) = self.selected_id() {
+            daemon.act(id as i32, action).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Move the selected download up or down the queue, only queued downloads have a place in it
+    async fn reprioritise(&mut self, daemon: &Daemon, by: i64) -> color_eyre::Result<()> {
+        if let Some(download) = self.selected() {
+            if download["state"] != "queued" {
+                bail!("Only queued downloads can be moved up or down the queue");
+            }
+            let path = format!("api/v1/queue/{}/priority", text_of(&download["id"]));
+            let priority = download["priority"].as_i64().unwrap_or_default() + by;
+
+            daemon
+                .call(Method::PUT, &path, Some(json!({ "priority": priority })))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn add(&mut self, daemon: &Daemon, url: &str) -> color_eyre::Result<()> {
+        let url = Url::parse(url.trim())?;
+        let created = daemon
+            .call(
+                Method::POST,
+                "api/v1/downloads",
+                Some(json!({ "url": url })),
+            )
+            .await?;
+
+        self.message = Some(format!("Queued {} as {}", url, text_of(&created["id"])));
+        Ok(())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [downloads, segments, footer] = Layout::vertical([
+            Constraint::Min(5),
+            Constraint::Length(self.segments.len().clamp(1, 8) as u16 + 2),
+            Constraint::Length(1),
+        ])
+        .areas(frame.size());
+
+        self.draw_downloads(frame, downloads);
+        self.draw_segments(frame, segments);
+        self.draw_footer(frame, footer);
+    }
+
+    fn draw_downloads(&mut self, frame: &mut Frame, area: Rect) {
+        let header = Row::new(["ID", "STATE", "PROGRESS", "SPEED", "ETA", "PRIO", "FILE"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = self.downloads.iter().map(|download| {
+            let speed = download["speed"].as_u64();
+            let state_color = match download["state"].as_str() {
+                Some("running") => Color::Green,
+                Some("failed") => Color::Red,
+                Some("paused") => Color::Yellow,
+                _ => Color::Reset,
+            };
+
+            Row::new([
+                Cell::from(text_of(&download["id"])),
+                Cell::from(text_of(&download["state"])).style(Style::default().fg(state_color)),
+                Cell::from(progress_of(download)),
+                Cell::from(speed.map_or("-".to_string(), |s| format!("{}/s", format_bytes(s)))),
+                Cell::from(eta_of(download, speed)),
+                Cell::from(text_of(&download["priority"])),
+                Cell::from(text_of(&download["file_path"])),
+            ])
+        });
+        let widths = [
+            Constraint::Length(5),
+            Constraint::Length(10),
+            Constraint::Length(28),
+            Constraint::Length(12),
+            Constraint::Length(7),
+            Constraint::Length(5),
+            Constraint::Min(10),
+        ];
+
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(Block::default().borders(Borders::ALL).title(" Downloads "))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+
+    /// One bar per range of the file fetched over its own connection
+    fn draw_segments(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::default().borders(Borders::ALL).title(" Segments ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        if self.segments.is_empty() {
+            frame.render_widget(Paragraph::new("No segments"), inner);
+            return;
+        }
+
+        let rows = Layout::vertical(vec![Constraint::Length(1); self.segments.len()]).split(inner);
+        for (segment, row) in self.segments.iter().zip(rows.iter()) {
+            let start = segment["start"].as_u64().unwrap_or_default();
+            let offset = segment["offset"].as_u64().unwrap_or_default();
+            let end = segment["end"].as_u64().unwrap_or_default();
+            let ratio = match end.saturating_sub(start) {
+                0 => 1.0,
+                size => offset.saturating_sub(start) as f64 / size as f64,
+            };
+
+            let gauge = LineGauge::default()
+                .ratio(ratio.clamp(0.0, 1.0))
+                .label(format!(
+                    "{:>10} - {:<10} {:>3}%",
+                    format_bytes(start),
+                    format_bytes(end),
+                    (ratio * 100.0) as u64
+                ))
+                .gauge_style(Style::default().fg(Color::Cyan));
+            frame.render_widget(gauge, *row);
+        }
+    }
+
+    fn draw_footer(&self, frame: &mut Frame, area: Rect) {
+        let text = match &self.mode {
+            Mode::Adding(url) => {
+                format!("Url to download (enter to queue, esc to give up): {}", url)
+            }
+            Mode::Cancelling(id) => format!("Cancel download {} and delete what it has? (y/n)", id),
+            Mode::Browsing => match &self.message {
+                Some(message) => message.clone(),
+                None => "q quit  a add  p pause  r resume  c cancel  +/- priority".to_string(),
+            },
+        };
+
+        frame.render_widget(Paragraph::new(text), area);
+    }
+}
+
+/// Show the dashboard until told to quit, looking at the daemon every `interval`
+pub async fn run(daemon: &Daemon, interval: Duration) -> color_eyre::Result<()> {
+    let mut screen = Screen::open()?;
+    let mut events = EventStream::new();
+    let mut refresh = tokio::time::interval(interval);
+    let mut app = App::new();
+
+    loop {
+        screen.0.draw(|frame| app.draw(frame))?;
+
+        tokio::select! {
+            _ = refresh.tick() => app.refresh(daemon).await,
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    // a message is only shown until the next key
+                    app.message = None;
+                    if app.handle(key, daemon).await {
+                        break;
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn eta_follows_the_speed() {
+        let download = json!({ "downloaded": 2048, "total_size": 2048 + 1024 * 3725 });
+        assert_eq!(eta_of(&download, Some(1024)), "1h02m");
+        assert_eq!(eta_of(&download, None), "-");
+        assert_eq!(format_duration(65), "1m05s");
+    }
+}
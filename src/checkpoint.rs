@@ -0,0 +1,163 @@
+use crate::{
+    http::{DownloadContext, HttpDownloaderError, SharedDownloadStore},
+    sink::DownloadSink,
+};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Formatter},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{event, Level};
+
+/// How often the progress of running downloads is written to the store
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Collects the progress of subdownloads in memory and writes it to the store in batches, instead
+/// of hitting the database after every chunk.
+///
+/// Before anything is written to the store, the sinks the progress belongs to are synced, so the
+/// persisted offset of a subdownload never runs ahead of the data that is actually on disk.
+pub struct Checkpointer {
+    store: SharedDownloadStore,
+    interval: Duration,
+    pending: Mutex<HashMap<i32, Checkpoint>>,
+    // flushes taking their snapshots in one order and writing them in another could put an older
+    // offset in the store after a newer one
+    flushing: tokio::sync::Mutex<()>,
+}
+
+struct Checkpoint {
+    download: DownloadContext,
+    sink: Arc<dyn DownloadSink>,
+}
+
+impl Debug for Checkpointer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Checkpointer")
+            .field("interval", &self.interval)
+            .field("pending", &self.pending.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Checkpointer {
+    pub fn new(store: SharedDownloadStore, interval: Duration) -> Self {
+        Self {
+            store,
+            interval,
+            pending: Mutex::new(HashMap::new()),
+            flushing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Remember the progress of `download`, whose data has been written to `sink`. Only the most
+    /// recent progress of every subdownload is kept.
+    pub fn record(&self, download: &DownloadContext, sink: Arc<dyn DownloadSink>) {
+        self.pending.lock().unwrap().insert(
+            download.id,
+            Checkpoint {
+                download: download.clone(),
+                sink,
+            },
+        );
+    }
+
+    /// Drop whatever is pending for the given subdownloads, they are about to leave the store
+    pub fn forget(&self, ids: &[i32]) {
+        let mut pending = self.pending.lock().unwrap();
+        for id in ids {
+            pending.remove(id);
+        }
+    }
+
+    /// Write everything pending to the store
+    pub async fn flush(&self) -> Result<(), HttpDownloaderError> {
+        let _flushing = self.flushing.lock().await;
+
+        let checkpoints: Vec<_> = self
+            .pending
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, c)| c)
+            .collect();
+        if checkpoints.is_empty() {
+            return Ok(());
+        }
+
+        // subdownloads of the same file share a sink, syncing it once is enough
+        let mut synced: Vec<&Arc<dyn DownloadSink>> = vec![];
+        for checkpoint in &checkpoints {
+            if !synced
+                .iter()
+                .any(|sink| Arc::ptr_eq(sink, &checkpoint.sink))
+            {
+                checkpoint.sink.sync().await?;
+                synced.push(&checkpoint.sink);
+            }
+        }
+
+        let downloads: Vec<_> = checkpoints
+            .into_iter()
+            .map(|checkpoint| checkpoint.download)
+            .collect();
+        self.store.update_downloads(&downloads).await?;
+
+        Ok(())
+    }
+
+    /// Flush on the configured interval, meant to be spawned for as long as downloads run
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = self.flush().await {
+                event!(
+                    Level::ERROR,
+                    "Failed to checkpoint download progress: {}",
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sink::MutexSink,
+        store::{sqlite::tests::init_db, tests::running},
+    };
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn only_the_latest_progress_is_flushed() -> color_eyre::Result<()> {
+        let store: SharedDownloadStore = Arc::new(init_db()?);
+        let checkpointer = Checkpointer::new(store.clone(), DEFAULT_CHECKPOINT_INTERVAL);
+        let sink: Arc<dyn DownloadSink> = Arc::new(MutexSink::new(Cursor::new(vec![])));
+
+        let mut download = running(&*store, "https://www.google.com", &[(0, 5000)])
+            .await?
+            .remove(0);
+
+        download.offset = 1000;
+        download.total = 4000;
+        checkpointer.record(&download, sink.clone());
+        download.offset = 2000;
+        download.total = 3000;
+        checkpointer.record(&download, sink.clone());
+
+        // nothing reaches the store until a flush
+        assert_eq!(store.all_downloads().await?[0].offset, 0);
+
+        checkpointer.flush().await?;
+        let stored = &store.all_downloads().await?[0];
+        assert_eq!((stored.offset, stored.total), (2000, 3000));
+
+        Ok(())
+    }
+}
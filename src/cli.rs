@@ -0,0 +1,98 @@
+use crate::{
+    export::{self, Export},
+    get::Get,
+    store::SharedDownloadStore,
+};
+use clap::{Args, Parser, Subcommand};
+use std::{net::SocketAddr, path::PathBuf};
+use url::Url;
+
+/// A download manager, runs the server unless told to do something else
+#[derive(Debug, Parser)]
+#[clap(name = "sulfur", version)]
+pub struct Cli {
+    /// The configuration file, `sulfur.toml` in the working directory if there is one
+    #[clap(long, short, env = "SULFUR_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[clap(flatten)]
+    pub overrides: Overrides,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Settings that take precedence over the configuration file, from the command line or else the
+/// environment
+#[derive(Debug, Clone, Default, Args)]
+pub struct Overrides {
+    /// Where the API listens
+    #[clap(long, env = "SULFUR_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// The SQLite database the downloads are kept in
+    #[clap(long, env = "SULFUR_DATABASE")]
+    pub database: Option<PathBuf>,
+    /// Where downloads requested with a relative path go
+    #[clap(long, env = "SULFUR_DOWNLOAD_DIR")]
+    pub download_dir: Option<PathBuf>,
+    /// How many downloads run at once
+    #[clap(long, env = "SULFUR_MAX_CONCURRENT_DOWNLOADS")]
+    pub max_concurrent_downloads: Option<usize>,
+    /// Bytes per second shared by every download outside of the bandwidth schedule
+    #[clap(long, env = "SULFUR_RATE_LIMIT")]
+    pub rate_limit: Option<u64>,
+    /// The HTTP proxy every download goes through
+    #[clap(long, env = "SULFUR_PROXY")]
+    pub proxy: Option<Url>,
+    /// One of trace, debug, info, warn or error
+    #[clap(long, env = "SULFUR_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    /// What aria2 JSON-RPC clients have to send along as `token:<secret>`, only /jsonrpc checks
+    /// it and the rest of the API stays open
+    #[clap(long, env = "SULFUR_RPC_SECRET")]
+    pub rpc_secret: Option<String>,
+    /// A file of requests, one JSON object per line, to queue when starting, or - for stdin
+    #[clap(long, env = "SULFUR_REQUESTS")]
+    pub requests: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[clap(flatten)]
+    Store(StoreCommand),
+    /// Download a single file without running the server
+    Get(Get),
+}
+
+/// The subcommands that work on the daemon's store instead of running it, one-shot downloads keep
+/// their own
+#[derive(Debug, Subcommand)]
+pub enum StoreCommand {
+    /// Write every download to a JSON document, to stdout if no file is given
+    Export { file: Option<PathBuf> },
+    /// Recreate the downloads of a JSON document written by export
+    Import { file: PathBuf },
+}
+
+impl StoreCommand {
+    pub async fn run(self, store: SharedDownloadStore) -> color_eyre::Result<()> {
+        match self {
+            StoreCommand::Export { file } => {
+                let export = export::export(&*store).await?;
+                let text = serde_json::to_string_pretty(&export)?;
+
+                match file {
+                    Some(file) => tokio::fs::write(file, text).await?,
+                    None => println!("{}", text),
+                }
+            }
+            StoreCommand::Import { file } => {
+                let text = tokio::fs::read_to_string(&file).await?;
+                let export: Export = serde_json::from_str(&text)?;
+                let ids = export::import(&*store, &export).await?;
+
+                println!("Imported {} downloads from {:?}", ids.len(), file);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use fs2::FileExt;
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{event, Level};
+
+/// Once a filesystem ran out of space, downloads onto it resume only after this much is free again
+pub const DEFAULT_RESUME_THRESHOLD: u64 = 256 * 1024 * 1024;
+
+/// How often a full filesystem is checked for free space
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The directory whose filesystem a file at `path` ends up on. A bare file name lives in the
+/// current directory.
+pub fn containing_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// The number of bytes an unprivileged user can still write to the filesystem `path` is on
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    fs2::available_space(containing_directory(path))
+}
+
+/// Reserve `size` bytes for `file` up front, so scattered writes don't fragment it and running out
+/// of space happens now rather than halfway through a download.
+///
+/// Filesystems that can't reserve blocks (FAT, some network filesystems) get a sparse file of the
+/// right length instead.
+pub fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    match file.allocate(size) {
+        Ok(()) => Ok(()),
+        Err(e) if is_disk_full(&e) => Err(e),
+        Err(_) => file.set_len(size),
+    }
+}
+
+/// The device a file at `path` ends up on, two paths with the same id share the same free space
+pub fn filesystem_id(path: &Path) -> io::Result<u64> {
+    Ok(std::fs::metadata(containing_directory(path))?.dev())
+}
+
+/// Whether `e` means the filesystem has no space left
+pub fn is_disk_full(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOSPC)
+}
+
+/// Keeps track of which filesystems ran out of space. Downloads writing to a full filesystem are
+/// paused together and resumed once enough space is free again.
+#[derive(Debug)]
+pub struct DiskSpaceMonitor {
+    resume_threshold: u64,
+    poll_interval: Duration,
+    filesystems: Mutex<HashMap<u64, WatchedFilesystem>>,
+}
+
+pub type SharedDiskSpaceMonitor = Arc<DiskSpaceMonitor>;
+
+#[derive(Debug)]
+struct WatchedFilesystem {
+    directory: PathBuf,
+    paused: watch::Sender<bool>,
+}
+
+/// What a download sees of the filesystem it writes to
+#[derive(Debug)]
+pub struct DiskWatch {
+    pub filesystem: u64,
+    paused: watch::Receiver<bool>,
+}
+
+/// The state of a filesystem downloads are writing to, as reported by the API
+#[derive(Debug, Serialize)]
+pub struct FilesystemStatus {
+    pub directory: PathBuf,
+    pub paused: bool,
+    pub available: Option<u64>,
+    pub resume_threshold: u64,
+}
+
+impl DiskSpaceMonitor {
+    pub fn new(resume_threshold: u64, poll_interval: Duration) -> Self {
+        Self {
+            resume_threshold,
+            poll_interval,
+            filesystems: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start watching the filesystem a file at `path` is written to
+    pub fn watch(&self, path: &Path) -> io::Result<DiskWatch> {
+        let id = filesystem_id(path)?;
+
+        let mut filesystems = self.filesystems.lock().unwrap();
+        let filesystem = filesystems.entry(id).or_insert_with(|| WatchedFilesystem {
+            directory: containing_directory(path).to_path_buf(),
+            paused: watch::channel(false).0,
+        });
+
+        Ok(DiskWatch {
+            filesystem: id,
+            paused: filesystem.paused.subscribe(),
+        })
+    }
+
+    /// Pause everything writing to `filesystem` until space frees up
+    pub fn pause(&self, filesystem: u64) {
+        if let Some(watched) = self.filesystems.lock().unwrap().get(&filesystem) {
+            if !watched.paused.send_replace(true) {
+                event!(
+                    Level::WARN,
+                    "{:?} is out of space, pausing every download on it",
+                    watched.directory
+                );
+            }
+        }
+    }
+
+    /// Resume the filesystems that have enough free space again, forgetting about the ones no
+    /// download is interested in anymore
+    pub fn poll(&self) {
+        let mut filesystems = self.filesystems.lock().unwrap();
+        filesystems.retain(|_, watched| watched.paused.receiver_count() > 0);
+
+        for watched in filesystems.values() {
+            if !*watched.paused.borrow() {
+                continue;
+            }
+
+            match fs2::available_space(&watched.directory) {
+                Ok(available) if available >= self.resume_threshold => {
+                    event!(
+                        Level::INFO,
+                        "{:?} has {} bytes free again, resuming downloads",
+                        watched.directory,
+                        available
+                    );
+                    watched.paused.send_replace(false);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    event!(Level::ERROR, "Failed to check {:?}: {}", watched.directory, e);
+                }
+            }
+        }
+    }
+
+    /// Keep polling the full filesystems, meant to be spawned for as long as downloads run
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.poll_interval);
+        loop {
+            interval.tick().await;
+            self.poll();
+        }
+    }
+
+    pub fn status(&self) -> Vec<FilesystemStatus> {
+        self.filesystems
+            .lock()
+            .unwrap()
+            .values()
+            .map(|watched| FilesystemStatus {
+                directory: watched.directory.clone(),
+                paused: *watched.paused.borrow(),
+                available: fs2::available_space(&watched.directory).ok(),
+                resume_threshold: self.resume_threshold,
+            })
+            .collect()
+    }
+}
+
+impl Default for DiskSpaceMonitor {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESUME_THRESHOLD, DEFAULT_POLL_INTERVAL)
+    }
+}
+
+impl DiskWatch {
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Wait for the filesystem to be writable
+    pub async fn writable(&mut self) {
+        while self.is_paused() {
+            if self.paused.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Wait for the filesystem to be paused, never returns if it doesn't happen
+    pub async fn paused(&mut self) {
+        while !self.is_paused() {
+            if self.paused.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn containing_directory_of_bare_file_name_is_current_directory() {
+        assert_eq!(containing_directory(Path::new("file.iso")), Path::new("."));
+        assert_eq!(containing_directory(Path::new("/tmp/file.iso")), Path::new("/tmp"));
+    }
+
+    #[test]
+    fn preallocate_sets_the_length() -> color_eyre::Result<()> {
+        let path = std::env::temp_dir().join("sulfur-preallocate-test");
+        let file = File::create(&path)?;
+
+        preallocate(&file, 4096)?;
+        let len = file.metadata()?.len();
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(len, 4096);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn monitor_pauses_and_resumes_the_whole_filesystem() -> color_eyre::Result<()> {
+        let directory = std::env::temp_dir();
+
+        // a threshold of zero means any amount of free space is enough to resume
+        let monitor = DiskSpaceMonitor::new(0, DEFAULT_POLL_INTERVAL);
+        let mut first = monitor.watch(&directory.join("first"))?;
+        let second = monitor.watch(&directory.join("second"))?;
+        assert_eq!(first.filesystem, second.filesystem);
+
+        monitor.pause(first.filesystem);
+        assert!(first.is_paused());
+        assert!(second.is_paused());
+        assert!(monitor.status()[0].paused);
+
+        monitor.poll();
+        first.writable().await;
+        assert!(!second.is_paused());
+
+        Ok(())
+    }
+}
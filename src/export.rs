@@ -0,0 +1,198 @@
+use crate::{
+    http::DownloadContext,
+    store::{unix_now, DownloadRecord, DownloadState, DownloadStore, StoreError},
+};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Arc};
+
+/// The version of the export format written by this build. Bump it whenever a document written
+/// by an older build can't be read as is anymore.
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Every download of a store, enough to recreate them in another one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    /// Seconds since the unix epoch
+    pub exported_at: u64,
+    pub downloads: Vec<ExportedDownload>,
+}
+
+/// A download along with what is left of each of its subdownloads
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedDownload {
+    #[serde(flatten)]
+    pub record: DownloadRecord,
+    #[serde(default)]
+    pub segments: Vec<ExportedSegment>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedSegment {
+    pub offset: u64,
+    pub total: u64,
+}
+
+/// Take every download out of `store`
+pub async fn export(store: &dyn DownloadStore) -> Result<Export, StoreError> {
+    let mut downloads = vec![];
+    for record in store.downloads().await? {
+        let segments = store
+            .segments(record.id)
+            .await?
+            .iter()
+            .map(|segment| ExportedSegment {
+                offset: segment.offset,
+                total: segment.total,
+            })
+            .collect();
+
+        downloads.push(ExportedDownload { record, segments });
+    }
+
+    Ok(Export {
+        version: EXPORT_VERSION,
+        exported_at: unix_now(),
+        downloads,
+    })
+}
+
+/// Recreate every download of `export` in `store`, all or nothing, returning their new ids in
+/// order. Downloads that were going when they were exported are queued again with what they had
+/// left, nothing would pick them up otherwise until the next restart.
+pub async fn import(store: &dyn DownloadStore, export: &Export) -> Result<Vec<i32>, StoreError> {
+    if export.version != EXPORT_VERSION {
+        return Err(StoreError::Unsupported(format!(
+            "export version {}, only version {} can be imported",
+            export.version, EXPORT_VERSION
+        )));
+    }
+
+    let downloads: Vec<_> = export
+        .downloads
+        .iter()
+        .map(|download| {
+            let mut record = download.record.clone();
+            if matches!(
+                record.state,
+                DownloadState::Probing | DownloadState::Running | DownloadState::Verifying
+            ) {
+                record.state = DownloadState::Queued;
+            }
+
+            let url = Arc::new(record.url.clone());
+            let file_path: Arc<Path> = Arc::from(record.file_path.clone());
+            let segments = download
+                .segments
+                .iter()
+                .map(|segment| DownloadContext {
+                    id: -1,
+                    download_id: -1,
+                    url: url.clone(),
+                    offset: segment.offset,
+                    total: segment.total,
+                    file_path: file_path.clone(),
+                })
+                .collect();
+
+            (record, segments)
+        })
+        .collect();
+
+    store.create_downloads(&downloads).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{
+        memory::MemoryStore,
+        sqlite::tests::init_db,
+        tests::{record, running},
+        DownloadState,
+    };
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn export_survives_a_round_trip_to_another_store() -> color_eyre::Result<()> {
+        let source = MemoryStore::new();
+        running(
+            &source,
+            "https://www.google.com",
+            &[(0, 5000), (5000, 5000)],
+        )
+        .await?;
+        let mut completed = record("https://www.bing.com", DownloadState::Completed);
+        completed.checksum = Some("abc".to_string());
+        source.create_download(&completed, &[]).await?;
+
+        let text = serde_json::to_string(&export(&source).await?)?;
+        let document: Export = serde_json::from_str(&text)?;
+
+        let destination = init_db()?;
+        let ids = import(&destination, &document).await?;
+        assert_eq!(ids.len(), 2);
+
+        let imported = export(&destination).await?;
+        for (exported, imported) in document.downloads.iter().zip(&imported.downloads) {
+            assert_eq!(imported.segments, exported.segments);
+            assert_eq!(imported.record.url, exported.record.url);
+            assert_eq!(imported.record.checksum, exported.record.checksum);
+        }
+        // nothing drives the running one in its new home, it has to wait its turn
+        assert_eq!(
+            imported
+                .downloads
+                .iter()
+                .map(|download| download.record.state)
+                .collect::<Vec<_>>(),
+            vec![DownloadState::Queued, DownloadState::Completed]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn imports_are_all_or_nothing() -> color_eyre::Result<()> {
+        let queued = ExportedDownload {
+            record: record("https://www.google.com", DownloadState::Queued),
+            segments: vec![],
+        };
+        // sqlite can't hold an offset that large
+        let broken = ExportedDownload {
+            segments: vec![ExportedSegment {
+                offset: u64::MAX,
+                total: 1,
+            }],
+            ..queued.clone()
+        };
+        let document = Export {
+            version: EXPORT_VERSION,
+            exported_at: 0,
+            downloads: vec![queued, broken],
+        };
+
+        let store = init_db()?;
+        assert!(import(&store, &document).await.is_err());
+        assert_eq!(store.downloads().await?, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_versions_are_refused() -> color_eyre::Result<()> {
+        let store = MemoryStore::new();
+        let document = Export {
+            version: EXPORT_VERSION + 1,
+            exported_at: 0,
+            downloads: vec![],
+        };
+
+        assert!(matches!(
+            import(&store, &document).await,
+            Err(StoreError::Unsupported(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+//! Bytes and headers as people write them. sulfur-ctl includes this file as well, so it only
+//! uses the standard library.
+
+/// `Name: value`
+pub fn parse_header(text: &str) -> Result<(String, String), String> {
+    let (name, value) = text
+        .split_once(':')
+        .ok_or_else(|| format!("{} isn't a header like \"Name: value\"", text))?;
+
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+/// `1536` as `1.5 KiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
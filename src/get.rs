@@ -0,0 +1,343 @@
+use crate::{
+    api::v1::{status_of, DownloadStatus},
+    config::Config,
+    format::{format_bytes, parse_header},
+    http::HttpDownloader,
+    request::http::{file_name_of, DownloadOptions, HttpRequest},
+    speed::Speeds,
+    store::{
+        sqlite::SqliteStore, DownloadRecord, DownloadState, DownloadStore, SharedDownloadStore,
+        StoreError,
+    },
+};
+use clap::Args;
+use hyper::client::connect::Connect;
+use std::{
+    fmt::{Debug, Display, Formatter},
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::oneshot;
+use url::Url;
+
+/// The extension of the store a one-shot download keeps next to its file until it is done
+pub const STATE_FILE_EXTENSION: &str = "sulfur-state";
+
+/// How often the progress is looked at
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Download a single file without a daemon, e.g. `sulfur get https://example.com/file.iso`. Run
+/// it again after it was interrupted or failed to pick up where it left off.
+#[derive(Debug, Args)]
+pub struct Get {
+    url: Url,
+    /// Where the file goes, the file name of the url in the working directory if not given
+    #[clap(long, short = 'o')]
+    output: Option<PathBuf>,
+    /// How many connections the download is split over, one per CPU core if not given
+    #[clap(long)]
+    connections: Option<u32>,
+    /// Sent along with every request, e.g. "Cookie: session=1234"
+    #[clap(long = "header", short = 'H', parse(try_from_str = parse_header))]
+    headers: Vec<(String, String)>,
+    /// The hex encoded sha-256 the finished file must have
+    #[clap(long)]
+    checksum: Option<String>,
+    /// Don't show the progress
+    #[clap(long, short = 'q')]
+    quiet: bool,
+}
+
+/// The store a one-shot download of `path` keeps its progress in, e.g. `name.iso` has
+/// `name.iso.sulfur-state`
+pub fn state_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(STATE_FILE_EXTENSION);
+
+    path.with_file_name(file_name)
+}
+
+#[derive(Debug)]
+pub enum GetError {
+    /// We were asked for something that can't be done
+    Invalid(String),
+    Store(StoreError),
+    /// The download failed, running it again resumes it
+    Failed(String),
+    /// We were told to stop, running it again resumes it
+    Interrupted,
+}
+
+impl GetError {
+    /// What the process exits with, so scripts can tell what went wrong
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GetError::Store(_) => 1,
+            GetError::Invalid(_) => 2,
+            GetError::Failed(_) => 3,
+            // like anything else killed by SIGINT
+            GetError::Interrupted => 130,
+        }
+    }
+}
+
+impl Display for GetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetError::Invalid(reason) => write!(f, "{}", reason),
+            GetError::Store(e) => write!(f, "{}", e),
+            GetError::Failed(reason) => {
+                write!(f, "The download failed, run it again to retry: {}", reason)
+            }
+            GetError::Interrupted => write!(f, "Interrupted, run it again to resume"),
+        }
+    }
+}
+
+impl std::error::Error for GetError {}
+
+impl From<StoreError> for GetError {
+    fn from(e: StoreError) -> Self {
+        GetError::Store(e)
+    }
+}
+
+/// A progress bar on stderr, only drawn when there's a terminal to draw it on
+struct Progress {
+    visible: bool,
+}
+
+impl Progress {
+    const WIDTH: u64 = 30;
+
+    fn new(quiet: bool) -> Self {
+        Self {
+            visible: !quiet && std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn show(&self, status: &DownloadStatus) {
+        if !self.visible {
+            return;
+        }
+
+        let downloaded = status.downloaded.unwrap_or_default();
+        let line = match status.record.total_size {
+            Some(total) if total > 0 => {
+                let filled = (downloaded * Self::WIDTH / total) as usize;
+                format!(
+                    "[{}{}] {:>3}%  {} / {}  {}/s",
+                    "#".repeat(filled),
+                    ".".repeat(Self::WIDTH as usize - filled),
+                    downloaded * 100 / total,
+                    format_bytes(downloaded),
+                    format_bytes(total),
+                    format_bytes(status.speed.unwrap_or_default())
+                )
+            }
+            _ => format!("{}...", status.record.state),
+        };
+        let mut stderr = std::io::stderr();
+        let _ = write!(stderr, "\r{:<80}", line);
+        let _ = stderr.flush();
+    }
+
+    fn finish(&self) {
+        if self.visible {
+            eprintln!();
+        }
+    }
+}
+
+/// What a previous run left of the download of `request`, if anything
+async fn previous_run(
+    store: &dyn DownloadStore,
+    request: &HttpRequest,
+) -> Result<Option<DownloadRecord>, StoreError> {
+    Ok(store.downloads().await?.into_iter().find(|record| {
+        record.url == request.url
+            && record.file_path == request.path
+            && record.state != DownloadState::Cancelled
+    }))
+}
+
+impl Get {
+    /// Download the file, returning where it ended up. Its progress is kept next to it until it
+    /// is complete.
+    pub async fn run<C>(self, config: &Config, connector: C) -> Result<PathBuf, GetError>
+    where
+        C: Connect + Clone + Send + Sync + Debug + 'static,
+    {
+        let path = match self.output {
+            Some(output) => output,
+            None => file_name_of(&self.url).map(PathBuf::from).ok_or_else(|| {
+                GetError::Invalid(format!(
+                    "Can't tell what to call the file of {}, give it a name with -o",
+                    self.url
+                ))
+            })?,
+        };
+        let state = state_path(&path);
+
+        let store: SharedDownloadStore = Arc::new(SqliteStore::open(&state.to_string_lossy())?);
+        let (downloader, _sources) = HttpDownloader::with_request_sources(store.clone());
+        let downloader = Arc::new(
+            downloader
+                .with_rate_limit(config.limits.rate)
+                .with_checkpoint_interval(config.checkpoint_interval())
+                .with_shutdown_timeout(config.shutdown_timeout()),
+        );
+
+        let request = HttpRequest {
+            url: self.url,
+            path: path.clone(),
+            options: DownloadOptions {
+                connections: self.connections,
+                headers: self.headers.into_iter().collect(),
+                checksum: self.checksum,
+                ..DownloadOptions::default()
+            },
+            origin: Some("get".to_string()),
+        };
+        let id = match previous_run(&*store, &request).await? {
+            Some(record) => {
+                // running ones are queued again by the downloader itself
+                if matches!(record.state, DownloadState::Failed | DownloadState::Paused) {
+                    downloader.resume(record.id).await?;
+                }
+                record.id
+            }
+            None => downloader.queue().enqueue(&request).await?,
+        };
+
+        let (stop, stop_token) = oneshot::channel();
+        let running = tokio::spawn({
+            let downloader = downloader.clone();
+            async move {
+                downloader
+                    .run(connector.clone(), connector, stop_token)
+                    .await
+            }
+        });
+
+        let progress = Progress::new(self.quiet);
+        let speeds = Speeds::default();
+        let interrupt = tokio::signal::ctrl_c();
+        tokio::pin!(interrupt);
+        let (record, interrupted) = loop {
+            let interrupted = tokio::select! {
+                _ = &mut interrupt => true,
+                _ = tokio::time::sleep(PROGRESS_INTERVAL) => false,
+            };
+
+            let status = status_of(&store, &speeds, store.download(id).await?).await?;
+            progress.show(&status);
+            if interrupted || status.record.state.is_finished() {
+                break (status.record, interrupted);
+            }
+        };
+        progress.finish();
+
+        // the progress is written down before we go, whatever happened
+        let _ = stop.send(true);
+        let _ = running.await;
+
+        if interrupted {
+            return Err(GetError::Interrupted);
+        }
+        match record.state {
+            DownloadState::Completed => {
+                drop((downloader, store));
+                let _ = tokio::fs::remove_file(&state).await;
+
+                Ok(path)
+            }
+            _ => Err(GetError::Failed(record.error.unwrap_or_default())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        client::HttpConnector,
+        header,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use pretty_assertions::assert_eq;
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn one_shot_downloads_clean_up_after_themselves() -> color_eyre::Result<()> {
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        // a server that answers every range it is asked for
+        let served = content.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let served = served.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let range = request
+                        .headers()
+                        .get(header::RANGE)
+                        .and_then(|range| range.to_str().ok())
+                        .and_then(|range| range.strip_prefix("bytes="))
+                        .and_then(|range| range.split_once('-'))
+                        .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+                    let response = match range {
+                        Some((start, end)) => Response::builder()
+                            .status(StatusCode::PARTIAL_CONTENT)
+                            .header(
+                                header::CONTENT_RANGE,
+                                format!("bytes {}-{}/{}", start, end, served.len()),
+                            )
+                            .body(Body::from(served[start..=end].to_vec())),
+                        None => Response::builder()
+                            .header(header::CONTENT_LENGTH, served.len())
+                            .body(Body::empty()),
+                    };
+
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                }))
+            }
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let path = std::env::temp_dir().join("sulfur-get-test.bin");
+        let _ = tokio::fs::remove_file(&path).await;
+        let _ = tokio::fs::remove_file(state_path(&path)).await;
+        let get = Get {
+            url: Url::parse(&format!("http://{}/file.bin", address))?,
+            output: Some(path.clone()),
+            connections: Some(3),
+            headers: vec![],
+            checksum: None,
+            quiet: true,
+        };
+
+        let downloaded = get.run(&Config::default(), HttpConnector::new()).await?;
+
+        assert_eq!(downloaded, path);
+        assert_eq!(tokio::fs::read(&path).await?, content);
+        assert!(!state_path(&path).exists());
+
+        tokio::fs::remove_file(&path).await?;
+        server.abort();
+
+        Ok(())
+    }
+
+    #[test]
+    fn the_state_is_kept_next_to_the_file() {
+        assert_eq!(
+            state_path(Path::new("/tmp/ubuntu.iso")),
+            PathBuf::from("/tmp/ubuntu.iso.sulfur-state")
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::store::{unix_now, DownloadRecord, DownloadState, DownloadStore, StoreError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Which finished downloads to look at, everything that isn't given matches
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct HistoryFilter {
+    /// Only completed or only failed downloads
+    pub state: Option<DownloadState>,
+    /// Only downloads whose url contains this
+    pub url: Option<String>,
+    /// Finished at or after, in seconds since the unix epoch
+    pub since: Option<u64>,
+    /// Finished before, in seconds since the unix epoch
+    pub until: Option<u64>,
+    /// At most this many, the most recent first
+    pub limit: Option<usize>,
+}
+
+impl HistoryFilter {
+    /// The states a download is kept in the history with
+    pub const STATES: [DownloadState; 2] = [DownloadState::Completed, DownloadState::Failed];
+
+    pub fn matches(&self, record: &DownloadRecord) -> bool {
+        let finished_at = match record.completed_at {
+            Some(finished_at) => finished_at,
+            None => return false,
+        };
+
+        Self::STATES.contains(&record.state)
+            && self.state.map_or(true, |state| state == record.state)
+            && self
+                .url
+                .as_ref()
+                .map_or(true, |url| record.url.as_str().contains(url.as_str()))
+            && self.since.map_or(true, |since| finished_at >= since)
+            && self.until.map_or(true, |until| finished_at < until)
+    }
+}
+
+/// A finished download along with how it went
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub record: DownloadRecord,
+    /// Seconds from the last time it started until it finished
+    pub duration: Option<u64>,
+    /// Bytes per second over the whole duration
+    pub average_speed: Option<u64>,
+}
+
+impl From<DownloadRecord> for HistoryEntry {
+    fn from(record: DownloadRecord) -> Self {
+        let duration = record
+            .started_at
+            .zip(record.completed_at)
+            .map(|(started_at, completed_at)| completed_at.saturating_sub(started_at));
+        // anything faster than a second took a second as far as we can tell
+        let average_speed = record
+            .total_size
+            .zip(duration)
+            .map(|(size, duration)| size / duration.max(1));
+
+        Self {
+            record,
+            duration,
+            average_speed,
+        }
+    }
+}
+
+/// How much of the history is kept around, all of it unless told otherwise
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Seconds a finished download is kept for
+    pub max_age: Option<u64>,
+    /// How many finished downloads are kept, the oldest go first
+    pub max_count: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn keeps_everything(&self) -> bool {
+        self.max_age.is_none() && self.max_count.is_none()
+    }
+
+    /// Remove the finished downloads the policy doesn't keep, returning how many there were
+    pub async fn apply(&self, store: &dyn DownloadStore) -> Result<usize, StoreError> {
+        if self.keeps_everything() {
+            return Ok(0);
+        }
+
+        let finished_before = self
+            .max_age
+            .map(|max_age| unix_now().saturating_sub(max_age));
+
+        store.prune_history(finished_before, self.max_count).await
+    }
+}
+
+/// Whether `prune_history` removes the download that finished at `finished_at`, `position` being
+/// how many finished after it
+pub(crate) fn is_pruned(
+    position: usize,
+    finished_at: u64,
+    finished_before: Option<u64>,
+    keep: Option<usize>,
+) -> bool {
+    finished_before.is_some_and(|finished_before| finished_at < finished_before)
+        || keep.is_some_and(|keep| position >= keep)
+}
+
+/// The hex encoded sha-256 of the file at `path`. Reading a large file takes a while, so it is
+/// done on the blocking thread pool.
+pub async fn checksum_file(path: &Path) -> io::Result<String> {
+    let path = PathBuf::from(path);
+
+    tokio::task::spawn_blocking(move || {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; 1 << 16];
+
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(hex::encode(hasher.finalize()))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::record;
+    use pretty_assertions::assert_eq;
+
+    fn finished(url: &str, state: DownloadState, completed_at: u64) -> DownloadRecord {
+        DownloadRecord {
+            state,
+            started_at: Some(completed_at - 10),
+            completed_at: Some(completed_at),
+            total_size: Some(5000),
+            ..record(url, DownloadState::Queued)
+        }
+    }
+
+    #[test]
+    fn filter_matches_only_finished_downloads() {
+        let completed = finished("https://www.google.com", DownloadState::Completed, 100);
+        let failed = finished("https://www.bing.com", DownloadState::Failed, 200);
+        let running = record("https://www.google.com", DownloadState::Running);
+
+        let everything = HistoryFilter::default();
+        assert!(everything.matches(&completed));
+        assert!(everything.matches(&failed));
+        assert!(!everything.matches(&running));
+
+        let failures = HistoryFilter {
+            state: Some(DownloadState::Failed),
+            ..HistoryFilter::default()
+        };
+        assert!(!failures.matches(&completed));
+        assert!(failures.matches(&failed));
+
+        let google = HistoryFilter {
+            url: Some("google".to_string()),
+            since: Some(100),
+            until: Some(200),
+            ..HistoryFilter::default()
+        };
+        assert!(google.matches(&completed));
+        assert!(!google.matches(&failed));
+    }
+
+    #[test]
+    fn entry_knows_how_fast_the_download_was() {
+        let entry = HistoryEntry::from(finished(
+            "https://www.google.com",
+            DownloadState::Completed,
+            100,
+        ));
+
+        assert_eq!(entry.duration, Some(10));
+        assert_eq!(entry.average_speed, Some(500));
+    }
+
+    #[tokio::test]
+    async fn checksum_is_the_sha256_of_the_file() -> color_eyre::Result<()> {
+        let path = std::env::temp_dir().join("sulfur-checksum-test");
+        tokio::fs::write(&path, b"abc").await?;
+
+        assert_eq!(
+            checksum_file(&path).await?,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        tokio::fs::remove_file(&path).await?;
+
+        Ok(())
+    }
+}
@@ -1,373 +1,122 @@
 use crate::{
-    request::http::{HttpRequest, HttpRequestSource},
-    schema::*,
-    util::{last_insert_rowid, EnableForeignKeys},
+    checkpoint::{Checkpointer, DEFAULT_CHECKPOINT_INTERVAL},
+    disk::{self, DiskSpaceMonitor, DiskWatch, SharedDiskSpaceMonitor},
+    history::{checksum_file, RetentionPolicy},
+    queue::DownloadQueue,
+    request::{
+        http::{DownloadOptions, HttpRequest, HttpRequestSource},
+        merged::{MergedHttpRequestSource, RequestSources},
+    },
+    schedule::{local_now, BandwidthSchedule, RateLimiter, TimeWindow, DEFAULT_SCHEDULE_INTERVAL},
+    sink::{DownloadSink, FileSink},
+    store::{DownloadRecord, DownloadState, StoreError},
 };
 use ::url::Url as WgUrl;
+use chrono::NaiveDateTime;
 use derive_more::{Display, Error};
-use diesel::{
-    r2d2,
-    r2d2::{ConnectionManager, Pool},
-    ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection,
-};
 use futures::future::join_all;
+use headers::{ContentRange, HeaderMapExt};
 use hyper::{
     body::HttpBody,
     client::{connect::Connect, HttpConnector},
-    Body, Client, Request, Version,
+    Body, Client, Request, StatusCode, Version,
 };
 use hyper_rustls::HttpsConnectorBuilder;
 use serde::ser::StdError;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     error::Error,
     fmt::{Debug, Display, Formatter},
     future::Future,
     num::ParseIntError,
-    ops::DerefMut,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tokio::{
-    fs::OpenOptions,
-    io::{AsyncSeekExt, AsyncWriteExt, SeekFrom},
-    sync::{oneshot::Receiver, Mutex},
+    sync::{oneshot::Receiver, watch, Mutex},
     task::JoinHandle,
 };
 use tracing::{event, Level};
 
+pub use crate::store::{DownloadStore, SharedDownloadStore};
+
+/// The request a download in the store was made from
+fn request_of(record: &DownloadRecord) -> HttpRequest {
+    HttpRequest {
+        url: record.url.clone(),
+        path: record.file_path.clone(),
+        options: record.options.clone(),
+        origin: record.origin.clone(),
+    }
+}
+
 #[derive(Debug, Display, Error)]
 enum ParseError {
     UnsupportedSchema,
 }
 
 /// A `DownloadContext` allow us to record all the states required to persist and restart a download.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DownloadContext {
     // having an id here directly is not great, but without it, we don't know which row to update
     // multiple subdownloads will share the same file path and url; offset and total will be changed
     // by then so we can't do a look up with them. Having an id is the easiest way to solve this
     // issue.
     pub id: i32,
+    /// The download this is a part of
+    pub download_id: i32,
     pub url: Arc<WgUrl>,
-    pub offset: usize,
-    pub total: usize,
+    pub offset: u64,
+    pub total: u64,
     pub file_path: Arc<Path>,
 }
 
-#[derive(Debug, Queryable, Insertable, Identifiable, Associations)]
-#[table_name = "file_path"]
-#[primary_key(path)]
-struct PathTable {
-    pub path: String,
-}
-
-#[derive(Debug)]
-struct PathConversionError {
-    invalid_path: bool,
-    not_file: bool,
-}
-
-impl Display for PathConversionError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let path_err = if self.invalid_path {
-            "Invalid path"
-        } else {
-            " "
-        };
-        let file_err = if self.not_file { "Not a file" } else { " " };
-        let err_msg = [path_err, file_err].join(" and ");
-        write!(f, "{}", err_msg.trim())
-    }
-}
-
-impl Error for PathConversionError {}
-
-impl TryInto<PathBuf> for PathTable {
-    type Error = PathConversionError;
-
-    fn try_into(self) -> Result<PathBuf, Self::Error> {
-        let mut path = PathBuf::new();
-        path.push(self.path);
-
-        let conversion_err = PathConversionError {
-            invalid_path: !path.exists(),
-            not_file: !path.is_file(),
-        };
-
-        if !conversion_err.not_file && !conversion_err.invalid_path {
-            Ok(path)
-        } else {
-            Err(conversion_err)
-        }
-    }
+/// A request for `url` carrying the extra headers of the download
+fn request_builder(url: &WgUrl, options: &DownloadOptions) -> hyper::http::request::Builder {
+    options.headers.iter().fold(
+        Request::builder().uri(url.as_str()),
+        |builder, (name, value)| builder.header(name.as_str(), value.as_str()),
+    )
 }
 
-impl From<&PathBuf> for PathTable {
-    fn from(path: &PathBuf) -> Self {
-        PathTable {
-            path: path.to_str().unwrap().to_string(),
-        }
-    }
-}
-
-#[derive(Debug, Queryable, Insertable, Identifiable, Associations)]
-#[table_name = "url"]
-#[primary_key(full_text)]
-struct UrlTable {
-    pub full_text: String,
-}
-
-impl From<&WgUrl> for UrlTable {
-    fn from(url: &WgUrl) -> Self {
-        UrlTable {
-            full_text: url.to_string(),
-        }
-    }
-}
-
-impl TryInto<WgUrl> for UrlTable {
-    type Error = ::url::ParseError;
-
-    fn try_into(self) -> Result<WgUrl, Self::Error> {
-        WgUrl::parse(&self.full_text)
-    }
-}
-
-#[derive(Debug, Queryable, Insertable, Identifiable, Associations, AsChangeset)]
-#[table_name = "http_subdownload"]
-#[belongs_to(UrlTable, foreign_key = "url")]
-struct SubDownloadTable {
-    pub id: i32,
-    pub url: String,
-    pub offset: i32,
-    pub total: i32,
-    pub file_path: String,
-}
-
-#[derive(Debug, Display, Error)]
-enum StoreError {
-    NotFound,
-}
-
-pub trait DownloadStore: Debug {
-    fn add_download(&self, download: &DownloadContext) -> Result<i32, diesel::result::Error>;
-    // todo: perhaps it should take an id?
-    fn update_download(&self, download: &DownloadContext) -> Result<(), diesel::result::Error>;
-    fn downloads_by_url(
-        &self,
-        wg_url: &WgUrl,
-    ) -> Result<Vec<DownloadContext>, diesel::result::Error>;
-    fn remove_by_url(&self, wg_url: &WgUrl) -> Result<(), diesel::result::Error>;
-    fn remove_by_id(&self, id: i32) -> Result<(), diesel::result::Error>;
-}
-
-pub type SharedDownloadStore = Arc<dyn DownloadStore + Send + Sync>;
-
-struct SqliteStore {
-    pool: Pool<ConnectionManager<SqliteConnection>>,
-}
-
-#[allow(unused_variables)]
-impl Debug for SqliteStore {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "FIXED FFS")
-    }
-}
-
-impl SqliteStore {
-    pub fn new(database_url: &str) -> Result<Self, r2d2::PoolError> {
-        let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-        let pool = Pool::builder()
-            .max_size(32)
-            .connection_customizer(Box::new(EnableForeignKeys::new()))
-            .build(manager)?;
-
-        Ok(Self { pool })
-    }
-}
-
-impl DownloadStore for SqliteStore {
-    fn add_download(&self, download: &DownloadContext) -> Result<i32, diesel::result::Error> {
-        // find if the url already exists
-        let conn = self.pool.get().expect("Failed to get connection");
-
-        use crate::schema::url::dsl::*;
-        let url_query = url.filter(full_text.eq(download.url.as_str())).limit(1);
-
-        // find if the file path already exists
-        use crate::schema::file_path::dsl::*;
-        let file_path_query = file_path
-            .filter(path.eq((*download.file_path).as_os_str().to_string_lossy()))
-            .limit(1);
-
-        conn.exclusive_transaction(|| {
-            let url_res = url_query.first::<UrlTable>(&conn);
-            let file_path_res = file_path_query.first::<PathTable>(&conn);
-
-            match (url_res, file_path_res) {
-                // they both exist, then we can just add the download
-                (Ok(_), Ok(_)) => {}
-                // both of them don't exist, we need to crate them first to fulfill the relationship
-                (Err(diesel::result::Error::NotFound), Err(diesel::result::Error::NotFound)) => {
-                    let url_table = UrlTable {
-                        full_text: download.url.to_string(),
-                    };
-                    let file_path_table = PathTable {
-                        path: (*download.file_path).to_string_lossy().to_string(),
-                    };
-
-                    // I don't know why all the sudden I need to use the full path here, and I hate it
-                    diesel::insert_into(crate::schema::url::dsl::url)
-                        .values(url_table)
-                        .execute(&conn)?;
-                    diesel::insert_into(crate::schema::file_path::dsl::file_path)
-                        .values(file_path_table)
-                        .execute(&conn)?;
-                }
-
-                // if somehow only one of them exist, then the program is already broken
-                (a, b) => {
-                    eprintln!("{:?}, {:?}", a, b);
-                    unreachable!()
-                }
-            };
-
-            use crate::schema::http_subdownload::dsl::*;
-            let insert = diesel::insert_into(http_subdownload).values((
-                url.eq(download.url.as_str()),
-                offset.eq(download.offset as i32),
-                total.eq(download.total as i32),
-                file_path.eq(download
-                    .file_path
-                    .to_str()
-                    .expect("file path is not utf8????")),
-            ));
-
-            insert.execute(&conn)?;
-
-            let row_id = diesel::select(last_insert_rowid).first(&conn)?;
-
-            Ok(row_id)
-        })
-    }
-
-    fn update_download(&self, download: &DownloadContext) -> Result<(), diesel::result::Error> {
-        let conn = self.pool.get().expect("Failed to get connection");
-
-        // construct the table equivalent of download (the subdownload struct itself cannot be
-        // directly used with diesel, and update it
-
-        let sql_form = SubDownloadTable {
-            id: download.id,
-            url: download.url.to_string(),
-            offset: download.offset as i32,
-            total: download.total as i32,
-            file_path: download
-                .file_path
-                .to_str()
-                .expect("file path is not utf8????")
-                .to_string(),
-        };
-
-        conn.exclusive_transaction(|| {
-            use crate::schema::http_subdownload::dsl::*;
-            diesel::update(http_subdownload)
-                .set(&sql_form)
-                .execute(&conn)?;
-
-            Ok(())
-        })
-    }
-
-    fn downloads_by_url(
-        &self,
-        wg_url: &WgUrl,
-    ) -> Result<Vec<DownloadContext>, diesel::result::Error> {
-        let conn = self.pool.get().expect("Failed to get connection");
-
-        conn.exclusive_transaction(|| {
-            use crate::schema::http_subdownload::dsl::*;
+/// The extension appended to a file while it is still being downloaded
+pub const PART_FILE_EXTENSION: &str = "sulfur-part";
 
-            let result = http_subdownload
-                .filter(url.eq(wg_url.as_str()))
-                .load::<SubDownloadTable>(&conn)?;
-            // assume result is not empty, if it is, then it should be an error and already returned
+/// The temporary sibling of `path` that a download writes into until it is complete, e.g.
+/// `name.iso` becomes `name.iso.sulfur-part`
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(PART_FILE_EXTENSION);
 
-            let url_p = Arc::new(
-                WgUrl::parse(&result.first().unwrap().url)
-                    .expect("database corrupted, url should be valid"),
-            );
-
-            let mut path = PathBuf::new();
-            path.push(&result.first().unwrap().file_path);
-
-            let path = Arc::from(path);
-
-            Ok(result
-                .iter()
-                .map(|x| DownloadContext {
-                    id: x.id,
-                    url: Arc::clone(&url_p),
-                    offset: x.offset as usize,
-                    total: x.total as usize,
-                    file_path: Arc::clone(&path),
-                })
-                .collect::<Vec<_>>())
-        })
-    }
-
-    fn remove_by_url(&self, wg_url: &WgUrl) -> Result<(), diesel::result::Error> {
-        use crate::schema::http_subdownload::dsl::*;
-
-        // delete cascade will delete all subdownloads
-        let matching_rows = http_subdownload.filter(url.eq(wg_url.as_str()));
-        let conn = self.pool.get().expect("Failed to get connection");
-        (*conn).exclusive_transaction(|| {
-            diesel::delete(matching_rows).execute(&conn)?;
-            Ok(())
-        })
-    }
-
-    fn remove_by_id(&self, id: i32) -> Result<(), diesel::result::Error> {
-        let identification = id;
-
-        {
-            use crate::schema::http_subdownload::dsl::*;
-
-            // delete the subdownload by id
-            let matching_rows = http_subdownload.filter(id.eq(identification));
-            let conn = self.pool.get().expect("Failed to get connection");
-            (*conn).exclusive_transaction(|| {
-                diesel::delete(matching_rows).execute(&conn)?;
-                Ok(())
-            })
-        }
-    }
+    path.with_file_name(file_name)
 }
 
-/// Given a size, split into [begin, end) intervals suitable for parallel downloads by the number of
-/// CPU cores
-fn split_range(size: usize) -> Vec<(usize, usize)> {
-    let cores = num_cpus::get();
-    let each_size = size / cores;
-    let remainder = size % cores;
+/// Given a size, split into `parts` [begin, end) intervals suitable for parallel downloads
+fn split_range(size: u64, parts: u64) -> Vec<(u64, u64)> {
+    let parts = parts.max(1);
+    let each_size = size / parts;
+    let remainder = size % parts;
 
-    // each cores gets the total size / core
+    // each part gets the total size / parts
 
-    let mut ranges: Vec<_> = (0..cores)
+    let mut ranges: Vec<_> = (0..parts)
         .map(|x| (each_size * x, each_size * (x + 1)))
         .collect();
 
-    // except the last core, which need to handle the remainder
+    // except the last part, which need to handle the remainder
     ranges.last_mut().unwrap().1 += remainder;
 
     ranges
 }
 
-pub trait DownloadSink: AsyncSeekExt + AsyncWriteExt + Send + Unpin + Debug + 'static {}
-
 pub struct Download<S, C> {
-    sink: Arc<Mutex<S>>,
+    sink: Arc<S>,
     context: DownloadContext,
     http_client: Client<C, Body>,
     persistence: SharedDownloadStore,
@@ -380,7 +129,7 @@ where
     C: Connect + Sync + Send + Clone + 'static,
 {
     fn new(
-        sink: Arc<Mutex<S>>,
+        sink: Arc<S>,
         context: DownloadContext,
         http_client: Client<C, Body>,
         persistence: SharedDownloadStore,
@@ -430,26 +179,19 @@ where
             _ = &mut self.stop_token => { return Ok(&self.context) },
             Some(chunk) = body.data() => {
                 let chunk = chunk?;
+                let len = chunk.len() as u64;
 
-                let mut guard = self.sink.lock().await;
-
-                let sink = guard.deref_mut();
-
-                // make sure to seek to the correct position
-                sink.seek(SeekFrom::Start(self.context.offset as u64)).await?;
-
-                // then we can write
-                sink.write_all(&chunk).await?;
+                self.sink.write_at(self.context.offset, chunk).await?;
 
                 // update the offset
-                self.context.offset += chunk.len();
+                self.context.offset += len;
 
                 // update the database so query knows the most recent truth
-                self.persistence.update_download(&self.context)?
+                self.persistence.update_download(&self.context).await?
             }
         }
 
-        self.persistence.remove_by_id(self.context.id)?;
+        self.persistence.remove_by_id(self.context.id).await?;
 
         Ok(&self.context)
     }
@@ -463,13 +205,84 @@ where
     request_source: Mutex<R>,
     download_store: SharedDownloadStore,
     current_downloads: Mutex<Vec<JoinHandle<()>>>,
+    disk_monitor: SharedDiskSpaceMonitor,
+    checkpointer: Arc<Checkpointer>,
+    queue: DownloadQueue,
+    max_concurrent_downloads: AtomicUsize,
+    /// The downloads taken off the queue that haven't finished yet
+    active: Mutex<HashMap<i32, ActiveDownload>>,
+    bandwidth_schedule: BandwidthSchedule,
+    rate_limiter: RateLimiter,
+    /// The limit whenever the bandwidth schedule doesn't have one
+    rate_limit: Mutex<Option<u64>>,
+    retention_policy: RetentionPolicy,
+    shutdown_timeout: Duration,
 }
 
+/// Tells the subdownloads of a download to stop, and what the download becomes once they did
+type StopSignal = watch::Receiver<Option<DownloadState>>;
+
+/// A download taken off the queue that hasn't finished yet
+#[derive(Debug)]
+struct ActiveDownload {
+    window: Option<TimeWindow>,
+    stop: watch::Sender<Option<DownloadState>>,
+}
+
+/// Wait until the download is asked to stop
+async fn stop_requested(stop: &mut StopSignal) {
+    loop {
+        if stop.borrow().is_some() {
+            return;
+        }
+
+        // nobody can ask anymore
+        if stop.changed().await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// How many downloads run at once unless told otherwise
+pub const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 3;
+
+/// The limits shared by every download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Bytes per second whenever the bandwidth schedule doesn't say otherwise, unlimited if none
+    pub rate: Option<u64>,
+    pub max_concurrent: usize,
+}
+
+/// The downloader the daemon runs, as the API gets to see it
+pub type SharedHttpDownloader = Arc<HttpDownloader<MergedHttpRequestSource>>;
+
+/// How long the running downloads get to stop and write down their progress when we shut down
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// The errors that could occur when we try to download a file in parallel
 #[derive(Debug)]
 pub enum HttpDownloaderError {
     ContentLengthNotSupported,
     BadServer,
+    /// The server won't give us the file, e.g. a 404
+    BadStatus(StatusCode),
+    /// The filesystem the download goes to can't hold the file
+    InsufficientSpace {
+        required: u64,
+        available: u64,
+    },
+    /// The filesystem ran out of space while downloading
+    DiskFull,
+    /// The download was stopped before it could finish, its progress is kept
+    Paused,
+    /// The download was asked to stop, its progress is kept
+    Stopped,
+    /// The finished file isn't what we were told it would be
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
     Other(String),
 }
 
@@ -482,6 +295,31 @@ impl Display for HttpDownloaderError {
             HttpDownloaderError::BadServer => {
                 write!(f, "The server returned something we can't continue with")
             }
+            HttpDownloaderError::BadStatus(status) => {
+                write!(f, "The server answered {}", status)
+            }
+            HttpDownloaderError::InsufficientSpace {
+                required,
+                available,
+            } => {
+                write!(
+                    f,
+                    "Not enough disk space: {} bytes required but only {} bytes available",
+                    required, available
+                )
+            }
+            HttpDownloaderError::DiskFull => {
+                write!(f, "No space left on the disk")
+            }
+            HttpDownloaderError::Paused => {
+                write!(f, "The download was paused")
+            }
+            HttpDownloaderError::Stopped => {
+                write!(f, "The download was stopped")
+            }
+            HttpDownloaderError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Expected a sha-256 of {} but got {}", expected, actual)
+            }
             HttpDownloaderError::Other(reason) => {
                 write!(f, "generic error: {}", reason)
             }
@@ -497,6 +335,12 @@ impl From<hyper::Error> for HttpDownloaderError {
     }
 }
 
+impl From<hyper::http::Error> for HttpDownloaderError {
+    fn from(e: hyper::http::Error) -> Self {
+        HttpDownloaderError::Other(format!("{}", e))
+    }
+}
+
 impl From<hyper::header::ToStrError> for HttpDownloaderError {
     fn from(e: hyper::header::ToStrError) -> Self {
         HttpDownloaderError::Other(format!("{}", e))
@@ -511,16 +355,31 @@ impl From<ParseIntError> for HttpDownloaderError {
 
 impl From<std::io::Error> for HttpDownloaderError {
     fn from(e: std::io::Error) -> Self {
+        if disk::is_disk_full(&e) {
+            return HttpDownloaderError::DiskFull;
+        }
+
         HttpDownloaderError::Other(format!("{}", e))
     }
 }
 
-impl From<diesel::result::Error> for HttpDownloaderError {
-    fn from(e: diesel::result::Error) -> Self {
+impl From<StoreError> for HttpDownloaderError {
+    fn from(e: StoreError) -> Self {
         HttpDownloaderError::Other(format!("{}", e))
     }
 }
 
+impl HttpDownloader<MergedHttpRequestSource> {
+    /// A downloader taking requests from every source added to the returned handle, whenever they
+    /// are added
+    pub fn with_request_sources(shared_store: SharedDownloadStore) -> (Self, RequestSources) {
+        let request_source = MergedHttpRequestSource::new();
+        let sources = request_source.sources();
+
+        (Self::new(request_source, shared_store), sources)
+    }
+}
+
 impl<R> HttpDownloader<R>
 where
     R: HttpRequestSource + Send + Sync + 'static,
@@ -528,8 +387,170 @@ where
     fn new(request_source: R, shared_store: SharedDownloadStore) -> Self {
         HttpDownloader {
             request_source: Mutex::new(request_source),
+            checkpointer: Arc::new(Checkpointer::new(
+                shared_store.clone(),
+                DEFAULT_CHECKPOINT_INTERVAL,
+            )),
+            queue: DownloadQueue::new(shared_store.clone()),
             download_store: shared_store,
             current_downloads: Mutex::new(vec![]),
+            disk_monitor: Arc::new(DiskSpaceMonitor::default()),
+            max_concurrent_downloads: AtomicUsize::new(DEFAULT_MAX_CONCURRENT_DOWNLOADS),
+            active: Mutex::new(HashMap::new()),
+            bandwidth_schedule: BandwidthSchedule::default(),
+            rate_limiter: RateLimiter::new(None),
+            rate_limit: Mutex::new(None),
+            retention_policy: RetentionPolicy::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    /// Prune the history of finished downloads according to `retention_policy`
+    pub fn with_retention_policy(mut self, retention_policy: RetentionPolicy) -> Self {
+        self.retention_policy = retention_policy;
+        self
+    }
+
+    /// Limit how fast all downloads together go depending on the time of the week
+    pub fn with_bandwidth_schedule(mut self, bandwidth_schedule: BandwidthSchedule) -> Self {
+        self.bandwidth_schedule = bandwidth_schedule;
+        self
+    }
+
+    /// Limit how fast all downloads together go whenever the bandwidth schedule doesn't say
+    /// otherwise, in bytes per second
+    pub fn with_rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.rate_limiter = RateLimiter::new(rate_limit);
+        self.rate_limit = Mutex::new(rate_limit);
+        self
+    }
+
+    /// Run at most `max` downloads at once, the rest wait in the queue
+    pub fn with_max_concurrent_downloads(mut self, max: usize) -> Self {
+        self.max_concurrent_downloads = AtomicUsize::new(max);
+        self
+    }
+
+    /// Write the progress of running downloads to the store every `interval` instead of every
+    /// second
+    pub fn with_checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpointer = Arc::new(Checkpointer::new(self.download_store.clone(), interval));
+        self
+    }
+
+    /// Give the running downloads `timeout` to stop when the downloader is stopped, the ones that
+    /// take longer are left for the next start to recover
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Share `disk_monitor` with the downloader instead of the one it creates itself, so whoever
+    /// else holds it sees which filesystems the downloads paused
+    pub fn with_disk_monitor(mut self, disk_monitor: SharedDiskSpaceMonitor) -> Self {
+        self.disk_monitor = disk_monitor;
+        self
+    }
+
+    pub fn disk_monitor(&self) -> &SharedDiskSpaceMonitor {
+        &self.disk_monitor
+    }
+
+    pub fn queue(&self) -> &DownloadQueue {
+        &self.queue
+    }
+
+    /// The limits shared by every download
+    pub async fn limits(&self) -> Limits {
+        Limits {
+            rate: *self.rate_limit.lock().await,
+            max_concurrent: self.max_concurrent_downloads.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Change the limits shared by every download, the running ones included. Lowering the number
+    /// of concurrent downloads lets the running ones finish rather than stopping any.
+    pub async fn set_limits(&self, limits: Limits) {
+        *self.rate_limit.lock().await = limits.rate;
+        self.max_concurrent_downloads
+            .store(limits.max_concurrent, Ordering::SeqCst);
+
+        self.enforce_schedule(local_now()).await;
+    }
+
+    /// Pause a download, the running one stops where it is and keeps its progress
+    pub async fn pause(&self, download_id: i32) -> Result<(), StoreError> {
+        self.stop_or_set_state(download_id, DownloadState::Paused)
+            .await
+    }
+
+    /// Queue a paused or failed download again, it picks up where it left off
+    pub async fn resume(&self, download_id: i32) -> Result<(), StoreError> {
+        let active = self.active.lock().await;
+        if let Some(active) = active.get(&download_id) {
+            // one that is still on its way to being paused goes back to the queue instead
+            if active.stop.borrow().is_some() {
+                let _ = active.stop.send(Some(DownloadState::Queued));
+            }
+            return Ok(());
+        }
+
+        self.download_store
+            .set_state(download_id, DownloadState::Queued, None)
+            .await?;
+        self.queue.notify();
+
+        Ok(())
+    }
+
+    /// Give up on a download for good, throwing away whatever it downloaded so far
+    pub async fn cancel(&self, download_id: i32) -> Result<(), StoreError> {
+        self.stop_or_set_state(download_id, DownloadState::Cancelled)
+            .await
+    }
+
+    /// Stop the download if it is running, it ends up in `state` once it did, or put it there
+    /// right away if it isn't
+    async fn stop_or_set_state(
+        &self,
+        download_id: i32,
+        state: DownloadState,
+    ) -> Result<(), StoreError> {
+        // the scheduler can't start the download while we hold on to this
+        let active = self.active.lock().await;
+        if let Some(active) = active.get(&download_id) {
+            let _ = active.stop.send(Some(state));
+            return Ok(());
+        }
+
+        self.download_store
+            .set_state(download_id, state, None)
+            .await?;
+        if state == DownloadState::Cancelled {
+            self.remove_part_file(download_id).await;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_part_file(&self, download_id: i32) {
+        let part = match self.download_store.download(download_id).await {
+            Ok(record) => part_path(&record.file_path),
+            Err(e) => {
+                event!(
+                    Level::ERROR,
+                    "Failed to find download {}: {}",
+                    download_id,
+                    e
+                );
+                return;
+            }
+        };
+
+        match tokio::fs::remove_file(&part).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => event!(Level::WARN, "Failed to remove {:?}: {}", part, e),
         }
     }
 
@@ -542,56 +563,323 @@ where
             .enable_http2()
             .build();
 
+        self.run(http_connector, https_connector, stop_token).await
+    }
+
+    /// Take requests and run downloads until `stop_token` fires, connecting to http urls with
+    /// `http_connector` and to https ones with `https_connector`
+    pub async fn run<C, H>(
+        self: &Arc<Self>,
+        http_connector: C,
+        https_connector: H,
+        mut stop_token: tokio::sync::oneshot::Receiver<bool>,
+    ) where
+        C: Connect + Clone + Send + Sync + Debug + 'static,
+        H: Connect + Clone + Send + Sync + Debug + 'static,
+    {
+        // whatever a previous run left in the store has to be picked up before we take new work
+        if let Err(e) = self.recover_downloads().await {
+            event!(
+                Level::ERROR,
+                "Failed to recover unfinished downloads: {}",
+                e
+            );
+        }
+
+        // the monitor resumes paused downloads, so it has to outlive every request we take
+        let disk_monitor = tokio::spawn(self.disk_monitor.clone().run());
+        let checkpointer = tokio::spawn(self.checkpointer.clone().run());
+
+        // whatever was queued before we started is up for grabs right away
+        self.queue.notify();
+
+        let mut schedule_ticks = tokio::time::interval(DEFAULT_SCHEDULE_INTERVAL);
+        let mut taking_requests = true;
         loop {
-            let _ = tokio::select! {
-                _stop = async { stop_token.await } => {
+            tokio::select! {
+                _stop = &mut stop_token => {
+                    self.stop_downloads().await;
+                    disk_monitor.abort();
+                    checkpointer.abort();
+
+                    // don't lose the progress made since the last checkpoint
+                    if let Err(e) = self.checkpointer.flush().await {
+                        event!(Level::ERROR, "Failed to checkpoint download progress: {}", e);
+                    }
                     return;
                 },
-                _huh = async {
-                    let request = self.request_source.lock().await.get_request().await.unwrap();
-                    let file_sink = OpenOptions::new()
-                                                .write(true)
-                                                .create(true)
-                                                .open(&*request.path)
-                                                .await
-                    .unwrap();
-
-                    let file_sink = Arc::new(Mutex::new(file_sink));
-
-                    if request.url.scheme() == "https" {
-                        let task = self.spawn_downloads(request, file_sink, https_connector.clone()).await.unwrap();
-
-                        self
-                        .current_downloads
-                        .lock()
-                        .await
-                        .push (task);
-                    } else if request.url.scheme() == "http" {
-                        let task = self.spawn_downloads(request, file_sink, http_connector.clone()).await.unwrap();
-
-                        self
-                        .current_downloads
-                        .lock()
-                        .await
-                        .push (task);
-                    } else {
-                        panic!("unsupported schema");
+                request = async { self.request_source.lock().await.get_request().await }, if taking_requests => {
+                    match request {
+                        // the request only has to be safe in the store, the scheduler starts it
+                        // once there's room
+                        Ok(request) => {
+                            if let Err(e) = self.queue.enqueue(&request).await {
+                                event!(Level::ERROR, "Failed to queue {:?}: {}", request, e);
+                            }
+                        }
+                        Err(e) => {
+                            event!(Level::ERROR, "No more requests, the source failed: {:?}", e);
+                            taking_requests = false;
+                        }
+                    }
+                },
+                _ = self.queue.changed() => {
+                    if let Err(e) = self.schedule(&http_connector, &https_connector).await {
+                        event!(Level::ERROR, "Failed to start queued downloads: {}", e);
                     }
-                } => { return; },
+                },
+                _ = schedule_ticks.tick() => {
+                    self.enforce_schedule(local_now()).await;
+                    self.prune_history().await;
+                },
             };
         }
     }
 
-    /// Given a http download request and a sink, split the download into multiple subdownloads and
-    /// start
-    pub async fn spawn_downloads<S, C>(
+    /// Start queued downloads, by priority then age, until `max_concurrent_downloads` of them are
+    /// running. Downloads outside of their window wait for it to open, and the ones we stopped
+    /// before pick up where they left off.
+    async fn schedule<C, H>(
         self: &Arc<Self>,
+        http_connector: &C,
+        https_connector: &H,
+    ) -> Result<(), HttpDownloaderError>
+    where
+        C: Connect + Clone + Send + Sync + Debug + 'static,
+        H: Connect + Clone + Send + Sync + Debug + 'static,
+    {
+        let mut active = self.active.lock().await;
+        let free = self
+            .max_concurrent_downloads
+            .load(Ordering::SeqCst)
+            .saturating_sub(active.len());
+        if free == 0 {
+            return Ok(());
+        }
+
+        let now = local_now();
+        let startable = self
+            .queue
+            .queued()
+            .await?
+            .into_iter()
+            .filter(|record| record.options.may_run_at(now.time()))
+            .take(free);
+
+        for record in startable {
+            let request = request_of(&record);
+
+            // the progress is only worth something as long as the data it describes is still there
+            let mut segments = self.download_store.segments(record.id).await?;
+            let resumable = !segments.is_empty() && part_path(&request.path).exists();
+            if resumable {
+                self.download_store
+                    .set_state(record.id, DownloadState::Running, None)
+                    .await?;
+            } else {
+                for segment in segments.drain(..) {
+                    self.download_store.remove_by_id(segment.id).await?;
+                }
+                self.download_store
+                    .set_state(record.id, DownloadState::Probing, None)
+                    .await?;
+            }
+
+            let (stop, stopped) = watch::channel(None);
+            let segments = resumable.then_some(segments);
+            let task = match request.url.scheme() {
+                "https" => tokio::spawn(self.clone().run_download(
+                    record.id,
+                    request,
+                    segments,
+                    https_connector.clone(),
+                    stopped,
+                )),
+                "http" => tokio::spawn(self.clone().run_download(
+                    record.id,
+                    request,
+                    segments,
+                    http_connector.clone(),
+                    stopped,
+                )),
+                scheme => {
+                    let e = HttpDownloaderError::Other(format!("unsupported scheme {}", scheme));
+                    self.fail_download(record.id, &e).await;
+                    continue;
+                }
+            };
+
+            active.insert(
+                record.id,
+                ActiveDownload {
+                    window: record.options.window,
+                    stop,
+                },
+            );
+            self.current_downloads.lock().await.push(task);
+        }
+
+        Ok(())
+    }
+
+    /// Stop every running download and wait up to `shutdown_timeout` for them to write down their
+    /// progress. They go back to the queue, so the next start resumes them.
+    async fn stop_downloads(&self) {
+        for active in self.active.lock().await.values() {
+            let _ = active.stop.send(Some(DownloadState::Queued));
+        }
+
+        let mut tasks: Vec<_> = self.current_downloads.lock().await.drain(..).collect();
+        let stopped = tokio::time::timeout(self.shutdown_timeout, join_all(tasks.iter_mut())).await;
+        if stopped.is_err() {
+            // whatever they wrote is in the checkpointer, `recover_downloads` queues them again
+            event!(
+                Level::WARN,
+                "Gave up on {} downloads that didn't stop in time",
+                self.active.lock().await.len()
+            );
+            for task in tasks {
+                task.abort();
+            }
+        }
+    }
+
+    /// Stop the running downloads whose window closed, they go back to the queue with their
+    /// progress until it opens again, and apply the bandwidth limit of the moment
+    async fn enforce_schedule(&self, now: NaiveDateTime) {
+        self.rate_limiter
+            .set_limit(
+                // a rule without a limit lifts the global one as well
+                self.bandwidth_schedule
+                    .limit_at(now)
+                    .unwrap_or(*self.rate_limit.lock().await),
+            )
+            .await;
+
+        for (download_id, active) in self.active.lock().await.iter() {
+            let closed = active
+                .window
+                .is_some_and(|window| !window.contains(now.time()));
+
+            if closed && active.stop.borrow().is_none() {
+                event!(
+                    Level::INFO,
+                    "Download {} is outside of its window, stopping it",
+                    download_id
+                );
+                let _ = active.stop.send(Some(DownloadState::Queued));
+            }
+        }
+
+        // queued downloads whose window just opened can start now
+        self.queue.notify();
+    }
+
+    async fn prune_history(&self) {
+        match self.retention_policy.apply(&*self.download_store).await {
+            Ok(0) => {}
+            Ok(pruned) => event!(Level::INFO, "Pruned {} downloads from the history", pruned),
+            Err(e) => event!(Level::ERROR, "Failed to prune the history: {}", e),
+        }
+    }
+
+    /// Put every download a previous run left going back in the queue, the scheduler resumes
+    /// them where they left off if their part file is still around and starts them over if it
+    /// isn't. A download being verified whose final file exists was finalized right before we died,
+    /// so it is simply marked completed.
+    async fn recover_downloads(self: &Arc<Self>) -> Result<(), HttpDownloaderError> {
+        for record in self.download_store.downloads().await? {
+            if !matches!(
+                record.state,
+                DownloadState::Probing | DownloadState::Running | DownloadState::Verifying
+            ) {
+                continue;
+            }
+
+            let request = request_of(&record);
+            // the part file is only renamed while verifying, before that a file at the path is
+            // one from before, e.g. the previous version of a monitored url
+            let finalized = record.state == DownloadState::Verifying
+                && !part_path(&request.path).exists()
+                && request.path.exists();
+            if finalized {
+                event!(Level::INFO, "{:?} was already finalized", request);
+                self.download_store
+                    .set_state(record.id, DownloadState::Completed, None)
+                    .await?;
+            } else {
+                event!(Level::INFO, "Queueing {:?} again", request);
+                self.download_store
+                    .set_state(record.id, DownloadState::Queued, None)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run a download taken off the queue, or resume `segments` of one, until it is done one way
+    /// or another and then make room for the next one
+    async fn run_download<C>(
+        self: Arc<Self>,
+        download_id: i32,
+        download_request: HttpRequest,
+        segments: Option<Vec<DownloadContext>>,
+        connector: C,
+        stop: StopSignal,
+    ) where
+        C: Connect + Clone + Send + Sync + Debug + 'static,
+    {
+        let started = match segments {
+            Some(segments) => {
+                self.run_segments(
+                    download_id,
+                    download_request,
+                    segments,
+                    connector,
+                    stop.clone(),
+                )
+                .await
+            }
+            None => {
+                self.start_download(download_id, download_request, connector, stop.clone())
+                    .await
+            }
+        };
+
+        match started {
+            // the task records how the download went by itself
+            Ok(task) => {
+                let _ = task.await;
+            }
+            Err(HttpDownloaderError::Stopped) => {
+                let state = stop.borrow().unwrap_or(DownloadState::Paused);
+                self.stop_download(download_id, state).await;
+            }
+            Err(e) => {
+                event!(
+                    Level::ERROR,
+                    "Failed to start download {}: {}",
+                    download_id,
+                    e
+                );
+                self.fail_download(download_id, &e).await;
+            }
+        }
+
+        self.active.lock().await.remove(&download_id);
+        self.queue.notify();
+    }
+
+    /// Find out how big the download is, reserve the space for it and start its subdownloads
+    async fn start_download<C>(
+        self: &Arc<Self>,
+        download_id: i32,
         download_request: HttpRequest,
-        sink: Arc<Mutex<S>>,
         connector: C,
+        mut stop: StopSignal,
     ) -> Result<JoinHandle<()>, HttpDownloaderError>
     where
-        S: AsyncWriteExt + AsyncSeekExt + Send + Unpin + Debug + 'static,
         C: Connect + Clone + Send + Sync + Debug + 'static,
     {
         // get the file size
@@ -600,278 +888,1070 @@ where
 
             // the HTTP head method will return just the head of the HTTP response, we hope it will
             // return the Content-Length header to allow us to split the download into multiple
-            let request = Request::head(download_request.url.as_str())
-                .body(Body::empty())
-                .unwrap();
+            let request = request_builder(&download_request.url, &download_request.options)
+                .method("HEAD")
+                .body(Body::empty())?;
+
+            // a server that takes its time to answer shouldn't keep the download from stopping
+            let response = tokio::select! {
+                response = client.request(request) => response?,
+                _ = stop_requested(&mut stop) => return Err(HttpDownloaderError::Stopped),
+            };
+            if !response.status().is_success() {
+                return Err(HttpDownloaderError::BadStatus(response.status()));
+            }
 
-            client
-                .request(request)
-                .await?
+            response
                 .headers()
                 .get("Content-Length")
                 .ok_or(HttpDownloaderError::ContentLengthNotSupported)?
                 .to_str()?
-                .parse::<usize>()?
+                .parse::<u64>()?
         };
-
-        // split the download into multiple subdownloads by the number of cores
-        let ranges = split_range(size);
+        Self::reserve_part_file(&download_request.path, size).await?;
+
+        // split the download into multiple subdownloads, by default one per core. A file smaller
+        // than that leaves some of them empty and there is no point requesting those
+        let parts = download_request
+            .options
+            .connections
+            .map(u64::from)
+            .unwrap_or(num_cpus::get() as u64);
+        let ranges = split_range(size, parts)
+            .into_iter()
+            .filter(|(begin, end)| begin != end);
 
         let url = Arc::new(download_request.url.clone());
         let file_path: Arc<Path> = Arc::from(download_request.path.clone());
 
         // convert the ranges to sub_downloads that we can store in the database
-        let downloads: Vec<DownloadContext> = ranges
-            .into_iter()
+        let mut downloads: Vec<DownloadContext> = ranges
             .map(|range| DownloadContext {
                 id: -1,
+                download_id,
                 url: url.clone(),
                 offset: range.0,
                 total: range.1 - range.0,
                 file_path: file_path.clone(),
             })
-            .map(|mut sub_download| {
-                // todo: allow storing to fail, the challenge here is that `?` inside the map method
-                // doesn't work since the return type is not Option<T> or Result<T, E>
-                let id = self.download_store.add_download(&sub_download).unwrap();
-
-                sub_download.id = id;
-                sub_download
-            })
             .collect();
 
+        let ids = self
+            .download_store
+            .add_segments(download_id, size, &downloads)
+            .await?;
+        for (download, id) in downloads.iter_mut().zip(ids) {
+            download.id = id;
+        }
+
+        self.run_segments(download_id, download_request, downloads, connector, stop)
+            .await
+    }
+
+    /// Record why a download failed, its progress is kept in case it is queued again
+    async fn fail_download(&self, download_id: i32, error: &HttpDownloaderError) {
+        if let Err(e) = self
+            .download_store
+            .set_state(download_id, DownloadState::Failed, Some(error.to_string()))
+            .await
+        {
+            event!(
+                Level::ERROR,
+                "Failed to mark download {} as failed: {}",
+                download_id,
+                e
+            );
+        }
+    }
+
+    /// Make sure the filesystem can hold a file of `size` bytes at `path` and reserve the space for
+    /// its part file, failing early instead of running out of space halfway through.
+    async fn reserve_part_file(path: &Path, size: u64) -> Result<(), HttpDownloaderError> {
+        let part = part_path(path);
+
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&part)?;
+
+            // whatever is already allocated for the part file doesn't need to be found again
+            let required = size.saturating_sub(file.metadata()?.len());
+            let available = disk::available_space(&part)?;
+            if required > available {
+                return Err(HttpDownloaderError::InsufficientSpace {
+                    required,
+                    available,
+                });
+            }
+
+            disk::preallocate(&file, size)?;
+
+            Ok(())
+        })
+        .await
+        .map_err(|e| HttpDownloaderError::Other(format!("{}", e)))?
+    }
+
+    /// Download the given subdownloads in parallel into the part file of the request, and finalize
+    /// the file once all of them are done.
+    async fn run_segments<C>(
+        self: &Arc<Self>,
+        download_id: i32,
+        download_request: HttpRequest,
+        downloads: Vec<DownloadContext>,
+        connector: C,
+        stop: StopSignal,
+    ) -> Result<JoinHandle<()>, HttpDownloaderError>
+    where
+        C: Connect + Clone + Send + Sync + Debug + 'static,
+    {
+        // the part file may hold data from a previous run that we are resuming
+        let sink = Arc::new(FileSink::open(&part_path(&download_request.path)).await?);
+
         // download the file in parallel
         let mut download_tasks = vec![];
+        let ids: Vec<i32> = downloads.iter().map(|download| download.id).collect();
+        let options = Arc::new(download_request.options.clone());
 
-        for download in downloads {
+        for download in downloads.into_iter().filter(|download| download.total > 0) {
             download_tasks.push(tokio::spawn(Self::chunked_download(
                 self.clone(),
                 download,
+                options.clone(),
                 sink.clone(),
                 connector.clone(),
+                stop.clone(),
             )));
         }
 
+        let this = self.clone();
         Ok(tokio::spawn(async move {
-            join_all(download_tasks).await;
+            let results = join_all(download_tasks).await;
+
+            let mut stopped = false;
+            let mut error = None;
+            for result in results {
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(HttpDownloaderError::Stopped)) => stopped = true,
+                    Ok(Err(e)) => {
+                        error.get_or_insert(e);
+                    }
+                    Err(e) => {
+                        error.get_or_insert(HttpDownloaderError::Other(format!("{}", e)));
+                    }
+                }
+            }
+
+            if let Some(e) = error {
+                // leave the part file and the progress alone so the download can be resumed later
+                event!(
+                    Level::ERROR,
+                    "Download for {:?} failed: {}",
+                    download_request,
+                    e
+                );
+                this.fail_download(download_id, &e).await;
+                return;
+            }
+
+            if stopped {
+                let state = stop.borrow().unwrap_or(DownloadState::Paused);
+                this.stop_download(download_id, state).await;
+                return;
+            }
+
+            if let Err(e) = this
+                .finalize_download(download_id, &download_request, sink, &ids)
+                .await
+            {
+                event!(
+                    Level::ERROR,
+                    "Failed to finalize {:?}: {}",
+                    download_request,
+                    e
+                );
+                this.fail_download(download_id, &e).await;
+                return;
+            }
+
             event!(Level::INFO, "Download for {:?} is done!", download_request);
         }))
     }
 
+    /// Put a download whose subdownloads stopped in `state`. Their progress has to be in the store
+    /// first, whoever picks the download up again resumes from there.
+    async fn stop_download(&self, download_id: i32, state: DownloadState) {
+        if let Err(e) = self.checkpointer.flush().await {
+            event!(
+                Level::ERROR,
+                "Failed to checkpoint download progress: {}",
+                e
+            );
+        }
+
+        match self
+            .download_store
+            .set_state(download_id, state, None)
+            .await
+        {
+            Ok(()) if state == DownloadState::Cancelled => {
+                event!(Level::INFO, "Download {} is now {}", download_id, state);
+                self.remove_part_file(download_id).await;
+            }
+            Ok(()) => event!(Level::INFO, "Download {} is now {}", download_id, state),
+            Err(e) => event!(
+                Level::ERROR,
+                "Failed to mark download {} as {}: {}",
+                download_id,
+                state,
+                e
+            ),
+        }
+    }
+
+    /// Flush the part file to disk, check it against the checksum we were given, move it to its
+    /// final place and mark the download completed, which forgets about the subdownloads.
+    ///
+    /// The store is only updated after the rename, so a crash at any point in here is picked up by
+    /// `recover_downloads`. A file that doesn't match its checksum is thrown away, there is no
+    /// telling which part of it is wrong.
+    async fn finalize_download(
+        &self,
+        download_id: i32,
+        download_request: &HttpRequest,
+        sink: Arc<FileSink>,
+        ids: &[i32],
+    ) -> Result<(), HttpDownloaderError> {
+        sink.sync().await?;
+        drop(sink);
+
+        self.download_store
+            .set_state(download_id, DownloadState::Verifying, None)
+            .await?;
+
+        let part = part_path(&download_request.path);
+        let checksum = match (
+            checksum_file(&part).await,
+            &download_request.options.checksum,
+        ) {
+            (Ok(actual), Some(expected)) if !actual.eq_ignore_ascii_case(expected) => {
+                self.checkpointer.forget(ids);
+                for id in ids {
+                    self.download_store.remove_by_id(*id).await?;
+                }
+                tokio::fs::remove_file(&part).await?;
+
+                return Err(HttpDownloaderError::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+            (Err(e), Some(_)) => return Err(e.into()),
+            (checksum, _) => checksum,
+        };
+
+        tokio::fs::rename(&part, &download_request.path).await?;
+
+        // the file is there either way, a missing checksum is no reason to fail the download
+        match checksum {
+            Ok(checksum) => {
+                self.download_store
+                    .set_checksum(download_id, checksum)
+                    .await?
+            }
+            Err(e) => event!(
+                Level::WARN,
+                "Failed to checksum {:?}: {}",
+                download_request.path,
+                e
+            ),
+        }
+
+        self.checkpointer.forget(ids);
+        self.download_store
+            .set_state(download_id, DownloadState::Completed, None)
+            .await?;
+
+        Ok(())
+    }
+
     #[tracing::instrument]
     fn chunked_download<S, C>(
         self: Arc<Self>,
         mut download: DownloadContext,
-        sink: Arc<Mutex<S>>,
+        options: Arc<DownloadOptions>,
+        sink: Arc<S>,
         connector: C,
+        mut stop: StopSignal,
     ) -> impl Future<Output = Result<(), HttpDownloaderError>>
     where
-        S: AsyncSeekExt + AsyncWriteExt + Send + Unpin + Debug + 'static,
+        S: DownloadSink,
         C: Connect + Clone + Send + Sync + Debug + 'static,
     {
         let this = self.clone();
         async move {
-            let request = Request::builder()
-                .method("GET")
-                .uri(download.url.as_str())
-                .header(
-                    "Range",
-                    dbg!(format!(
-                        "bytes={}-{}",
-                        download.offset,
-                        download.offset + download.total - 1
-                    )),
-                )
-                .body(Body::empty())
-                .unwrap();
+            let mut disk = this.disk_monitor.watch(&part_path(&download.file_path))?;
 
-            let client = Client::builder().build::<_, Body>(connector.clone());
-
-            let mut body = client.request(request).await?.into_body();
+            loop {
+                // there is no point asking the server for data the disk can't take
+                tokio::select! {
+                    _ = disk.writable() => {},
+                    _ = stop_requested(&mut stop) => return Err(HttpDownloaderError::Stopped),
+                }
 
-            // start downloading chunk by chunk
-            while let Some(chunk) = body.data().await {
-                let chunk = chunk?;
+                match this
+                    .download_range(
+                        &mut download,
+                        &options,
+                        &sink,
+                        &connector,
+                        &mut disk,
+                        &mut stop,
+                    )
+                    .await
                 {
-                    //event!(Level::INFO, "chunk received len: {:?}, download offset: {:?}", chunk.len(), download.offset);
+                    Err(HttpDownloaderError::DiskFull) => {
+                        // what we managed to write is with the checkpointer, stop every download
+                        // on this filesystem and pick up from there once space frees up
+                        this.disk_monitor.pause(disk.filesystem);
+                    }
+                    // another download on the same filesystem ran out of space
+                    Err(HttpDownloaderError::Paused) => {}
+                    result => return result,
+                }
+            }
+        }
+    }
 
-                    let mut guard = sink.lock().await;
+    /// Request what is left of `download` and write it to the sink until the body ends, the disk is
+    /// full or another download paused the filesystem.
+    async fn download_range<S, C>(
+        &self,
+        download: &mut DownloadContext,
+        options: &DownloadOptions,
+        sink: &Arc<S>,
+        connector: &C,
+        disk: &mut DiskWatch,
+        stop: &mut StopSignal,
+    ) -> Result<(), HttpDownloaderError>
+    where
+        S: DownloadSink,
+        C: Connect + Clone + Send + Sync + Debug + 'static,
+    {
+        let (first, last) = (download.offset, download.offset + download.total - 1);
+        let range = format!("bytes={}-{}", first, last);
+        event!(Level::TRACE, "Requesting {} of {}", range, download.url);
+        let request = request_builder(&download.url, options)
+            .method("GET")
+            .header("Range", range)
+            .body(Body::empty())?;
+
+        let client = Client::builder().build::<_, Body>(connector.clone());
+
+        // a server that takes its time to answer doesn't hold up stopping either
+        let response = tokio::select! {
+            response = client.request(request) => response?,
+            _ = disk.paused() => return Err(HttpDownloaderError::Paused),
+            _ = stop_requested(stop) => return Err(HttpDownloaderError::Stopped),
+        };
+        // anything else is a whole file or an error page, neither belongs at our offset
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(HttpDownloaderError::BadStatus(response.status()));
+        }
+        // some other part of the file, or one the server won't tell us about
+        let answered = response
+            .headers()
+            .typed_get::<ContentRange>()
+            .and_then(|range| range.bytes_range());
+        if answered != Some((first, last)) {
+            return Err(HttpDownloaderError::BadServer);
+        }
+        let mut body = response.into_body();
 
-                    let sink = guard.deref_mut();
+        // start downloading chunk by chunk
+        loop {
+            let chunk = tokio::select! {
+                chunk = body.data() => chunk,
+                _ = disk.paused() => return Err(HttpDownloaderError::Paused),
+                _ = stop_requested(stop) => return Err(HttpDownloaderError::Stopped),
+            };
 
-                    // make sure to seek to the correct position
-                    sink.seek(SeekFrom::Start(download.offset as u64)).await?;
+            let chunk = match chunk {
+                Some(chunk) => chunk?,
+                None => break,
+            };
 
-                    // then we can write
-                    sink.write_all(&chunk).await?;
+            let len = chunk.len() as u64;
+            // more than we asked for, writing it would spill into the next range
+            let left = download
+                .total
+                .checked_sub(len)
+                .ok_or(HttpDownloaderError::BadServer)?;
+            {
+                // every subdownload writes to its own range, no need to wait for the others
+                sink.write_at(download.offset, chunk).await?;
+
+                // move the range we still need forward, so a resume requests exactly the rest
+                download.offset += len;
+                download.total = left;
+            }
 
-                    // update the offset
-                    download.offset += chunk.len();
+            // the data is written, so the store may learn about it with the next checkpoint
+            self.checkpointer.record(download, sink.clone());
 
-                    let file_offset = sink.stream_position().await?;
-                    //event!(Level::INFO, "data written, download offset: {:?}, file offset: {:?}", download.offset, file_offset);
-                }
+            // stay under the bandwidth limit together with every other subdownload
+            self.rate_limiter.consume(len).await;
+        }
 
-                // update the database so query knows the most recent truth
-                this.download_store.update_download(&download)?
-            }
+        // the body ended early, what we got is written down so a retry asks for the rest
+        if download.total != 0 {
+            return Err(HttpDownloaderError::BadServer);
+        }
 
-            // we're done our chunk, remove ourselves from the store
-            self.download_store.remove_by_id(download.id)?;
+        // we're done our chunk, the row stays (with nothing left to download) until the whole
+        // file is finalized so a crash before the rename can still be recovered from
 
-            Ok(())
-        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::request::http::ChannelHttpRequestSource;
+    use crate::{
+        request::http::{ChannelHttpRequestSource, DownloadOptions},
+        store::{memory::MemoryStore, sqlite::tests::init_db},
+    };
     use std::env::current_dir;
     use std::time::Duration;
     use tokio::sync::{mpsc, oneshot};
     use tokio::{join, time};
 
-    embed_migrations!("migrations");
+    #[test]
+    fn split_range_covers_files_over_4_gib() {
+        use pretty_assertions::assert_eq;
 
-    fn init_db() -> color_eyre::Result<SqliteStore> {
-        let store = SqliteStore::new(":memory:")?;
-        embedded_migrations::run(&store.pool.get()?)?;
+        let size = 5 * 1024 * 1024 * 1024 + 7;
+        let ranges = split_range(size, 8);
 
-        Ok(store)
+        assert_eq!(ranges.len(), 8);
+        assert_eq!(ranges.first().unwrap().0, 0);
+        assert_eq!(ranges.last().unwrap().1, size);
+        assert!(ranges.windows(2).all(|pair| pair[0].1 == pair[1].0));
     }
 
     #[test]
-    fn sqlite_store_can_add() -> color_eyre::Result<()> {
-        use pretty_assertions::assert_ne;
+    fn part_path_is_a_sibling() {
+        use pretty_assertions::assert_eq;
 
-        let store = init_db()?;
-        color_eyre::install()?;
+        assert_eq!(
+            part_path(Path::new("/tmp/ubuntu.iso")),
+            PathBuf::from("/tmp/ubuntu.iso.sulfur-part")
+        );
+        assert_eq!(
+            part_path(Path::new("relative")),
+            PathBuf::from("relative.sulfur-part")
+        );
+    }
 
-        let url = Arc::from(WgUrl::parse("https://www.google.com")?);
-        let file_path: Arc<Path> = Arc::from(PathBuf::from("/tmp/test.txt"));
+    #[tokio::test]
+    async fn scheduler_runs_at_most_max_concurrent_downloads() -> color_eyre::Result<()> {
+        use pretty_assertions::assert_eq;
 
-        let download = DownloadContext {
-            id: -90999,
-            url: Arc::clone(&url),
-            offset: 0,
-            total: 5000,
-            file_path: Arc::clone(&file_path),
-        };
+        // a server that takes connections and never answers keeps every download probing
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let downloader = Arc::new(
+            HttpDownloader::new(ChannelHttpRequestSource::new(req_rx), store.clone())
+                .with_max_concurrent_downloads(2),
+        );
+
+        let mut ids = vec![];
+        for i in 0..3 {
+            let request = HttpRequest {
+                url: WgUrl::parse(&format!("http://{}/{}", address, i))?,
+                path: std::env::temp_dir().join(format!("sulfur-scheduler-{}", i)),
+                options: DownloadOptions::default(),
+                origin: None,
+            };
+            ids.push(downloader.queue().enqueue(&request).await?);
+        }
+        downloader.queue().move_to_front(ids[2]).await?;
+
+        let connector = HttpConnector::new();
+        downloader.schedule(&connector, &connector).await?;
+        // there's no room left, so this must not start anything
+        downloader.schedule(&connector, &connector).await?;
+
+        let mut states = vec![];
+        for id in &ids {
+            states.push(store.download(*id).await?.state);
+        }
+        assert_eq!(
+            states,
+            vec![
+                DownloadState::Probing,
+                DownloadState::Queued,
+                DownloadState::Probing
+            ]
+        );
+
+        for task in downloader.current_downloads.lock().await.drain(..) {
+            task.abort();
+        }
+        server.abort();
 
-        let res1 = store.add_download(&download)?;
+        Ok(())
+    }
 
-        let download = DownloadContext {
-            // we do a bit of trolling
-            id: -0,
-            url: Arc::clone(&url),
-            offset: 0,
-            total: 5000,
-            file_path: Arc::clone(&file_path),
+    #[tokio::test]
+    async fn downloads_only_run_inside_their_window() -> color_eyre::Result<()> {
+        use crate::schedule::BandwidthRule;
+        use chrono::{Duration, NaiveTime};
+        use pretty_assertions::assert_eq;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let schedule = BandwidthSchedule {
+            rules: vec![BandwidthRule {
+                days: vec![],
+                start: NaiveTime::MIN,
+                end: NaiveTime::MIN,
+                limit: Some(1024),
+            }],
         };
+        let downloader = Arc::new(
+            HttpDownloader::new(ChannelHttpRequestSource::new(req_rx), store.clone())
+                .with_bandwidth_schedule(schedule),
+        );
+
+        let now = local_now();
+        let windows = [
+            // opens in an hour
+            TimeWindow {
+                start: (now + Duration::hours(1)).time(),
+                end: (now + Duration::hours(2)).time(),
+            },
+            // open right now
+            TimeWindow {
+                start: (now - Duration::hours(1)).time(),
+                end: (now + Duration::hours(1)).time(),
+            },
+        ];
+        let mut ids = vec![];
+        for (i, window) in windows.iter().enumerate() {
+            let request = HttpRequest {
+                url: WgUrl::parse(&format!("http://{}/{}", address, i))?,
+                path: std::env::temp_dir().join(format!("sulfur-window-{}", i)),
+                options: DownloadOptions {
+                    window: Some(*window),
+                    ..DownloadOptions::default()
+                },
+                origin: None,
+            };
+            ids.push(downloader.queue().enqueue(&request).await?);
+        }
 
-        let res2 = store.add_download(&download)?;
+        let connector = HttpConnector::new();
+        downloader.schedule(&connector, &connector).await?;
+        assert_eq!(store.download(ids[0]).await?.state, DownloadState::Queued);
+        assert_eq!(store.download(ids[1]).await?.state, DownloadState::Probing);
 
-        assert_ne!(res1, res2);
+        // the open window has closed by then, the download goes back to the queue
+        downloader.enforce_schedule(now + Duration::hours(3)).await;
+        let task = downloader.current_downloads.lock().await.pop().unwrap();
+        task.await?;
+        assert_eq!(store.download(ids[1]).await?.state, DownloadState::Queued);
+        assert!(downloader.active.lock().await.is_empty());
+        assert_eq!(downloader.rate_limiter.limit().await, Some(1024));
+
+        server.abort();
 
         Ok(())
     }
 
-    #[test]
-    fn sqlite_store_remove_by_url_cleans_itself() -> color_eyre::Result<()> {
-        color_eyre::install()?;
+    #[tokio::test]
+    async fn downloads_can_be_paused_resumed_and_cancelled() -> color_eyre::Result<()> {
+        use pretty_assertions::assert_eq;
 
-        let store = init_db()?;
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let downloader = HttpDownloader::new(ChannelHttpRequestSource::new(req_rx), store.clone());
+
+        let path = std::env::temp_dir().join("sulfur-cancel-test");
+        tokio::fs::write(part_path(&path), "half of it").await?;
+        let id = downloader
+            .queue()
+            .enqueue(&HttpRequest {
+                url: WgUrl::parse("http://127.0.0.1:1/file")?,
+                path,
+                options: DownloadOptions::default(),
+                origin: None,
+            })
+            .await?;
 
-        let url = Arc::from(WgUrl::parse("https://www.google.com")?);
-        let file_path: Arc<Path> = Arc::from(PathBuf::from("/tmp/test.txt"));
+        downloader.pause(id).await?;
+        assert_eq!(store.download(id).await?.state, DownloadState::Paused);
+        assert!(downloader.queue().queued().await?.is_empty());
 
-        let download = DownloadContext {
-            id: -90999,
-            url: Arc::clone(&url),
-            offset: 0,
-            total: 5000,
-            file_path: Arc::clone(&file_path),
-        };
+        downloader.resume(id).await?;
+        assert_eq!(store.download(id).await?.state, DownloadState::Queued);
+
+        downloader.cancel(id).await?;
+        assert_eq!(store.download(id).await?.state, DownloadState::Cancelled);
+        assert!(!part_path(&store.download(id).await?.file_path).exists());
 
-        store.add_download(&download)?;
+        // there's no coming back from that
+        assert!(downloader.resume(id).await.is_err());
 
-        let download = DownloadContext {
-            // we do a bit of trolling
-            id: -0,
-            url: Arc::clone(&url),
-            offset: 0,
-            total: 5000,
-            file_path: Arc::clone(&file_path),
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn servers_that_ignore_the_range_fail_the_download() -> color_eyre::Result<()> {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Response, Server,
         };
+        use pretty_assertions::assert_eq;
+        use std::convert::Infallible;
+
+        // a server that sends the whole file whatever range it is asked for
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| {
+                let response = Response::builder().header("Content-Length", 1000);
+                let response = match request.method() == hyper::Method::HEAD {
+                    true => response.body(Body::empty()),
+                    false => response.body(Body::from(vec![b'x'; 1000])),
+                };
+
+                async move { Ok::<_, Infallible>(response.unwrap()) }
+            }))
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let downloader = Arc::new(HttpDownloader::new(
+            ChannelHttpRequestSource::new(req_rx),
+            store.clone(),
+        ));
+
+        let path = std::env::temp_dir().join("sulfur-ignored-range-test");
+        let id = downloader
+            .queue()
+            .enqueue(&HttpRequest {
+                url: WgUrl::parse(&format!("http://{}/file", address))?,
+                path: path.clone(),
+                options: DownloadOptions {
+                    connections: Some(4),
+                    ..DownloadOptions::default()
+                },
+                origin: None,
+            })
+            .await?;
+
+        let (stop, stop_token) = oneshot::channel();
+        let connector = HttpConnector::new();
+        let running = tokio::spawn({
+            let downloader = downloader.clone();
+            async move {
+                downloader
+                    .run(connector.clone(), connector, stop_token)
+                    .await
+            }
+        });
 
-        store.add_download(&download)?;
-        store.remove_by_url(&url)?;
-        {
-            use crate::schema::http_subdownload::dsl::*;
+        time::timeout(Duration::from_secs(5), async {
+            while !store.download(id).await.unwrap().state.is_finished() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
 
-            let result = http_subdownload.load::<SubDownloadTable>(&store.pool.get()?);
-            assert!(result.is_ok());
-            assert!(result.unwrap().is_empty());
+        let record = store.download(id).await?;
+        assert_eq!(record.state, DownloadState::Failed);
+        assert_eq!(
+            record.error,
+            Some(HttpDownloaderError::BadStatus(StatusCode::OK).to_string())
+        );
 
-            let result = crate::schema::url::dsl::url.load::<UrlTable>(&store.pool.get()?);
-            assert!(result.is_ok());
-            assert!(result.unwrap().is_empty());
-        }
+        stop.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), running).await??;
+        let _ = tokio::fs::remove_file(part_path(&path)).await;
+        server.abort();
 
-        {
-            use crate::schema::file_path::dsl::*;
+        Ok(())
+    }
 
-            let result = file_path.load::<PathTable>(&store.pool.get()?);
-            assert!(result.is_ok());
-            assert!(result.unwrap().is_empty());
-        }
+    #[tokio::test]
+    async fn bodies_that_end_early_fail_the_download() -> color_eyre::Result<()> {
+        use hyper::{
+            body::Bytes,
+            service::{make_service_fn, service_fn},
+            Response, Server,
+        };
+        use pretty_assertions::assert_eq;
+        use std::convert::Infallible;
+
+        // a server that answers the right range, then hangs up after 100 of its 1000 bytes
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| {
+                let response = Response::builder().header("Content-Length", 1000);
+                let response = match request.method() == hyper::Method::HEAD {
+                    true => response.body(Body::empty()),
+                    false => {
+                        let (mut sender, body) = Body::channel();
+                        sender.try_send_data(Bytes::from(vec![b'x'; 100])).unwrap();
+                        Response::builder()
+                            .status(206)
+                            .header("Content-Range", "bytes 0-999/1000")
+                            .body(body)
+                    }
+                };
+
+                async move { Ok::<_, Infallible>(response.unwrap()) }
+            }))
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let downloader = Arc::new(HttpDownloader::new(
+            ChannelHttpRequestSource::new(req_rx),
+            store.clone(),
+        ));
+
+        let path = std::env::temp_dir().join("sulfur-early-end-test");
+        let _ = tokio::fs::remove_file(&path).await;
+        let id = downloader
+            .queue()
+            .enqueue(&HttpRequest {
+                url: WgUrl::parse(&format!("http://{}/file", address))?,
+                path: path.clone(),
+                options: DownloadOptions {
+                    connections: Some(1),
+                    ..DownloadOptions::default()
+                },
+                origin: None,
+            })
+            .await?;
+
+        let (stop, stop_token) = oneshot::channel();
+        let connector = HttpConnector::new();
+        let running = tokio::spawn({
+            let downloader = downloader.clone();
+            async move {
+                downloader
+                    .run(connector.clone(), connector, stop_token)
+                    .await
+            }
+        });
+
+        time::timeout(Duration::from_secs(5), async {
+            while !store.download(id).await.unwrap().state.is_finished() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        let record = store.download(id).await?;
+        assert_eq!(record.state, DownloadState::Failed);
+        assert_eq!(
+            record.error,
+            Some(HttpDownloaderError::BadServer.to_string())
+        );
+        // the zeros after the first 100 bytes never make it to the path
+        assert!(!path.exists());
+
+        stop.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), running).await??;
+        let _ = tokio::fs::remove_file(part_path(&path)).await;
+        server.abort();
 
         Ok(())
     }
 
-    #[test]
-    fn sqlite_store_removes_by_id() -> color_eyre::Result<()> {
+    #[tokio::test]
+    async fn stopping_doesnt_wait_for_servers_that_never_answer() -> color_eyre::Result<()> {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Response, Server,
+        };
         use pretty_assertions::assert_eq;
+        use std::convert::Infallible;
 
-        color_eyre::install()?;
+        // a server that knows the file but never answers a request for any of it
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                if request.method() != hyper::Method::HEAD {
+                    futures::future::pending::<()>().await;
+                }
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .header("Content-Length", 1000)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }))
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let downloader = Arc::new(
+            HttpDownloader::new(ChannelHttpRequestSource::new(req_rx), store.clone())
+                // only stopping may end the download in time
+                .with_shutdown_timeout(Duration::from_secs(60 * 60)),
+        );
+
+        let path = std::env::temp_dir().join("sulfur-silent-server-test");
+        let id = downloader
+            .queue()
+            .enqueue(&HttpRequest {
+                url: WgUrl::parse(&format!("http://{}/file", address))?,
+                path: path.clone(),
+                options: DownloadOptions {
+                    connections: Some(1),
+                    ..DownloadOptions::default()
+                },
+                origin: None,
+            })
+            .await?;
+
+        let (stop, stop_token) = oneshot::channel();
+        let connector = HttpConnector::new();
+        let running = tokio::spawn({
+            let downloader = downloader.clone();
+            async move {
+                downloader
+                    .run(connector.clone(), connector, stop_token)
+                    .await
+            }
+        });
 
-        let store = init_db()?;
+        // wait for the range to be asked for
+        time::timeout(Duration::from_secs(5), async {
+            while store.download(id).await.unwrap().state != DownloadState::Running {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        time::sleep(Duration::from_millis(100)).await;
 
-        let url = Arc::from(WgUrl::parse("https://www.google.com")?);
-        let file_path: Arc<Path> = Arc::from(PathBuf::from("/tmp/test.txt"));
+        stop.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), running).await??;
 
-        let mut download1 = DownloadContext {
-            id: -90999,
-            url: Arc::clone(&url),
-            offset: 0,
-            total: 5000,
-            file_path: Arc::clone(&file_path),
-        };
+        assert_eq!(store.download(id).await?.state, DownloadState::Queued);
 
-        download1.id = store.add_download(&download1)?;
+        let _ = tokio::fs::remove_file(part_path(&path)).await;
+        server.abort();
 
-        let mut download2 = DownloadContext {
-            // we do a bit of trolling
-            id: -0,
-            url: Arc::clone(&url),
-            offset: 0,
-            total: 5000,
-            file_path: Arc::clone(&file_path),
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stopping_writes_down_the_progress_of_running_downloads() -> color_eyre::Result<()> {
+        use hyper::{
+            body::Bytes,
+            service::{make_service_fn, service_fn},
+            Response, Server,
         };
+        use pretty_assertions::assert_eq;
+        use std::convert::Infallible;
+
+        // a server that sends the first 100 bytes of the file and then nothing, ever
+        let bodies = Arc::new(std::sync::Mutex::new(vec![]));
+        let senders = bodies.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let senders = senders.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let response = Response::builder().header("Content-Length", 1000);
+                    let response = match request.method() == hyper::Method::HEAD {
+                        true => response.body(Body::empty()),
+                        false => {
+                            let (mut sender, body) = Body::channel();
+                            sender.try_send_data(Bytes::from(vec![b'x'; 100])).unwrap();
+                            senders.lock().unwrap().push(sender);
+                            response
+                                .status(206)
+                                .header("Content-Range", "bytes 0-999/1000")
+                                .body(body)
+                        }
+                    };
+
+                    async move { Ok::<_, Infallible>(response.unwrap()) }
+                }))
+            }
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let downloader = Arc::new(
+            HttpDownloader::new(ChannelHttpRequestSource::new(req_rx), store.clone())
+                // only stopping may write the progress down
+                .with_checkpoint_interval(Duration::from_secs(60 * 60)),
+        );
+
+        let path = std::env::temp_dir().join("sulfur-shutdown-test");
+        let _ = tokio::fs::remove_file(part_path(&path)).await;
+        let id = downloader
+            .queue()
+            .enqueue(&HttpRequest {
+                url: WgUrl::parse(&format!("http://{}/file", address))?,
+                path: path.clone(),
+                options: DownloadOptions {
+                    connections: Some(1),
+                    ..DownloadOptions::default()
+                },
+                origin: None,
+            })
+            .await?;
+
+        let (stop, stop_token) = oneshot::channel();
+        let connector = HttpConnector::new();
+        let running = tokio::spawn({
+            let downloader = downloader.clone();
+            async move {
+                downloader
+                    .run(connector.clone(), connector, stop_token)
+                    .await
+            }
+        });
 
-        download2.id = store.add_download(&download2)?;
-        store.remove_by_id(download1.id)?;
+        // wait for the data to make it to the part file
+        time::timeout(Duration::from_secs(5), async {
+            while !tokio::fs::read(part_path(&path))
+                .await
+                .is_ok_and(|data| data.first() == Some(&b'x'))
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
 
-        {
-            use crate::schema::http_subdownload::dsl::*;
+        stop.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), running).await??;
 
-            let result = http_subdownload.load::<SubDownloadTable>(&store.pool.get()?)?;
+        assert_eq!(store.download(id).await?.state, DownloadState::Queued);
+        assert_eq!(store.segments(id).await?[0].offset, 100);
+
+        tokio::fs::remove_file(part_path(&path)).await?;
+        server.abort();
+        drop(bodies);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarts_only_finish_downloads_that_were_verified() -> color_eyre::Result<()> {
+        use pretty_assertions::assert_eq;
 
-            assert_eq!(result.len(), 1);
-            assert_eq!(result[0].id, download2.id);
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let downloader = Arc::new(HttpDownloader::new(
+            ChannelHttpRequestSource::new(req_rx),
+            store.clone(),
+        ));
+
+        // an older file is in the way of the one still probing
+        let directory = std::env::temp_dir().join("sulfur-recover-test");
+        let _ = tokio::fs::remove_dir_all(&directory).await;
+        tokio::fs::create_dir_all(&directory).await?;
+        let mut ids = vec![];
+        for name in ["probing", "verifying"] {
+            let path = directory.join(name);
+            tokio::fs::write(&path, name).await?;
+            ids.push(
+                downloader
+                    .queue()
+                    .enqueue(&HttpRequest {
+                        url: WgUrl::parse(&format!("http://localhost/{}", name))?,
+                        path,
+                        options: DownloadOptions::default(),
+                        origin: None,
+                    })
+                    .await?,
+            );
         }
+        store
+            .set_state(ids[0], DownloadState::Probing, None)
+            .await?;
+        store.add_segments(ids[1], 8, &[]).await?;
+        store
+            .set_state(ids[1], DownloadState::Verifying, None)
+            .await?;
+
+        downloader.recover_downloads().await?;
+
+        assert_eq!(store.download(ids[0]).await?.state, DownloadState::Queued);
+        assert_eq!(
+            store.download(ids[1]).await?.state,
+            DownloadState::Completed
+        );
+
+        tokio::fs::remove_dir_all(&directory).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rules_without_a_limit_lift_the_global_one() -> color_eyre::Result<()> {
+        use crate::schedule::BandwidthRule;
+        use chrono::NaiveDate;
+        use pretty_assertions::assert_eq;
+
+        let (_req_tx, req_rx) = mpsc::channel(1);
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let overnight = BandwidthRule {
+            days: vec![],
+            start: "22:00".parse()?,
+            end: "06:00".parse()?,
+            limit: None,
+        };
+        let downloader = HttpDownloader::new(ChannelHttpRequestSource::new(req_rx), store)
+            .with_rate_limit(Some(1024))
+            .with_bandwidth_schedule(BandwidthSchedule {
+                rules: vec![overnight],
+            });
+        let at = |hour| {
+            NaiveDate::from_ymd_opt(2022, 6, 6)
+                .unwrap()
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+        };
+
+        downloader.enforce_schedule(at(23)).await;
+        assert_eq!(downloader.rate_limiter.limit().await, None);
+        downloader.enforce_schedule(at(12)).await;
+        assert_eq!(downloader.rate_limiter.limit().await, Some(1024));
 
         Ok(())
     }
@@ -894,6 +1974,8 @@ mod tests {
                 path: current_dir()
                     .unwrap()
                     .join("ubuntu-22.04-desktop-amd64.iso"),
+                options: DownloadOptions::default(),
+                origin: None,
             })
             .await
             .unwrap();
@@ -2,55 +2,240 @@
 #[macro_use]
 extern crate diesel;
 
-#[cfg(test)]
 #[macro_use]
 extern crate diesel_migrations;
 
 
 mod schema;
+mod sink;
+mod store;
 mod api;
+mod checkpoint;
+mod cli;
+mod config;
+mod disk;
+mod export;
+mod format;
+mod get;
+mod history;
 mod http;
+mod queue;
 mod request;
+mod schedule;
+mod speed;
 mod util;
 
 
-use axum::{routing::get, Router};
+use axum::{
+    routing::{get, post, put},
+    Extension, Router,
+};
 
 
 
-use std::net::SocketAddr;
+use clap::Parser;
+use color_eyre::eyre::eyre;
+use request::{
+    feed::FeedHttpRequestSource, json_lines::JsonLinesHttpRequestSource,
+    monitor::MonitorHttpRequestSource,
+};
+#[cfg(target_os = "linux")]
+use request::watch_folder::WatchFolderHttpRequestSource;
+use std::sync::Arc;
+use tokio::sync::oneshot;
 use tower_http::{trace::TraceLayer};
-use tracing::{Level};
 use tokio::task::JoinSet;
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
+    let cli = cli::Cli::parse();
+    let config = config::Config::load(cli.config.as_deref(), &cli.overrides)?;
+
+    let command = match cli.command {
+        Some(cli::Command::Get(get)) => {
+            let connector = config.connector().map_err(|e| eyre!(e))?;
+            match get.run(&config, connector).await {
+                Ok(path) => {
+                    println!("{}", path.display());
+                    return Ok(());
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Some(cli::Command::Store(command)) => Some(command),
+        None => None,
+    };
+
+    let database_url = config.database.to_string_lossy();
+    let store: store::SharedDownloadStore =
+        Arc::new(store::sqlite::SqliteStore::open(&database_url)?);
+
+    // subcommands may write their output to stdout, keep the logs out of it
+    if let Some(command) = command {
+        return command.run(store).await;
+    }
 
     tracing_subscriber::fmt()
-        .with_max_level(Level::TRACE)
+        .with_max_level(config.log_level())
         .init();
 
+    // shared with the downloader so the API can tell which filesystems are paused for being full
+    let disk_monitor = Arc::new(disk::DiskSpaceMonitor::default());
+
+    let connector = config.connector().map_err(|e| eyre!(e))?;
+
+    let (downloader, sources) = http::HttpDownloader::with_request_sources(store.clone());
+    let downloader = Arc::new(
+        downloader
+            .with_max_concurrent_downloads(config.downloads.max_concurrent)
+            .with_checkpoint_interval(config.checkpoint_interval())
+            .with_rate_limit(config.limits.rate)
+            .with_bandwidth_schedule(config.limits.schedule.clone())
+            .with_retention_policy(config.history.clone())
+            .with_shutdown_timeout(config.shutdown_timeout())
+            .with_disk_monitor(disk_monitor.clone()),
+    );
+    // the API and the downloader have to share the queue, so changes to it wake the downloader
+    let queue = downloader.queue().clone();
+    // every client sees the same speed, however often it asks
+    let speeds = speed::SharedSpeeds::default();
+    let aria2 = Arc::new(api::aria2::Aria2::new(
+        store.clone(),
+        downloader.clone(),
+        config.download_dir.clone(),
+        config.rpc_secret.clone(),
+        speeds.clone(),
+    ));
+
+    #[cfg(target_os = "linux")]
+    if !config.watch_folders.is_empty() {
+        let source = WatchFolderHttpRequestSource::new(config.watch_folders.clone())?;
+        sources.add("watch-folder", source);
+    }
+    if let Some(requests) = &config.requests {
+        match requests.as_os_str() == "-" {
+            true => sources.add("requests", JsonLinesHttpRequestSource::stdin(None).await?),
+            false => sources.add("requests", JsonLinesHttpRequestSource::open(requests).await?),
+        }
+    }
+    if !config.feeds.is_empty() {
+        let source =
+            FeedHttpRequestSource::new(config.feeds.clone(), store.clone(), connector.clone())?;
+        sources.add("feed", source);
+    }
+    if !config.monitored.is_empty() {
+        let source =
+            MonitorHttpRequestSource::new(config.monitored.clone(), store.clone(), connector.clone());
+        sources.add("monitor", source);
+    }
+
+    let (stop_downloads, stop_token) = oneshot::channel();
+    let downloads = tokio::spawn({
+        let downloader = downloader.clone();
+        async move { downloader.run(connector.clone(), connector, stop_token).await }
+    });
+
+    // the server and the downloads stop together, rather than the downloads waiting on whatever
+    // connections the server is still finishing
+    let (stop_server, server_stopped) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutting down");
+        let _ = stop_server.send(());
+        let _ = stop_downloads.send(true);
+    });
+
     let app = Router::new()
-        .route("/", get(root))
+        .route("/", get(api::ui::index))
         .layer(TraceLayer::new_for_http())
         .route("/api/v1/hello-world", get(hello_world))
+        .route(
+            "/jsonrpc",
+            get(api::aria2::websocket).post(api::aria2::rpc),
+        )
+        .route("/api/v1/disks", get(api::v1::disks))
+        .route(
+            "/api/v1/queue",
+            get(api::v1::queue).put(api::v1::reorder_queue),
+        )
+        .route("/api/v1/queue/:id/front", post(api::v1::move_to_front))
+        .route("/api/v1/queue/:id/priority", put(api::v1::set_priority))
+        .route("/api/v1/events", get(api::v1::events::events))
+        .route("/api/v1/history", get(api::v1::history))
+        .route("/api/v1/export", get(api::v1::export))
+        .route("/api/v1/import", post(api::v1::import))
+        .route(
+            "/api/v1/downloads",
+            get(api::v1::downloads).post(api::v1::create_download),
+        )
+        .route("/api/v1/downloads/:id", get(api::v1::download))
+        .route("/api/v1/downloads/:id/segments", get(api::v1::segments))
+        .route("/api/v1/downloads/:id/pause", post(api::v1::pause))
+        .route("/api/v1/downloads/:id/resume", post(api::v1::resume))
+        .route("/api/v1/downloads/:id/cancel", post(api::v1::cancel))
+        .route(
+            "/api/v1/limits",
+            get(api::v1::limits).put(api::v1::set_limits),
+        )
+        .layer(Extension(api::v1::DownloadDir(config.download_dir.clone())))
+        .layer(Extension(aria2))
+        .layer(Extension(speeds))
+        .layer(Extension(downloader.clone()))
+        .layer(Extension(disk_monitor))
+        .layer(Extension(queue))
+        .layer(Extension(store))
         // .route("/api/v1/download", get(v1::new_download));
         ;
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 6969));
+    let addr = config.bind;
 
     tracing::info!("listening on {}", addr);
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            let _ = server_stopped.await;
+        })
         .await
         .unwrap();
 
+    // the downloads write down where they are before we let go of the store
+    downloads.await?;
+
     Ok(())
 }
 
-async fn root() -> &'static str {
-    "This is the root, you shouldn't be here"
+/// Wait for SIGINT, or SIGTERM where there is such a thing
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("failed to listen for SIGINT: {}", e);
+            futures::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("failed to listen for SIGTERM: {}", e);
+                futures::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
 }
 
 async fn hello_world() -> &'static str {
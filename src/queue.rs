@@ -0,0 +1,257 @@
+use crate::{
+    request::http::HttpRequest,
+    store::{DownloadRecord, DownloadState, SharedDownloadStore, StoreError},
+};
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
+
+/// The downloads waiting for their turn. Accepted requests are kept in the store as queued
+/// downloads, so nothing is lost if we die before getting to them.
+///
+/// The queue is shared between the downloader, which takes downloads off it, and whoever wants to
+/// rearrange it; every change wakes the downloader up to have another look.
+#[derive(Debug, Clone)]
+pub struct DownloadQueue {
+    store: SharedDownloadStore,
+    changed: Arc<Notify>,
+    // reordering reads the whole queue before writing it back, two at once would undo each other
+    rearranging: Arc<Mutex<()>>,
+}
+
+impl DownloadQueue {
+    pub fn new(store: SharedDownloadStore) -> Self {
+        Self {
+            store,
+            changed: Arc::new(Notify::new()),
+            rearranging: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Put `request` at the back of the queue, returning the id of its download
+    pub async fn enqueue(&self, request: &HttpRequest) -> Result<i32, StoreError> {
+        self.enqueue_with_priority(request, 0).await
+    }
+
+    /// Put `request` at the back of the downloads with the same `priority`, returning the id of its
+    /// download
+    pub async fn enqueue_with_priority(
+        &self,
+        request: &HttpRequest,
+        priority: i32,
+    ) -> Result<i32, StoreError> {
+        let record = DownloadRecord {
+            origin: request.origin.clone(),
+            priority,
+            ..DownloadRecord::new(
+                request.url.clone(),
+                request.path.clone(),
+                DownloadState::Queued,
+                request.options.clone(),
+            )
+        };
+        let (download_id, _) = self.store.create_download(&record, &[]).await?;
+
+        self.notify();
+
+        Ok(download_id)
+    }
+
+    /// Every queued download, the next one to start first
+    pub async fn queued(&self) -> Result<Vec<DownloadRecord>, StoreError> {
+        self.store.queue().await
+    }
+
+    pub async fn set_priority(&self, download_id: i32, priority: i32) -> Result<(), StoreError> {
+        let _rearranging = self.rearranging.lock().await;
+
+        self.queued_download(download_id).await?;
+        self.store
+            .set_priorities(&[(download_id, priority)])
+            .await?;
+
+        self.notify();
+
+        Ok(())
+    }
+
+    /// Move the given downloads to the front of the queue, in the given order. The rest keep their
+    /// order behind them.
+    pub async fn reorder(&self, download_ids: &[i32]) -> Result<(), StoreError> {
+        let _rearranging = self.rearranging.lock().await;
+
+        let mut queue = self.store.queue().await?;
+        let mut front = Vec::with_capacity(download_ids.len());
+        for download_id in download_ids {
+            let position = queue
+                .iter()
+                .position(|queued| queued.id == *download_id)
+                .ok_or(StoreError::NotFound)?;
+            front.push(queue.remove(position));
+        }
+        front.append(&mut queue);
+
+        // the order is all that matters, so the priorities simply count down to the back
+        let priorities: Vec<_> = front
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(priority, queued)| (queued.id, priority as i32))
+            .collect();
+        self.store.set_priorities(&priorities).await?;
+
+        self.notify();
+
+        Ok(())
+    }
+
+    pub async fn move_to_front(&self, download_id: i32) -> Result<(), StoreError> {
+        self.reorder(&[download_id]).await
+    }
+
+    /// Put a queued download at `position` in the queue, 0 being the front and anything past the
+    /// end the back. Returns where it ended up.
+    pub async fn move_to(&self, download_id: i32, position: usize) -> Result<usize, StoreError> {
+        let mut order: Vec<_> = self
+            .queued()
+            .await?
+            .iter()
+            .map(|queued| queued.id)
+            .collect();
+        let current = order
+            .iter()
+            .position(|queued| *queued == download_id)
+            .ok_or(StoreError::NotFound)?;
+        order.remove(current);
+
+        let position = position.min(order.len());
+        order.insert(position, download_id);
+        self.reorder(&order).await?;
+
+        Ok(position)
+    }
+
+    /// Let the downloader know it should have another look at the queue
+    pub fn notify(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Wait until something about the queue or the running downloads changed
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    async fn queued_download(&self, download_id: i32) -> Result<DownloadRecord, StoreError> {
+        let record = self.store.download(download_id).await?;
+
+        match record.state {
+            DownloadState::Queued => Ok(record),
+            _ => Err(StoreError::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request::http::DownloadOptions, store::memory::MemoryStore};
+    use pretty_assertions::assert_eq;
+    use url::Url;
+
+    async fn queue_of(count: usize) -> color_eyre::Result<(DownloadQueue, Vec<i32>)> {
+        let queue = DownloadQueue::new(Arc::new(MemoryStore::new()));
+
+        let mut ids = vec![];
+        for i in 0..count {
+            let request = HttpRequest {
+                url: Url::parse("https://www.google.com")?,
+                path: format!("/tmp/{}.txt", i).into(),
+                options: DownloadOptions::default(),
+                origin: None,
+            };
+            ids.push(queue.enqueue(&request).await?);
+        }
+
+        Ok((queue, ids))
+    }
+
+    async fn order(queue: &DownloadQueue) -> color_eyre::Result<Vec<i32>> {
+        Ok(queue
+            .queued()
+            .await?
+            .iter()
+            .map(|queued| queued.id)
+            .collect())
+    }
+
+    #[tokio::test]
+    async fn requests_are_queued_in_submission_order() -> color_eyre::Result<()> {
+        let (queue, ids) = queue_of(3).await?;
+
+        assert_eq!(order(&queue).await?, ids);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reorder_moves_the_given_downloads_to_the_front() -> color_eyre::Result<()> {
+        let (queue, ids) = queue_of(4).await?;
+
+        queue.reorder(&[ids[3], ids[1]]).await?;
+        assert_eq!(order(&queue).await?, vec![ids[3], ids[1], ids[0], ids[2]]);
+
+        queue.move_to_front(ids[2]).await?;
+        assert_eq!(order(&queue).await?, vec![ids[2], ids[3], ids[1], ids[0]]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn downloads_can_be_moved_anywhere_in_the_queue() -> color_eyre::Result<()> {
+        let (queue, ids) = queue_of(3).await?;
+
+        assert_eq!(queue.move_to(ids[0], 1).await?, 1);
+        assert_eq!(order(&queue).await?, vec![ids[1], ids[0], ids[2]]);
+
+        assert_eq!(queue.move_to(ids[1], 10).await?, 2);
+        assert_eq!(order(&queue).await?, vec![ids[0], ids[2], ids[1]]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn urgent_downloads_skip_the_line() -> color_eyre::Result<()> {
+        let (queue, ids) = queue_of(2).await?;
+        let request = HttpRequest {
+            url: Url::parse("https://www.google.com")?,
+            path: "/tmp/urgent.txt".into(),
+            options: DownloadOptions::default(),
+            origin: None,
+        };
+        let urgent = queue.enqueue_with_priority(&request, 5).await?;
+
+        assert_eq!(order(&queue).await?, vec![urgent, ids[0], ids[1]]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_queued_downloads_can_be_rearranged() -> color_eyre::Result<()> {
+        let (queue, ids) = queue_of(2).await?;
+        queue
+            .store
+            .set_state(ids[0], DownloadState::Probing, None)
+            .await?;
+
+        assert!(matches!(
+            queue.move_to_front(ids[0]).await,
+            Err(StoreError::NotFound)
+        ));
+        assert!(matches!(
+            queue.set_priority(ids[0], 3).await,
+            Err(StoreError::NotFound)
+        ));
+        assert_eq!(order(&queue).await?, vec![ids[1]]);
+
+        Ok(())
+    }
+}
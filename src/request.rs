@@ -1,4 +1,10 @@
+pub(crate) mod feed;
 pub(crate) mod http;
+pub(crate) mod json_lines;
+pub(crate) mod merged;
+pub(crate) mod monitor;
+#[cfg(target_os = "linux")]
+pub(crate) mod watch_folder;
 
 #[non_exhaustive]
 pub enum Request<T> {
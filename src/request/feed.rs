@@ -0,0 +1,804 @@
+use super::http::{file_name_of, DownloadOptions, HttpRequest, HttpRequestSource};
+use crate::{
+    schedule::local_now,
+    store::{SharedDownloadStore, StoreError},
+};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate};
+use hyper::{
+    body::HttpBody, client::connect::Connect, header, Body, Client, Request, Response, StatusCode,
+};
+use quick_xml::{events::Event, Reader};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::{Debug, Display, Formatter},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{event, Level};
+use url::Url;
+
+/// How often the feeds are fetched unless told otherwise
+pub const DEFAULT_FEED_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// How many redirects fetching a feed may go through
+const MAX_REDIRECTS: usize = 5;
+
+/// How long fetching a feed may take unless told otherwise, from the first request to the end of
+/// its body
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most of a feed we read, anything bigger isn't a feed we want
+const MAX_FEED_SIZE: usize = 16 * 1024 * 1024;
+/// What can be filled in from an item in the destination of a feed
+const PLACEHOLDERS: [&str; 4] = ["feed", "title", "file_name", "date"];
+
+/// A feed whose new items are downloaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Feed {
+    pub url: Url,
+    /// Where each item goes, with `{feed}`, `{title}`, `{file_name}` and `{date}` filled in from
+    /// the item, e.g. `/srv/podcasts/{feed}/{date} {title}.mp3`
+    pub destination: String,
+    /// Only items whose title or url match this regex are downloaded, every item if not given
+    pub include: Option<String>,
+    /// Items whose title or url match this regex are left alone
+    pub exclude: Option<String>,
+    /// The options of every download of this feed
+    #[serde(default)]
+    pub options: DownloadOptions,
+}
+
+impl Feed {
+    /// Whether the feed is set up right, i.e. its filters are regexes and its destination only
+    /// has placeholders we know
+    pub fn check(&self) -> Result<(), FeedError> {
+        Subscription::new(self.clone()).map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+pub enum FeedError {
+    /// A feed isn't set up right, e.g. its include pattern isn't a regex
+    Invalid(String),
+    Store(StoreError),
+}
+
+impl Display for FeedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeedError::Invalid(reason) => write!(f, "Invalid feed: {}", reason),
+            FeedError::Store(e) => write!(f, "Failed to remember the feed items: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FeedError {}
+
+impl From<StoreError> for FeedError {
+    fn from(e: StoreError) -> Self {
+        FeedError::Store(e)
+    }
+}
+
+/// An `<item>` of an RSS feed or an `<entry>` of an Atom feed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct FeedItem {
+    id: Option<String>,
+    title: Option<String>,
+    enclosure: Option<Url>,
+    link: Option<Url>,
+    published: Option<NaiveDate>,
+}
+
+impl FeedItem {
+    /// What gets downloaded, the enclosure if there is one
+    fn url(&self) -> Option<&Url> {
+        self.enclosure.as_ref().or(self.link.as_ref())
+    }
+
+    /// What the item is remembered by, its guid or id if it has one
+    fn key(&self) -> Option<String> {
+        self.id.clone().or_else(|| self.url().map(Url::to_string))
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ParsedFeed {
+    title: Option<String>,
+    items: Vec<FeedItem>,
+}
+
+/// Either an RFC 2822 date like RSS has or an RFC 3339 one like Atom has
+fn parse_date(text: &str) -> Option<NaiveDate> {
+    DateTime::parse_from_rfc2822(text)
+        .or_else(|_| DateTime::parse_from_rfc3339(text))
+        .ok()
+        .map(|date| date.naive_local().date())
+}
+
+/// The items of an RSS or Atom feed, with relative urls resolved against `base`
+fn parse_feed(text: &str, base: &Url) -> Result<ParsedFeed, String> {
+    let mut reader = Reader::from_str(text);
+    reader.trim_text(true);
+
+    let mut feed = ParsedFeed::default();
+    let mut buffer = vec![];
+    let mut is_feed = false;
+    let mut item: Option<FeedItem> = None;
+    // the element whose text is being read, along with the text so far
+    let mut inside: Option<(Vec<u8>, String)> = None;
+
+    loop {
+        let event = reader
+            .read_event(&mut buffer)
+            .map_err(|e| format!("invalid feed at {}: {}", reader.buffer_position(), e))?;
+
+        match event {
+            Event::Start(ref element) | Event::Empty(ref element) => {
+                let empty = matches!(event, Event::Empty(_));
+                let attribute = |key: &[u8]| -> Option<String> {
+                    element
+                        .attributes()
+                        .flatten()
+                        .find(|attribute| attribute.key == key)
+                        .and_then(|attribute| {
+                            attribute
+                                .unescaped_value()
+                                .ok()
+                                .map(|value| String::from_utf8_lossy(&value).into_owned())
+                        })
+                };
+                let href = |key: &[u8]| attribute(key).and_then(|url| base.join(&url).ok());
+
+                // prefixed elements, e.g. <itunes:title>, are left alone
+                match element.name() {
+                    b"rss" | b"feed" | b"rdf:RDF" => is_feed = true,
+                    b"item" | b"entry" => item = Some(FeedItem::default()),
+                    b"enclosure" => {
+                        if let Some(item) = item.as_mut().filter(|item| item.enclosure.is_none()) {
+                            item.enclosure = href(b"url");
+                        }
+                    }
+                    // atom links are all in the attributes
+                    b"link" if attribute(b"href").is_some() => {
+                        if let Some(item) = &mut item {
+                            match attribute(b"rel").as_deref() {
+                                Some("enclosure") if item.enclosure.is_none() => {
+                                    item.enclosure = href(b"href")
+                                }
+                                None | Some("alternate") if item.link.is_none() => {
+                                    item.link = href(b"href")
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    name @ (b"title" | b"link" | b"guid" | b"id" | b"pubDate" | b"published"
+                    | b"updated")
+                        if !empty =>
+                    {
+                        inside = Some((name.to_vec(), String::new()))
+                    }
+                    _ => {}
+                }
+            }
+            Event::Text(text) => {
+                if let Some((_, content)) = &mut inside {
+                    content.push_str(
+                        &text
+                            .unescape_and_decode(&reader)
+                            .map_err(|e| e.to_string())?,
+                    );
+                }
+            }
+            Event::CData(data) => {
+                if let Some((_, content)) = &mut inside {
+                    content.push_str(&String::from_utf8_lossy(&data.into_inner()));
+                }
+            }
+            Event::End(element) => match element.name() {
+                b"item" | b"entry" => feed.items.extend(item.take()),
+                name => match inside.take() {
+                    Some((element, content)) if element == name => {
+                        let content = content.trim().to_string();
+
+                        match (&mut item, name) {
+                            // the first title outside of the items is the feed's, an <image> of
+                            // the channel has one too
+                            (None, b"title") if feed.title.is_none() => feed.title = Some(content),
+                            (Some(item), b"title") => item.title = Some(content),
+                            (Some(item), b"link") if item.link.is_none() => {
+                                item.link = base.join(&content).ok()
+                            }
+                            (Some(item), b"guid" | b"id") => item.id = Some(content),
+                            (Some(item), b"pubDate" | b"published" | b"updated")
+                                if item.published.is_none() =>
+                            {
+                                item.published = parse_date(&content)
+                            }
+                            _ => {}
+                        }
+                    }
+                    other => inside = other,
+                },
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+
+    match is_feed {
+        true => Ok(feed),
+        false => Err("neither an RSS nor an Atom feed".to_string()),
+    }
+}
+
+/// Fill in the `{name}` placeholders of `template` with `value(name)`, which is `None` for names
+/// it doesn't know
+fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in {}", template))?
+            + start;
+        let name = &rest[start + 1..end];
+        rendered.push_str(
+            &value(name)
+                .ok_or_else(|| format!("unknown placeholder {{{}}} in {}", name, template))?,
+        );
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// `text` made safe to use as a single part of a path, a title with slashes in it could put the
+/// file anywhere
+fn path_safe(text: &str) -> String {
+    let safe: String = text
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    match safe.trim() {
+        "" | "." | ".." => "_".to_string(),
+        safe => safe.to_string(),
+    }
+}
+
+/// A feed along with its compiled filters
+#[derive(Debug)]
+struct Subscription {
+    feed: Feed,
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+}
+
+impl Subscription {
+    fn new(feed: Feed) -> Result<Self, FeedError> {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|e| FeedError::Invalid(format!("{}: {}", feed.url, e)))
+        };
+        let include = compile(&feed.include)?;
+        let exclude = compile(&feed.exclude)?;
+
+        // better to find out now than on the first new item
+        render(&feed.destination, |name| {
+            PLACEHOLDERS.contains(&name).then(String::new)
+        })
+        .map_err(|e| FeedError::Invalid(format!("{}: {}", feed.url, e)))?;
+
+        Ok(Self {
+            feed,
+            include,
+            exclude,
+        })
+    }
+
+    fn wants(&self, item: &FeedItem, url: &Url) -> bool {
+        let matches = |pattern: &Regex| {
+            pattern.is_match(url.as_str())
+                || item
+                    .title
+                    .as_deref()
+                    .is_some_and(|title| pattern.is_match(title))
+        };
+
+        self.include.as_ref().map_or(true, matches) && !self.exclude.as_ref().is_some_and(matches)
+    }
+
+    fn request_of(&self, feed_title: Option<&str>, item: &FeedItem, url: Url) -> HttpRequest {
+        let file_name = file_name_of(&url);
+        let path = render(&self.feed.destination, |name| {
+            let value = match name {
+                "feed" => feed_title
+                    .map(str::to_string)
+                    .or_else(|| self.feed.url.host_str().map(str::to_string)),
+                "title" => item.title.clone().or_else(|| file_name.clone()),
+                "file_name" => file_name.clone(),
+                "date" => Some(
+                    item.published
+                        .unwrap_or_else(|| local_now().date())
+                        .to_string(),
+                ),
+                _ => return None,
+            };
+
+            Some(path_safe(value.as_deref().unwrap_or_default()))
+        })
+        // the template was checked when subscribing
+        .unwrap_or_default();
+
+        HttpRequest {
+            url,
+            path: path.into(),
+            options: self.feed.options.clone(),
+            origin: None,
+        }
+    }
+}
+
+/// Send the request `build` makes for `url`, and again for wherever the server redirects it to.
+/// Returns the url that was answered along with the answer, which may be anything but a redirect.
+pub(super) async fn send_following_redirects<C>(
+    client: &Client<C>,
+    url: &Url,
+    build: impl Fn(&Url) -> Result<Request<Body>, hyper::http::Error>,
+) -> Result<(Url, Response<Body>), String>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let mut url = url.clone();
+
+    for _ in 0..=MAX_REDIRECTS {
+        let request = build(&url).map_err(|e| e.to_string())?;
+        let response = client.request(request).await.map_err(|e| e.to_string())?;
+        let status = response.status();
+
+        // not modified is a redirect too, to the copy the client already has
+        if !status.is_redirection() || status == StatusCode::NOT_MODIFIED {
+            return Ok((url, response));
+        }
+
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| format!("{} without a location", status))?;
+        url = url.join(location).map_err(|e| e.to_string())?;
+    }
+
+    Err(format!("more than {} redirects", MAX_REDIRECTS))
+}
+
+/// The whole of `body`, unless it is more than `limit` bytes
+async fn read_limited(mut body: Body, limit: usize) -> Result<Vec<u8>, String> {
+    let mut read = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        if read.len() + chunk.len() > limit {
+            return Err(format!("more than {} bytes", limit));
+        }
+        read.extend_from_slice(&chunk);
+    }
+
+    Ok(read)
+}
+
+/// Get the feed at `url`, giving up after `timeout`. Returns where the feed ended up being along
+/// with its text.
+async fn fetch<C>(client: &Client<C>, url: &Url, timeout: Duration) -> Result<(Url, String), String>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let fetching = async {
+        let (url, response) = send_following_redirects(client, url, |url| {
+            Request::get(url.as_str()).body(Body::empty())
+        })
+        .await?;
+        if !response.status().is_success() {
+            return Err(format!("the server answered {}", response.status()));
+        }
+
+        let body = read_limited(response.into_body(), MAX_FEED_SIZE).await?;
+
+        Ok((url, String::from_utf8_lossy(&body).into_owned()))
+    };
+
+    tokio::time::timeout(timeout, fetching)
+        .await
+        .map_err(|_| format!("no answer within {} seconds", timeout.as_secs_f64()))?
+}
+
+/// Fetches a set of RSS and Atom feeds every so often and hands out a request for every new item,
+/// its enclosure or else its link. The items handed out are remembered in the store, so they
+/// aren't downloaded again after a restart.
+///
+/// A feed that can't be fetched is tried again the next time around. The feeds are fetched one
+/// after the other, each one gets `DEFAULT_FETCH_TIMEOUT` so a feed that doesn't answer only holds
+/// up the others that long.
+#[derive(Debug)]
+pub struct FeedHttpRequestSource<C> {
+    subscriptions: Vec<Subscription>,
+    client: Client<C>,
+    store: SharedDownloadStore,
+    interval: Duration,
+    fetch_timeout: Duration,
+    next_poll: Option<Instant>,
+    /// The new items of the last poll, with the feed and key they are remembered by
+    found: VecDeque<(String, String, HttpRequest)>,
+}
+
+impl<C> FeedHttpRequestSource<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    pub fn new(
+        feeds: Vec<Feed>,
+        store: SharedDownloadStore,
+        connector: C,
+    ) -> Result<Self, FeedError> {
+        Ok(Self {
+            subscriptions: feeds
+                .into_iter()
+                .map(Subscription::new)
+                .collect::<Result<_, _>>()?,
+            client: Client::builder().build(connector),
+            store,
+            interval: DEFAULT_FEED_INTERVAL,
+            fetch_timeout: DEFAULT_FETCH_TIMEOUT,
+            next_poll: None,
+            found: VecDeque::new(),
+        })
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_fetch_timeout(mut self, timeout: Duration) -> Self {
+        self.fetch_timeout = timeout;
+        self
+    }
+
+    /// Fetch every feed, lining up the items that weren't handed out before
+    async fn poll(&mut self) {
+        for subscription in &self.subscriptions {
+            let url = &subscription.feed.url;
+            let feed = match fetch(&self.client, url, self.fetch_timeout)
+                .await
+                .and_then(|(base, text)| parse_feed(&text, &base))
+            {
+                Ok(feed) => feed,
+                Err(e) => {
+                    event!(Level::WARN, "Failed to fetch the feed {}: {}", url, e);
+                    continue;
+                }
+            };
+
+            let feed_url = url.to_string();
+            let mut keys = HashSet::new();
+            // feeds have the newest items first, the oldest are downloaded first
+            for item in feed.items.iter().rev() {
+                let (item_url, key) = match (item.url(), item.key()) {
+                    (Some(item_url), Some(key)) => (item_url, key),
+                    _ => continue,
+                };
+                if !subscription.wants(item, item_url) || !keys.insert(key.clone()) {
+                    continue;
+                }
+                match self.store.has_feed_item(&feed_url, &key).await {
+                    Ok(false) => {}
+                    Ok(true) => continue,
+                    // it is looked at again with the next poll
+                    Err(e) => {
+                        event!(Level::ERROR, "Failed to look up {} of {}: {}", key, url, e);
+                        continue;
+                    }
+                }
+
+                let request =
+                    subscription.request_of(feed.title.as_deref(), item, item_url.clone());
+                self.found.push_back((feed_url.clone(), key, request));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<C> HttpRequestSource for FeedHttpRequestSource<C>
+where
+    C: Connect + Clone + Send + Sync + Debug + 'static,
+{
+    type Error = FeedError;
+
+    async fn get_request(&mut self) -> Result<HttpRequest, FeedError> {
+        loop {
+            if let Some((feed, key, request)) = self.found.pop_front() {
+                // handing it out without remembering it would download it again with every poll,
+                // the next poll finds it again instead
+                match self.store.add_feed_item(&feed, &key).await {
+                    Ok(()) => return Ok(request),
+                    Err(e) => {
+                        event!(
+                            Level::ERROR,
+                            "Failed to remember {} of {}: {}",
+                            key,
+                            feed,
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(next_poll) = self.next_poll {
+                tokio::time::sleep_until(next_poll).await;
+            }
+            self.next_poll = Some(Instant::now() + self.interval);
+            self.poll().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use hyper::{
+        client::HttpConnector,
+        service::{make_service_fn, service_fn},
+        Server,
+    };
+    use pretty_assertions::assert_eq;
+    use std::{convert::Infallible, path::PathBuf, sync::Arc};
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+  <channel>
+    <title>Sulfur Weekly</title>
+    <image><title>Not the title</title></image>
+    <item>
+      <title><![CDATA[Episode 2: Up/Down]]></title>
+      <itunes:title>Not the title either</itunes:title>
+      <guid>episode-2</guid>
+      <pubDate>Tue, 05 Jul 2022 10:00:00 +0000</pubDate>
+      <enclosure url="/episodes/2.mp3" type="audio/mpeg" length="1"/>
+    </item>
+    <item>
+      <title>Season 2 Trailer</title>
+      <guid>trailer</guid>
+      <enclosure url="https://www.google.com/trailer.mp3" type="audio/mpeg" length="1"/>
+    </item>
+    <item>
+      <title>Episode 1</title>
+      <link>https://www.google.com/episodes/1</link>
+      <pubDate>Tue, 28 Jun 2022 10:00:00 +0000</pubDate>
+      <enclosure url="https://www.google.com/episodes/1.mp3" type="audio/mpeg" length="1"/>
+    </item>
+  </channel>
+</rss>"#;
+
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Releases</title>
+  <link href="https://www.google.com/releases"/>
+  <entry>
+    <title>v1.1</title>
+    <id>tag:google.com,2022:v1.1</id>
+    <updated>2022-07-01T12:00:00Z</updated>
+    <link rel="alternate" href="https://www.google.com/releases/v1.1"/>
+    <link rel="enclosure" href="https://www.google.com/releases/v1.1.tar.gz"/>
+  </entry>
+  <entry>
+    <title>v1.0</title>
+    <id>tag:google.com,2022:v1.0</id>
+    <link href="https://www.google.com/releases/v1.0.tar.gz"/>
+  </entry>
+</feed>"#;
+
+    fn feed(url: Url, destination: &str) -> Feed {
+        Feed {
+            url,
+            destination: destination.to_string(),
+            include: None,
+            exclude: None,
+            options: DownloadOptions::default(),
+        }
+    }
+
+    #[test]
+    fn rss_and_atom_items_are_found() {
+        let base = Url::parse("https://www.google.com/podcast.xml").unwrap();
+
+        let rss = parse_feed(RSS, &base).unwrap();
+        assert_eq!(rss.title.as_deref(), Some("Sulfur Weekly"));
+        assert_eq!(rss.items.len(), 3);
+        assert_eq!(
+            rss.items[0],
+            FeedItem {
+                id: Some("episode-2".to_string()),
+                title: Some("Episode 2: Up/Down".to_string()),
+                enclosure: Some(base.join("/episodes/2.mp3").unwrap()),
+                link: None,
+                published: NaiveDate::from_ymd_opt(2022, 7, 5),
+            }
+        );
+        // no guid, the url it is downloaded from will do
+        assert_eq!(
+            rss.items[2].key().as_deref(),
+            Some("https://www.google.com/episodes/1.mp3")
+        );
+
+        let atom = parse_feed(ATOM, &base).unwrap();
+        assert_eq!(atom.title.as_deref(), Some("Releases"));
+        let urls: Vec<_> = atom
+            .items
+            .iter()
+            .map(|item| item.url().unwrap().as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://www.google.com/releases/v1.1.tar.gz",
+                "https://www.google.com/releases/v1.0.tar.gz"
+            ]
+        );
+        assert_eq!(atom.items[0].published, NaiveDate::from_ymd_opt(2022, 7, 1));
+
+        assert!(parse_feed("<html></html>", &base).is_err());
+    }
+
+    #[test]
+    fn destinations_are_checked_and_filled_in_safely() {
+        let url = Url::parse("https://www.google.com/podcast.xml").unwrap();
+
+        assert!(matches!(
+            Subscription::new(feed(url.clone(), "/tmp/{episode}.mp3")),
+            Err(FeedError::Invalid(_))
+        ));
+        assert!(matches!(
+            Subscription::new(Feed {
+                include: Some("(".to_string()),
+                ..feed(url.clone(), "/tmp/{title}")
+            }),
+            Err(FeedError::Invalid(_))
+        ));
+
+        let subscription = Subscription::new(feed(url, "/tmp/{feed}/{date} {title}.mp3")).unwrap();
+        let item = FeedItem {
+            title: Some("../../etc/passwd".to_string()),
+            published: NaiveDate::from_ymd_opt(2022, 7, 5),
+            ..FeedItem::default()
+        };
+        let request = subscription.request_of(
+            Some("Sulfur Weekly"),
+            &item,
+            Url::parse("https://www.google.com/2.mp3").unwrap(),
+        );
+
+        assert_eq!(
+            request.path,
+            PathBuf::from("/tmp/Sulfur Weekly/2022-07-05 .._.._etc_passwd.mp3")
+        );
+    }
+
+    #[tokio::test]
+    async fn only_new_items_are_handed_out() -> color_eyre::Result<()> {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let response = match request.uri().path() {
+                    "/podcast.xml" => Response::new(Body::from(RSS)),
+                    _ => Response::builder()
+                        .status(StatusCode::MOVED_PERMANENTLY)
+                        .header(header::LOCATION, "/podcast.xml")
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+
+                Ok::<_, Infallible>(response)
+            }))
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let podcast = Feed {
+            exclude: Some("(?i)trailer".to_string()),
+            ..feed(
+                Url::parse(&format!("http://{}/moved", address))?,
+                "/tmp/{file_name}",
+            )
+        };
+
+        let mut source =
+            FeedHttpRequestSource::new(vec![podcast.clone()], store.clone(), HttpConnector::new())?;
+        let first = source.get_request().await?;
+        let second = source.get_request().await?;
+        // the oldest first, and the relative enclosure is relative to where the feed really is
+        assert_eq!(first.path, PathBuf::from("/tmp/1.mp3"));
+        assert_eq!(
+            second.url,
+            Url::parse(&format!("http://{}/episodes/2.mp3", address))?
+        );
+        assert!(source.found.is_empty());
+
+        // the store remembers, so starting over finds nothing new
+        let mut again = FeedHttpRequestSource::new(vec![podcast], store, HttpConnector::new())?;
+        again.poll().await;
+        assert!(again.found.is_empty());
+
+        server.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_feed_that_never_answers_doesnt_hold_up_the_others() -> color_eyre::Result<()> {
+        // takes the connection and never says a word
+        let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let stalled_address = stalled.local_addr()?;
+        let stalling = tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((connection, _)) = stalled.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::from(RSS)))
+            }))
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let feeds = vec![
+            feed(
+                Url::parse(&format!("http://{}/stalled.xml", stalled_address))?,
+                "/tmp/{file_name}",
+            ),
+            feed(
+                Url::parse(&format!("http://{}/podcast.xml", address))?,
+                "/tmp/{file_name}",
+            ),
+        ];
+        let mut source =
+            FeedHttpRequestSource::new(feeds, Arc::new(MemoryStore::new()), HttpConnector::new())?
+                .with_fetch_timeout(Duration::from_millis(100));
+
+        tokio::time::timeout(Duration::from_secs(5), source.poll()).await?;
+        assert!(!source.found.is_empty());
+
+        server.abort();
+        stalling.abort();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn feeds_are_only_read_up_to_the_limit() {
+        assert_eq!(
+            read_limited(Body::from("<rss/>"), 6).await,
+            Ok(b"<rss/>".to_vec())
+        );
+        assert!(read_limited(Body::from("<rss></rss>"), 6).await.is_err());
+    }
+}
@@ -5,11 +5,45 @@ use async_trait::async_trait;
 use tokio::sync::mpsc::Receiver;
 use url::Url;
 use std::path::PathBuf;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use chrono::NaiveTime;
+use crate::schedule::TimeWindow;
+
+/// The last part of the url's path, e.g. `ubuntu.iso` for `https://example.com/isos/ubuntu.iso`
+pub(crate) fn file_name_of(url: &Url) -> Option<String> {
+    url.path_segments()?
+        .rfind(|segment| !segment.is_empty())
+        .map(str::to_string)
+}
 
 #[derive(Debug)]
 pub struct HttpRequest {
     pub url: Url,
     pub path: PathBuf,
+    pub options: DownloadOptions,
+    /// Where the request came from, e.g. `api` or the folder it was dropped into
+    pub origin: Option<String>,
+}
+
+/// The knobs of a single download, persisted alongside it so a resumed download behaves the same
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadOptions {
+    /// How many connections the download is split over, one per CPU core if not given
+    pub connections: Option<u32>,
+    /// The part of the day the download may run in, any time if not given
+    pub window: Option<TimeWindow>,
+    /// Sent along with every request of the download, e.g. cookies or authorization
+    pub headers: BTreeMap<String, String>,
+    /// The hex encoded sha-256 the finished file must have
+    pub checksum: Option<String>,
+}
+
+impl DownloadOptions {
+    pub fn may_run_at(&self, time: NaiveTime) -> bool {
+        self.window.map_or(true, |window| window.contains(time))
+    }
 }
 
 
@@ -17,6 +51,8 @@ pub struct HttpRequest {
 pub trait HttpRequestSource: Debug {
     type Error: Debug;
 
+    /// The next request, waiting for one if need be. An error means no request is ever coming
+    /// again, a source deals with what may go better next time itself.
     async fn get_request(&mut self) -> Result<HttpRequest, Self::Error>;
 }
 
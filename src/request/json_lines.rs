@@ -0,0 +1,306 @@
+use super::http::{DownloadOptions, HttpRequest, HttpRequestSource};
+use async_trait::async_trait;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{Debug, Display, Formatter},
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Lines},
+};
+use tracing::{event, Level};
+use url::Url;
+
+/// The extension of the file remembering which lines of a request file were already handed out
+pub const JOURNAL_EXTENSION: &str = "sulfur-done";
+
+/// A line of a request file, e.g.
+/// `{"url": "https://example.com/a.iso", "path": "/tmp/a.iso", "headers": {"Cookie": "a=b"}}`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RequestLine {
+    url: Url,
+    path: PathBuf,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    checksum: Option<String>,
+    #[serde(default)]
+    options: DownloadOptions,
+}
+
+impl From<RequestLine> for HttpRequest {
+    fn from(line: RequestLine) -> Self {
+        let mut options = line.options;
+        options.headers.extend(line.headers);
+        options.checksum = line.checksum.or(options.checksum);
+
+        HttpRequest {
+            url: line.url,
+            path: line.path,
+            options,
+            origin: None,
+        }
+    }
+}
+
+/// A line of a request file that isn't a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineError {
+    /// Counting from 1, like editors do
+    pub line: usize,
+    pub reason: String,
+}
+
+impl Display for LineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+#[derive(Debug)]
+pub enum JsonLinesError {
+    Io(io::Error),
+    /// Every line has been read
+    Exhausted,
+}
+
+impl Display for JsonLinesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonLinesError::Io(e) => write!(f, "Failed to read the requests: {}", e),
+            JsonLinesError::Exhausted => write!(f, "No requests left"),
+        }
+    }
+}
+
+impl std::error::Error for JsonLinesError {}
+
+impl From<io::Error> for JsonLinesError {
+    fn from(e: io::Error) -> Self {
+        JsonLinesError::Io(e)
+    }
+}
+
+/// Reads one request per line of JSON, from a file or stdin. Blank lines are skipped and so are
+/// lines that aren't a request, those are logged with their line number and kept in
+/// `line_errors`.
+///
+/// With a journal, the source remembers every line it handed out and skips them the next time the
+/// same file is read, so a file can be fed in again after adding to it. A line counts as handed out
+/// as soon as `get_request` returns it.
+#[derive(Debug)]
+pub struct JsonLinesHttpRequestSource<R> {
+    lines: Lines<BufReader<R>>,
+    line_number: usize,
+    journal: Option<PathBuf>,
+    /// The sha-256 of every line handed out so far, the line itself may hold credentials
+    done: HashSet<String>,
+    line_errors: Vec<LineError>,
+}
+
+impl JsonLinesHttpRequestSource<File> {
+    /// Read the requests in `path`, journaling them next to it
+    pub async fn open(path: &Path) -> Result<Self, JsonLinesError> {
+        let file = File::open(path).await?;
+
+        Self::with_journal(file, journal_path(path)).await
+    }
+}
+
+impl JsonLinesHttpRequestSource<tokio::io::Stdin> {
+    /// Read the requests from stdin, journaling them in `journal` if given
+    pub async fn stdin(journal: Option<PathBuf>) -> Result<Self, JsonLinesError> {
+        match journal {
+            Some(journal) => Self::with_journal(tokio::io::stdin(), journal).await,
+            None => Ok(Self::new(tokio::io::stdin())),
+        }
+    }
+}
+
+impl<R> JsonLinesHttpRequestSource<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Read the requests from `reader`, without remembering them
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: BufReader::new(reader).lines(),
+            line_number: 0,
+            journal: None,
+            done: HashSet::new(),
+            line_errors: vec![],
+        }
+    }
+
+    /// Read the requests from `reader`, skipping the ones already in `journal` and adding the rest
+    pub async fn with_journal(reader: R, journal: PathBuf) -> Result<Self, JsonLinesError> {
+        let done = match tokio::fs::read_to_string(&journal).await {
+            Ok(text) => text.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            journal: Some(journal),
+            done,
+            ..Self::new(reader)
+        })
+    }
+
+    /// The lines skipped so far for not being a request
+    pub fn line_errors(&self) -> &[LineError] {
+        &self.line_errors
+    }
+
+    async fn mark_done(&mut self, key: String) -> Result<(), JsonLinesError> {
+        if let Some(journal) = &self.journal {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(journal)
+                .await?;
+            file.write_all(format!("{}\n", key).as_bytes()).await?;
+            file.sync_data().await?;
+        }
+
+        self.done.insert(key);
+
+        Ok(())
+    }
+}
+
+/// Where the journal of the request file at `path` goes, e.g. `requests.jsonl` is journaled in
+/// `requests.jsonl.sulfur-done`
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(JOURNAL_EXTENSION);
+
+    path.with_file_name(file_name)
+}
+
+fn line_key(line: &str) -> String {
+    hex::encode(Sha256::digest(line.as_bytes()))
+}
+
+#[async_trait]
+impl<R> HttpRequestSource for JsonLinesHttpRequestSource<R>
+where
+    R: AsyncRead + Unpin + Debug + Send + Sync,
+{
+    type Error = JsonLinesError;
+
+    async fn get_request(&mut self) -> Result<HttpRequest, JsonLinesError> {
+        while let Some(line) = self.lines.next_line().await? {
+            self.line_number += 1;
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let key = line_key(line);
+            if self.done.contains(&key) {
+                continue;
+            }
+
+            match serde_json::from_str::<RequestLine>(line) {
+                Ok(request) => {
+                    self.mark_done(key).await?;
+                    return Ok(request.into());
+                }
+                Err(e) => {
+                    let error = LineError {
+                        line: self.line_number,
+                        reason: e.to_string(),
+                    };
+                    event!(Level::WARN, "Skipping a request: {}", error);
+                    self.line_errors.push(error);
+                }
+            }
+        }
+
+        Err(JsonLinesError::Exhausted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const REQUESTS: &str = r#"{"url": "https://www.google.com/a", "path": "/tmp/a"}
+
+{"url": "not a url", "path": "/tmp/b"}
+{"url": "https://www.google.com/c", "path": "/tmp/c", "headers": {"Cookie": "a=b"}, "checksum": "abc", "options": {"connections": 2}}
+"#;
+
+    async fn drain<R>(source: &mut JsonLinesHttpRequestSource<R>) -> Vec<HttpRequest>
+    where
+        R: AsyncRead + Unpin + Debug + Send + Sync,
+    {
+        let mut requests = vec![];
+        while let Ok(request) = source.get_request().await {
+            requests.push(request);
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn bad_lines_are_skipped_with_their_line_number() {
+        let mut source = JsonLinesHttpRequestSource::new(REQUESTS.as_bytes());
+
+        let requests = drain(&mut source).await;
+
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, PathBuf::from("/tmp/a"));
+        assert_eq!(requests[1].options.connections, Some(2));
+        assert_eq!(requests[1].options.checksum.as_deref(), Some("abc"));
+        assert_eq!(
+            requests[1]
+                .options
+                .headers
+                .get("Cookie")
+                .map(String::as_str),
+            Some("a=b")
+        );
+
+        assert_eq!(source.line_errors().len(), 1);
+        assert_eq!(source.line_errors()[0].line, 3);
+        assert!(matches!(
+            source.get_request().await,
+            Err(JsonLinesError::Exhausted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn journaled_lines_are_not_handed_out_again() -> color_eyre::Result<()> {
+        let path = std::env::temp_dir().join("sulfur-json-lines-test.jsonl");
+        let journal = journal_path(&path);
+        let _ = tokio::fs::remove_file(&journal).await;
+        tokio::fs::write(&path, REQUESTS).await?;
+
+        let mut first = JsonLinesHttpRequestSource::open(&path).await?;
+        assert_eq!(drain(&mut first).await.len(), 2);
+
+        // only what was added since is new
+        let mut file = OpenOptions::new().append(true).open(&path).await?;
+        file.write_all(br#"{"url": "https://www.google.com/d", "path": "/tmp/d"}"#)
+            .await?;
+        drop(file);
+
+        let mut second = JsonLinesHttpRequestSource::open(&path).await?;
+        let requests = drain(&mut second).await;
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, PathBuf::from("/tmp/d"));
+
+        tokio::fs::remove_file(&path).await?;
+        tokio::fs::remove_file(&journal).await?;
+
+        Ok(())
+    }
+}
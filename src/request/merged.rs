@@ -0,0 +1,220 @@
+use super::http::{HttpRequest, HttpRequestSource};
+use async_trait::async_trait;
+use futures::future::poll_fn;
+use std::{convert::Infallible, task::Poll};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{event, Level};
+
+/// A source added to a `MergedHttpRequestSource`, running in a task of its own
+#[derive(Debug)]
+struct RunningSource {
+    origin: String,
+    requests: mpsc::Receiver<HttpRequest>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for RunningSource {
+    fn drop(&mut self) {
+        // it may be waiting on a request that is never coming
+        self.task.abort();
+    }
+}
+
+/// Takes requests from any number of sources at once, each one tagged with the origin its source
+/// was added with unless it already has one. Sources can be added at any time through
+/// `RequestSources`. A source only fails once it can't ever hand out another request, so one
+/// that does is dropped and the others carry on. With no sources left it waits for the next one to
+/// be added.
+///
+/// The sources take turns, so a busy source can't starve the others. Each one holds on to at most
+/// one request until it is taken.
+#[derive(Debug)]
+pub struct MergedHttpRequestSource {
+    sources: Vec<RunningSource>,
+    /// Where to look first the next time, the one after the last source a request was taken from
+    next: usize,
+    added: mpsc::UnboundedReceiver<RunningSource>,
+    handle: RequestSources,
+}
+
+/// Adds sources to a `MergedHttpRequestSource`
+#[derive(Debug, Clone)]
+pub struct RequestSources {
+    added: mpsc::UnboundedSender<RunningSource>,
+}
+
+impl MergedHttpRequestSource {
+    pub fn new() -> Self {
+        let (sender, added) = mpsc::unbounded_channel();
+
+        Self {
+            sources: vec![],
+            next: 0,
+            added,
+            handle: RequestSources { added: sender },
+        }
+    }
+
+    pub fn sources(&self) -> RequestSources {
+        self.handle.clone()
+    }
+}
+
+impl Default for MergedHttpRequestSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestSources {
+    /// Start taking requests from `source`, tagging them with `origin`
+    pub fn add<S>(&self, origin: impl Into<String>, mut source: S)
+    where
+        S: HttpRequestSource + Send + 'static,
+    {
+        let origin = origin.into();
+        let (sender, requests) = mpsc::channel(1);
+
+        let task_origin = origin.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let mut request = match source.get_request().await {
+                    Ok(request) => request,
+                    Err(e) => {
+                        event!(
+                            Level::ERROR,
+                            "Request source {} failed, no more requests are taken from it: {:?}",
+                            task_origin,
+                            e
+                        );
+                        return;
+                    }
+                };
+                request.origin.get_or_insert_with(|| task_origin.clone());
+
+                // nobody takes requests anymore
+                if sender.send(request).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        // if the merged source is gone this drops the source right away, which stops the task
+        let _ = self.added.send(RunningSource {
+            origin,
+            requests,
+            task,
+        });
+    }
+}
+
+#[async_trait]
+impl HttpRequestSource for MergedHttpRequestSource {
+    type Error = Infallible;
+
+    async fn get_request(&mut self) -> Result<HttpRequest, Infallible> {
+        let request = poll_fn(|cx| {
+            // never closes, `handle` is still around
+            while let Poll::Ready(Some(source)) = self.added.poll_recv(cx) {
+                self.sources.push(source);
+            }
+
+            let count = self.sources.len();
+            let mut done = vec![];
+            let mut taken = None;
+            for i in 0..count {
+                let index = (self.next + i) % count;
+                match self.sources[index].requests.poll_recv(cx) {
+                    Poll::Ready(Some(request)) => {
+                        taken = Some((index, request));
+                        break;
+                    }
+                    Poll::Ready(None) => done.push(index),
+                    Poll::Pending => {}
+                }
+            }
+
+            if let Some((index, _)) = taken {
+                self.next = index + 1;
+            }
+            done.sort_unstable();
+            for index in done.into_iter().rev() {
+                let source = self.sources.remove(index);
+                event!(Level::DEBUG, "Dropped request source {}", source.origin);
+                if index < self.next {
+                    self.next -= 1;
+                }
+            }
+
+            match taken {
+                Some((_, request)) => Poll::Ready(request),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::http::{ChannelHttpRequestSource, DownloadOptions};
+    use pretty_assertions::assert_eq;
+    use url::Url;
+
+    async fn source_of(name: &str, count: usize) -> ChannelHttpRequestSource {
+        let (sender, receiver) = mpsc::channel(count);
+        for i in 0..count {
+            sender
+                .send(HttpRequest {
+                    url: Url::parse(&format!("https://www.google.com/{}/{}", name, i)).unwrap(),
+                    path: format!("/tmp/{}-{}", name, i).into(),
+                    options: DownloadOptions::default(),
+                    origin: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        ChannelHttpRequestSource::new(receiver)
+    }
+
+    #[tokio::test]
+    async fn sources_take_turns() -> color_eyre::Result<()> {
+        let mut merged = MergedHttpRequestSource::new();
+        merged.sources().add("api", source_of("api", 3).await);
+        merged.sources().add("folder", source_of("folder", 3).await);
+
+        let mut origins = vec![];
+        for _ in 0..6 {
+            origins.push(merged.get_request().await.unwrap().origin.unwrap());
+        }
+
+        assert_eq!(
+            origins,
+            vec!["api", "folder", "api", "folder", "api", "folder"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sources_can_be_added_later() -> color_eyre::Result<()> {
+        let mut merged = MergedHttpRequestSource::new();
+        let sources = merged.sources();
+
+        // the first source is done before the second comes along
+        sources.add("first", source_of("first", 1).await);
+        let request = merged.get_request().await.unwrap();
+        assert_eq!(request.origin.as_deref(), Some("first"));
+
+        sources.add("second", source_of("second", 1).await);
+        let request = merged.get_request().await.unwrap();
+        assert_eq!(request.origin.as_deref(), Some("second"));
+        assert_eq!(request.path, std::path::PathBuf::from("/tmp/second-0"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,545 @@
+use super::{
+    feed::{send_following_redirects, DEFAULT_FETCH_TIMEOUT},
+    http::{DownloadOptions, HttpRequest, HttpRequestSource},
+};
+use crate::store::{unix_now, DownloadRecord, DownloadState, SharedDownloadStore, StoreError};
+use async_trait::async_trait;
+use hyper::{
+    client::connect::Connect,
+    header::{self, HeaderMap},
+    Body, Client, Request, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    fmt::Debug,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{event, Level};
+use url::Url;
+
+/// Seconds between two checks of a monitored url unless told otherwise
+pub const DEFAULT_CHECK_INTERVAL: u64 = 60 * 60;
+
+fn default_check_interval() -> u64 {
+    DEFAULT_CHECK_INTERVAL
+}
+
+/// A url that changes in place, e.g. the latest nightly build, downloaded again whenever it does
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonitoredUrl {
+    pub url: Url,
+    pub path: PathBuf,
+    /// Seconds between two checks
+    #[serde(default = "default_check_interval")]
+    pub interval: u64,
+    /// How many previous versions are kept next to the latest one, the most recent as `<path>.1`
+    /// and so on. Without any the new version replaces the old one.
+    #[serde(default)]
+    pub keep: usize,
+    /// The options of every download of this url
+    #[serde(default)]
+    pub options: DownloadOptions,
+}
+
+/// What the server says about the version of a url it has, enough to tell whether it changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UrlVersion {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub length: Option<u64>,
+}
+
+impl UrlVersion {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let text = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Self {
+            etag: text(header::ETAG),
+            last_modified: text(header::LAST_MODIFIED),
+            length: text(header::CONTENT_LENGTH).and_then(|length| length.parse().ok()),
+        }
+    }
+}
+
+/// Ask the server whether `url` changed since `known`, with a conditional request. Returns the
+/// new version if it did.
+///
+/// Servers that ignore the conditions are caught by comparing the version they answer with, a
+/// url the server says nothing at all about is only downloaded once.
+async fn check<C>(
+    client: &Client<C>,
+    url: &Url,
+    known: Option<&UrlVersion>,
+) -> Result<Option<UrlVersion>, String>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let (_, response) = send_following_redirects(client, url, |url| {
+        let mut request = Request::head(url.as_str());
+        if let Some(etag) = known.and_then(|known| known.etag.as_deref()) {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = known.and_then(|known| known.last_modified.as_deref()) {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+
+        request.body(Body::empty())
+    })
+    .await?;
+
+    match response.status() {
+        StatusCode::NOT_MODIFIED => Ok(None),
+        status if status.is_success() => {
+            let version = UrlVersion::from_headers(response.headers());
+
+            Ok(known
+                .map_or(true, |known| *known != version)
+                .then_some(version))
+        }
+        status => Err(format!("the server answered {}", status)),
+    }
+}
+
+/// Where the `n`th previous version of the file at `path` is kept, e.g. `nightly.tar.gz.1`
+fn version_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+
+    PathBuf::from(name)
+}
+
+/// Whether the two paths are the same file
+#[cfg(unix)]
+async fn same_file(first: &Path, second: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (
+        tokio::fs::metadata(first).await,
+        tokio::fs::metadata(second).await,
+    ) {
+        (Ok(first), Ok(second)) => first.dev() == second.dev() && first.ino() == second.ino(),
+        _ => false,
+    }
+}
+
+/// Whether the two paths are the same file, which can't be told here so they never are
+#[cfg(not(unix))]
+async fn same_file(_first: &Path, _second: &Path) -> bool {
+    false
+}
+
+/// Make room for a new version of the file at `path`, moving the previous versions up by one and
+/// dropping the ones past `keep`. The current version is linked rather than moved, so it stays at
+/// `path` until the new one replaces it. Rotating again before that does nothing.
+async fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let latest = version_path(path, 1);
+    if keep == 0 || !path.exists() || same_file(path, &latest).await {
+        return Ok(());
+    }
+
+    let ignore_missing = |result: io::Result<()>| match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    };
+
+    ignore_missing(tokio::fs::remove_file(version_path(path, keep)).await)?;
+    for n in (1..keep).rev() {
+        ignore_missing(tokio::fs::rename(version_path(path, n), version_path(path, n + 1)).await)?;
+    }
+    // some filesystems can't link, a copy does too
+    if tokio::fs::hard_link(path, &latest).await.is_err() {
+        tokio::fs::copy(path, &latest).await?;
+    }
+
+    Ok(())
+}
+
+/// A version that was handed out and isn't downloaded yet
+#[derive(Debug)]
+struct Pending {
+    version: UrlVersion,
+    /// When it was handed out, the download of it was created after
+    since: u64,
+    /// Whether its download was seen in the store
+    queued: bool,
+}
+
+#[derive(Debug)]
+struct Monitor {
+    url: MonitoredUrl,
+    next_check: Instant,
+    pending: Option<Pending>,
+}
+
+/// A change found by a check, waiting to be handed out
+#[derive(Debug)]
+struct Change {
+    /// Of the monitor that found it
+    index: usize,
+    version: UrlVersion,
+}
+
+/// Checks a set of urls every so often and hands out a request for each one whenever it changes.
+/// Once the download of a version completes the version is remembered in the store, so a restart
+/// doesn't download it again. A download that doesn't complete is tried again with the next
+/// check.
+///
+/// The previous versions to keep are linked aside when the request is handed out, the latest one
+/// stays at the path until its download replaces it.
+#[derive(Debug)]
+pub struct MonitorHttpRequestSource<C> {
+    monitors: Vec<Monitor>,
+    client: Client<C>,
+    store: SharedDownloadStore,
+    changes: VecDeque<Change>,
+}
+
+impl<C> MonitorHttpRequestSource<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    pub fn new(urls: Vec<MonitoredUrl>, store: SharedDownloadStore, connector: C) -> Self {
+        let now = Instant::now();
+
+        Self {
+            monitors: urls
+                .into_iter()
+                .map(|url| Monitor {
+                    url,
+                    next_check: now,
+                    pending: None,
+                })
+                .collect(),
+            client: Client::builder().build(connector),
+            store,
+            changes: VecDeque::new(),
+        }
+    }
+
+    /// The most recent download of the monitored url, if it was ever downloaded
+    async fn latest_download(
+        store: &SharedDownloadStore,
+        url: &MonitoredUrl,
+    ) -> Result<Option<DownloadRecord>, StoreError> {
+        Ok(store
+            .downloads()
+            .await?
+            .into_iter()
+            .filter(|record| record.url == url.url && record.file_path == url.path)
+            .max_by_key(|record| record.id))
+    }
+
+    /// Check every url whose time has come, lining up the ones that changed
+    async fn check_due(&mut self) {
+        let now = Instant::now();
+
+        for (index, monitor) in self.monitors.iter_mut().enumerate() {
+            if monitor.next_check > now {
+                continue;
+            }
+            monitor.next_check = now + Duration::from_secs(monitor.url.interval);
+
+            let url = &monitor.url.url;
+            // it is checked again next time
+            let (known, latest) = match (
+                self.store.url_version(url).await,
+                Self::latest_download(&self.store, &monitor.url).await,
+            ) {
+                (Ok(known), Ok(latest)) => (known, latest),
+                (Err(e), _) | (_, Err(e)) => {
+                    event!(Level::ERROR, "Failed to look up {}: {}", url, e);
+                    continue;
+                }
+            };
+
+            if let Some(pending) = &mut monitor.pending {
+                match latest
+                    .as_ref()
+                    .filter(|latest| latest.created_at >= pending.since)
+                {
+                    Some(latest) if latest.state == DownloadState::Completed => {
+                        if let Err(e) = self.store.set_url_version(url, &pending.version).await {
+                            event!(
+                                Level::ERROR,
+                                "Failed to remember the version of {}: {}",
+                                url,
+                                e
+                            );
+                        } else {
+                            monitor.pending = None;
+                        }
+                        continue;
+                    }
+                    // see whether it is still the version to get
+                    Some(latest) if latest.state.is_finished() => {
+                        event!(Level::WARN, "The download of {} didn't complete", url);
+                        monitor.pending = None;
+                    }
+                    Some(_) => {
+                        pending.queued = true;
+                        continue;
+                    }
+                    // it was removed while it was going
+                    None if pending.queued => monitor.pending = None,
+                    // not queued yet
+                    None => continue,
+                }
+            }
+
+            let check = tokio::time::timeout(
+                DEFAULT_FETCH_TIMEOUT,
+                check(&self.client, url, known.as_ref()),
+            );
+            match check.await.unwrap_or_else(|_| Err("no answer".to_string())) {
+                // a download from before we started is still going, it gets this version
+                Ok(Some(version)) if latest.as_ref().is_some_and(|l| !l.state.is_finished()) => {
+                    monitor.pending = latest.map(|latest| Pending {
+                        version,
+                        since: latest.created_at,
+                        queued: true,
+                    });
+                }
+                Ok(Some(version)) => {
+                    event!(Level::INFO, "{} changed", url);
+                    self.changes.push_back(Change { index, version });
+                }
+                Ok(None) => event!(Level::DEBUG, "{} didn't change", url),
+                Err(e) => event!(Level::WARN, "Failed to check {}: {}", url, e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<C> HttpRequestSource for MonitorHttpRequestSource<C>
+where
+    C: Connect + Clone + Send + Sync + Debug + 'static,
+{
+    type Error = Infallible;
+
+    async fn get_request(&mut self) -> Result<HttpRequest, Infallible> {
+        loop {
+            if let Some(change) = self.changes.pop_front() {
+                let monitor = &mut self.monitors[change.index];
+                let url = &monitor.url;
+                // the next check finds the change again
+                if let Err(e) = rotate(&url.path, url.keep).await {
+                    event!(
+                        Level::ERROR,
+                        "Failed to keep the previous version of {}: {}",
+                        url.url,
+                        e
+                    );
+                    continue;
+                }
+                monitor.pending = Some(Pending {
+                    version: change.version,
+                    since: unix_now(),
+                    queued: false,
+                });
+
+                return Ok(HttpRequest {
+                    url: url.url.clone(),
+                    path: url.path.clone(),
+                    options: url.options.clone(),
+                    origin: None,
+                });
+            }
+
+            match self.monitors.iter().map(|monitor| monitor.next_check).min() {
+                Some(next_check) => tokio::time::sleep_until(next_check).await,
+                // nothing to check, ever
+                None => futures::future::pending::<()>().await,
+            }
+            self.check_due().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use hyper::{
+        client::HttpConnector,
+        service::{make_service_fn, service_fn},
+        Response, Server,
+    };
+    use pretty_assertions::assert_eq;
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    #[tokio::test]
+    async fn previous_versions_are_kept_up_to_keep() -> color_eyre::Result<()> {
+        let directory = std::env::temp_dir().join("sulfur-monitor-rotate-test");
+        let _ = tokio::fs::remove_dir_all(&directory).await;
+        tokio::fs::create_dir_all(&directory).await?;
+        let path = directory.join("nightly.tar.gz");
+
+        // every download writes its part file and renames it over the path
+        let part = directory.join("nightly.tar.gz.part");
+        let download = |version: &'static str| {
+            let (part, path) = (part.clone(), path.clone());
+            async move {
+                tokio::fs::write(&part, version).await?;
+                tokio::fs::rename(&part, &path).await
+            }
+        };
+        for version in ["1", "2", "3", "4"] {
+            rotate(&path, 2).await?;
+            // the latest version stays in place until it is replaced
+            assert!(path.exists() || version == "1");
+            download(version).await?;
+        }
+        assert_eq!(tokio::fs::read_to_string(&path).await?, "4");
+        assert_eq!(
+            tokio::fs::read_to_string(version_path(&path, 1)).await?,
+            "3"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(version_path(&path, 2)).await?,
+            "2"
+        );
+
+        // handing out the next version again, after its first download failed, only rotates once
+        rotate(&path, 2).await?;
+        rotate(&path, 2).await?;
+
+        assert_eq!(tokio::fs::read_to_string(&path).await?, "4");
+        assert_eq!(
+            tokio::fs::read_to_string(version_path(&path, 1)).await?,
+            "4"
+        );
+        assert_eq!(
+            tokio::fs::read_to_string(version_path(&path, 2)).await?,
+            "3"
+        );
+        assert!(!version_path(&path, 3).exists());
+
+        // without keeping anything the file is left to be replaced
+        rotate(&path, 0).await?;
+        assert!(path.exists());
+
+        tokio::fs::remove_dir_all(&directory).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn urls_are_only_handed_out_again_once_they_change() -> color_eyre::Result<()> {
+        // a server whose file changes whenever `version` does, and that knows what its clients have
+        let version = Arc::new(AtomicUsize::new(1));
+        let served = version.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let served = served.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let etag = format!("\"{}\"", served.load(Ordering::SeqCst));
+                    let unchanged = request
+                        .headers()
+                        .get(header::IF_NONE_MATCH)
+                        .is_some_and(|known| known.as_bytes() == etag.as_bytes());
+                    let status = match unchanged {
+                        true => StatusCode::NOT_MODIFIED,
+                        false => StatusCode::OK,
+                    };
+
+                    async move {
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .header(header::ETAG, etag)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        }));
+        let address = server.local_addr();
+        let server = tokio::spawn(server);
+
+        let store: SharedDownloadStore = Arc::new(MemoryStore::new());
+        let nightly = MonitoredUrl {
+            url: Url::parse(&format!("http://{}/nightly.tar.gz", address))?,
+            path: "/tmp/sulfur-monitor-nightly.tar.gz".into(),
+            interval: 0,
+            keep: 0,
+            options: DownloadOptions::default(),
+        };
+        let mut source = MonitorHttpRequestSource::new(
+            vec![nightly.clone()],
+            store.clone(),
+            HttpConnector::new(),
+        );
+
+        // what the downloader does with the requests it gets
+        let download = |state| {
+            let store = store.clone();
+            let nightly = nightly.clone();
+            async move {
+                let record = DownloadRecord::new(
+                    nightly.url,
+                    nightly.path,
+                    state,
+                    DownloadOptions::default(),
+                );
+                store.create_download(&record, &[]).await.map(|_| ())
+            }
+        };
+        let etag = || async {
+            store
+                .url_version(&nightly.url)
+                .await
+                .map(|version| version.and_then(|version| version.etag))
+        };
+
+        let request = source.get_request().await?;
+        assert_eq!(request.url, nightly.url);
+        assert_eq!(request.path, nightly.path);
+
+        // nothing is handed out while the download is going, and a failed one is tried again
+        source.check_due().await;
+        assert!(source.changes.is_empty());
+        download(DownloadState::Failed).await?;
+        source.check_due().await;
+        assert_eq!(source.changes.len(), 1);
+        assert_eq!(etag().await?, None);
+
+        source.get_request().await?;
+        download(DownloadState::Completed).await?;
+        source.check_due().await;
+        assert_eq!(etag().await?.as_deref(), Some("\"1\""));
+        source.check_due().await;
+        assert!(source.changes.is_empty());
+
+        version.store(2, Ordering::SeqCst);
+        source.check_due().await;
+        assert_eq!(source.changes.len(), 1);
+        source.get_request().await?;
+        // only once it is downloaded
+        assert_eq!(etag().await?.as_deref(), Some("\"1\""));
+        download(DownloadState::Completed).await?;
+        source.check_due().await;
+        assert_eq!(etag().await?.as_deref(), Some("\"2\""));
+
+        server.abort();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+use super::http::{file_name_of, DownloadOptions, HttpRequest, HttpRequestSource};
+use async_trait::async_trait;
+use futures::StreamExt;
+use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask};
+use quick_xml::{events::Event, Reader};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Display, Formatter},
+    io,
+    path::{Path, PathBuf},
+};
+use tracing::{event, Level};
+use url::Url;
+
+/// Where picked up files are moved to, inside the watched folder
+pub const DONE_FOLDER: &str = "done";
+/// Where files we couldn't make sense of are moved to, along with a `.error` file saying why
+pub const FAILED_FOLDER: &str = "failed";
+
+/// A folder people drop files requesting downloads into
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchFolder {
+    pub path: PathBuf,
+    /// Where the downloads requested in this folder go
+    pub destination: PathBuf,
+    /// The options of every download requested in this folder
+    #[serde(default)]
+    pub options: DownloadOptions,
+}
+
+#[derive(Debug)]
+pub enum WatchFolderError {
+    Io(io::Error),
+    /// The watch went away, nothing else will be dropped
+    Closed,
+}
+
+impl Display for WatchFolderError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchFolderError::Io(e) => write!(f, "Failed to watch the folders: {}", e),
+            WatchFolderError::Closed => write!(f, "The folders aren't watched anymore"),
+        }
+    }
+}
+
+impl std::error::Error for WatchFolderError {}
+
+impl From<io::Error> for WatchFolderError {
+    fn from(e: io::Error) -> Self {
+        WatchFolderError::Io(e)
+    }
+}
+
+/// A download requested by a file, before we know where it goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Link {
+    url: Url,
+    /// The name the file should get, taken from the url if not given
+    name: Option<String>,
+    checksum: Option<String>,
+}
+
+impl Link {
+    fn new(url: Url) -> Self {
+        Self {
+            url,
+            name: None,
+            checksum: None,
+        }
+    }
+}
+
+/// The url of an internet shortcut, the `URL=` line of its `[InternetShortcut]` section
+fn parse_url_file(text: &str) -> Result<Vec<Link>, String> {
+    let mut in_shortcut = false;
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_shortcut = line.eq_ignore_ascii_case("[InternetShortcut]");
+        } else if let Some(url) = line.strip_prefix("URL=").filter(|_| in_shortcut) {
+            let url = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+            return Ok(vec![Link::new(url)]);
+        }
+    }
+
+    Err("no URL= line in an [InternetShortcut] section".to_string())
+}
+
+/// One url per line, blank lines and lines starting with `#` don't count
+fn parse_link_list(text: &str) -> Result<Vec<Link>, String> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            Url::parse(line)
+                .map(Link::new)
+                .map_err(|e| format!("line {}: invalid url {}: {}", index + 1, line, e))
+        })
+        .collect()
+}
+
+/// Every `<file>` of a metalink 4 document (RFC 5854), with its most preferred url and sha-256
+fn parse_metalink(text: &str) -> Result<Vec<Link>, String> {
+    let mut reader = Reader::from_str(text);
+    reader.trim_text(true);
+
+    let mut links = vec![];
+    let mut buffer = vec![];
+    // what we know about the <file> we are in so far: its name, its urls with their priority and
+    // its sha-256
+    let mut name = None;
+    let mut urls: Vec<(u32, String)> = vec![];
+    let mut checksum = None;
+    // the element whose text comes next
+    let mut inside: Option<(Vec<u8>, Option<u32>)> = None;
+
+    loop {
+        match reader.read_event(&mut buffer) {
+            Ok(Event::Start(element)) => {
+                let attribute = |key: &[u8]| -> Option<String> {
+                    element
+                        .attributes()
+                        .flatten()
+                        .find(|attribute| attribute.key == key)
+                        .and_then(|attribute| {
+                            attribute
+                                .unescaped_value()
+                                .ok()
+                                .map(|value| String::from_utf8_lossy(&value).into_owned())
+                        })
+                };
+
+                match element.local_name() {
+                    b"file" => {
+                        name = attribute(b"name");
+                        urls.clear();
+                        checksum = None;
+                    }
+                    b"url" => {
+                        // lower numbers are preferred, urls without a priority come last
+                        let priority = attribute(b"priority").and_then(|p| p.parse().ok());
+                        inside = Some((b"url".to_vec(), priority));
+                    }
+                    b"hash" if attribute(b"type").as_deref() == Some("sha-256") => {
+                        inside = Some((b"hash".to_vec(), None));
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Event::Text(text)) => {
+                let text = text
+                    .unescape_and_decode(&reader)
+                    .map_err(|e| e.to_string())?;
+
+                match inside.take() {
+                    Some((element, priority)) if element == b"url" => {
+                        urls.push((priority.unwrap_or(u32::MAX), text))
+                    }
+                    Some((element, _)) if element == b"hash" => checksum = Some(text),
+                    _ => {}
+                }
+            }
+            Ok(Event::End(element)) if element.local_name() == b"file" => {
+                let file_name = name.take().ok_or("a <file> without a name")?;
+                urls.sort_by_key(|(priority, _)| *priority);
+                let (_, url) = urls
+                    .first()
+                    .ok_or_else(|| format!("no <url> for {}", file_name))?;
+
+                links.push(Link {
+                    url: Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?,
+                    name: Some(file_name),
+                    checksum: checksum.take(),
+                });
+            }
+            Ok(Event::End(_)) => inside = None,
+            Ok(Event::Eof) => break,
+            Err(e) => {
+                return Err(format!(
+                    "invalid metalink at {}: {}",
+                    reader.buffer_position(),
+                    e
+                ))
+            }
+            _ => {}
+        }
+        buffer.clear();
+    }
+
+    Ok(links)
+}
+
+/// The links requested by the file at `path`, `None` if it isn't a file we pick up
+fn parse_file(path: &Path, text: &str) -> Option<Result<Vec<Link>, String>> {
+    let links = match path.extension()?.to_str()? {
+        "url" => parse_url_file(text),
+        "txt" => parse_link_list(text),
+        "meta4" => parse_metalink(text),
+        _ => return None,
+    };
+
+    Some(links.and_then(|links| match links.is_empty() {
+        true => Err("no links in the file".to_string()),
+        false => Ok(links),
+    }))
+}
+
+impl WatchFolder {
+    fn request_of(&self, link: Link) -> Result<HttpRequest, String> {
+        let name = link
+            .name
+            .or_else(|| file_name_of(&link.url))
+            .ok_or_else(|| format!("can't tell what to call the file of {}", link.url))?;
+        // a name with slashes in it could put the file anywhere
+        let name = Path::new(&name)
+            .file_name()
+            .ok_or_else(|| format!("{} isn't a file name", name))?;
+
+        let mut options = self.options.clone();
+        options.checksum = link.checksum.or(options.checksum);
+
+        Ok(HttpRequest {
+            url: link.url,
+            path: self.destination.join(name),
+            options,
+            origin: None,
+        })
+    }
+}
+
+/// Picks up the files dropped into a set of folders: `.url` internet shortcuts, `.txt` files with
+/// a url per line and `.meta4` metalinks. Each file is moved to the `done` folder once all of its
+/// requests were taken, or to the `failed` folder if it doesn't make sense. A file with the same
+/// name as one already there gets a number, e.g. `links.1.txt`.
+///
+/// Files that were dropped while we weren't watching are picked up first, so a file whose requests
+/// weren't all taken when we stopped is picked up again.
+#[derive(Debug)]
+pub struct WatchFolderHttpRequestSource {
+    folders: HashMap<WatchDescriptor, WatchFolder>,
+    events: EventStream<Vec<u8>>,
+    /// Files waiting to be picked up
+    dropped: VecDeque<(WatchFolder, PathBuf)>,
+    requests: VecDeque<HttpRequest>,
+    /// The file `requests` came from, it stays where it is until they were all taken
+    picked_up: Option<(WatchFolder, PathBuf)>,
+}
+
+impl WatchFolderHttpRequestSource {
+    pub fn new(folders: Vec<WatchFolder>) -> Result<Self, WatchFolderError> {
+        let inotify = Inotify::init()?;
+        let mut watched = HashMap::new();
+        let mut dropped = VecDeque::new();
+
+        for folder in folders {
+            std::fs::create_dir_all(folder.path.join(DONE_FOLDER))?;
+            std::fs::create_dir_all(folder.path.join(FAILED_FOLDER))?;
+
+            // only files that are complete, whether written in place or moved in
+            let descriptor = inotify
+                .watches()
+                .add(&folder.path, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+
+            // watching first, a file dropped in between is picked up twice rather than never
+            let mut existing = std::fs::read_dir(&folder.path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            existing.sort();
+            dropped.extend(existing.into_iter().map(|path| (folder.clone(), path)));
+
+            watched.insert(descriptor, folder);
+        }
+
+        Ok(Self {
+            folders: watched,
+            events: inotify.into_event_stream(vec![0; 4096])?,
+            dropped,
+            requests: VecDeque::new(),
+            picked_up: None,
+        })
+    }
+
+    /// Take the requests out of the file at `path`, if it is one of ours
+    async fn pick_up(&mut self, folder: &WatchFolder, path: &Path) -> io::Result<()> {
+        match path.file_name() {
+            Some(name) if !name.to_string_lossy().starts_with('.') && path.is_file() => {}
+            _ => return Ok(()),
+        }
+
+        let text = match tokio::fs::read_to_string(path).await {
+            Ok(text) => text,
+            // already picked up
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return self.give_up(folder, path, &e.to_string()).await,
+        };
+
+        let requests = match parse_file(path, &text) {
+            Some(links) => links.and_then(|links| {
+                links
+                    .into_iter()
+                    .map(|link| folder.request_of(link))
+                    .collect::<Result<Vec<_>, _>>()
+            }),
+            None => return Ok(()),
+        };
+
+        match requests {
+            Ok(requests) => {
+                event!(
+                    Level::INFO,
+                    "Picked up {} requests from {:?}",
+                    requests.len(),
+                    path
+                );
+                self.requests.extend(requests);
+                self.picked_up = Some((folder.clone(), path.to_path_buf()));
+
+                Ok(())
+            }
+            Err(reason) => self.give_up(folder, path, &reason).await,
+        }
+    }
+
+    /// Move the file at `path` to the failed folder along with why
+    async fn give_up(&self, folder: &WatchFolder, path: &Path, reason: &str) -> io::Result<()> {
+        event!(Level::WARN, "Can't pick up {:?}: {}", path, reason);
+
+        let failed = unused_path(&folder.path.join(FAILED_FOLDER), path);
+        tokio::fs::rename(path, &failed).await?;
+
+        let mut error_file = failed.into_os_string();
+        error_file.push(".error");
+        tokio::fs::write(error_file, reason).await
+    }
+}
+
+/// Where the file at `path` can go in `folder` without taking the place of another one
+fn unused_path(folder: &Path, path: &Path) -> PathBuf {
+    let name = Path::new(path.file_name().unwrap_or_default());
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    std::iter::once(folder.join(name))
+        .chain((1..).map(|n| folder.join(format!("{}.{}{}", stem, n, extension))))
+        .find(|candidate| !candidate.exists())
+        .expect("there's always a number left")
+}
+
+#[async_trait]
+impl HttpRequestSource for WatchFolderHttpRequestSource {
+    type Error = WatchFolderError;
+
+    async fn get_request(&mut self) -> Result<HttpRequest, WatchFolderError> {
+        loop {
+            if let Some(request) = self.requests.pop_front() {
+                return Ok(request);
+            }
+            // every request of the file was taken
+            if let Some((folder, path)) = self.picked_up.take() {
+                let done = unused_path(&folder.path.join(DONE_FOLDER), &path);
+                if let Err(e) = tokio::fs::rename(&path, &done).await {
+                    // it is picked up again with the next start, rather than never
+                    event!(
+                        Level::ERROR,
+                        "Failed to move {:?} to {:?}: {}",
+                        path,
+                        done,
+                        e
+                    );
+                }
+            }
+
+            // one file we can't pick up doesn't stop the others
+            if let Some((folder, path)) = self.dropped.pop_front() {
+                if let Err(e) = self.pick_up(&folder, &path).await {
+                    event!(Level::ERROR, "Failed to pick up {:?}: {}", path, e);
+                }
+                continue;
+            }
+
+            let event = self.events.next().await.ok_or(WatchFolderError::Closed)??;
+            if event.mask.contains(EventMask::ISDIR) {
+                continue;
+            }
+            if let (Some(folder), Some(name)) = (self.folders.get(&event.wd), event.name) {
+                self.dropped
+                    .push_back((folder.clone(), folder.path.join(name)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    #[test]
+    fn files_are_parsed_by_their_extension() {
+        let shortcut = "[InternetShortcut]\r\nURL=https://www.google.com/a.iso\r\n";
+        assert_eq!(
+            parse_file(Path::new("a.url"), shortcut),
+            Some(Ok(vec![Link::new(
+                Url::parse("https://www.google.com/a.iso").unwrap()
+            )]))
+        );
+
+        let list = "# isos\nhttps://www.google.com/a.iso\n\nhttps://www.google.com/b.iso\n";
+        assert_eq!(
+            parse_file(Path::new("a.txt"), list).unwrap().unwrap().len(),
+            2
+        );
+        assert!(matches!(
+            parse_file(Path::new("a.txt"), "https://www.google.com\nnope"),
+            Some(Err(reason)) if reason.starts_with("line 2")
+        ));
+        assert!(matches!(
+            parse_file(Path::new("a.txt"), "# nothing here"),
+            Some(Err(_))
+        ));
+
+        assert_eq!(parse_file(Path::new("a.pdf"), list), None);
+    }
+
+    #[test]
+    fn metalink_picks_the_preferred_url() {
+        let metalink = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+              <file name="ubuntu.iso">
+                <size>3654957056</size>
+                <hash type="sha-1">nope</hash>
+                <hash type="sha-256">abc</hash>
+                <url priority="2">https://mirror.example.com/ubuntu.iso</url>
+                <url priority="1">https://releases.ubuntu.com/ubuntu.iso</url>
+              </file>
+              <file name="../../etc/passwd">
+                <url>https://www.google.com/passwd</url>
+              </file>
+            </metalink>"#;
+
+        let links = parse_metalink(metalink).unwrap();
+        assert_eq!(
+            links[0],
+            Link {
+                url: Url::parse("https://releases.ubuntu.com/ubuntu.iso").unwrap(),
+                name: Some("ubuntu.iso".to_string()),
+                checksum: Some("abc".to_string()),
+            }
+        );
+
+        let folder = WatchFolder {
+            path: PathBuf::from("/tmp/watched"),
+            destination: PathBuf::from("/tmp/downloads"),
+            options: DownloadOptions::default(),
+        };
+        let request = folder.request_of(links[1].clone()).unwrap();
+        assert_eq!(request.path, PathBuf::from("/tmp/downloads/passwd"));
+    }
+
+    #[tokio::test]
+    async fn dropped_files_are_picked_up_and_moved() -> color_eyre::Result<()> {
+        let root = std::env::temp_dir().join("sulfur-watch-folder-test");
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        let watched = root.join("watched");
+        tokio::fs::create_dir_all(&watched).await?;
+
+        // dropped while nobody was watching
+        tokio::fs::write(
+            watched.join("early.txt"),
+            "https://www.google.com/early.iso",
+        )
+        .await?;
+
+        let mut source = WatchFolderHttpRequestSource::new(vec![WatchFolder {
+            path: watched.clone(),
+            destination: root.join("downloads"),
+            options: DownloadOptions::default(),
+        }])?;
+
+        let early = source.get_request().await?;
+        assert_eq!(early.path, root.join("downloads").join("early.iso"));
+        // not before its requests are safe with whoever took them
+        assert!(watched.join("early.txt").exists());
+
+        tokio::fs::write(watched.join("broken.url"), "URL=nothing").await?;
+        tokio::fs::write(
+            watched.join("late.url"),
+            "[InternetShortcut]\nURL=https://www.google.com/late.iso",
+        )
+        .await?;
+
+        let late = tokio::time::timeout(Duration::from_secs(5), source.get_request()).await??;
+        assert_eq!(late.url.as_str(), "https://www.google.com/late.iso");
+        assert!(watched.join(FAILED_FOLDER).join("broken.url").exists());
+        assert!(watched
+            .join(FAILED_FOLDER)
+            .join("broken.url.error")
+            .exists());
+        assert!(watched.join(DONE_FOLDER).join("early.txt").exists());
+
+        // the same name again doesn't replace the first one
+        tokio::fs::write(
+            watched.join("early.txt"),
+            "https://www.google.com/again.iso",
+        )
+        .await?;
+        let again = tokio::time::timeout(Duration::from_secs(5), source.get_request()).await??;
+        assert_eq!(again.url.as_str(), "https://www.google.com/again.iso");
+        tokio::fs::write(watched.join("last.txt"), "https://www.google.com/last.iso").await?;
+        tokio::time::timeout(Duration::from_secs(5), source.get_request()).await??;
+        assert_eq!(
+            tokio::fs::read_to_string(watched.join(DONE_FOLDER).join("early.1.txt")).await?,
+            "https://www.google.com/again.iso"
+        );
+
+        tokio::fs::remove_dir_all(&root).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_file_that_cant_be_moved_doesnt_stop_the_others() -> color_eyre::Result<()> {
+        let root = std::env::temp_dir().join("sulfur-watch-folder-failure-test");
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        let watched = root.join("watched");
+        tokio::fs::create_dir_all(&watched).await?;
+        tokio::fs::write(
+            watched.join("first.txt"),
+            "https://www.google.com/first.iso",
+        )
+        .await?;
+
+        let mut source = WatchFolderHttpRequestSource::new(vec![WatchFolder {
+            path: watched.clone(),
+            destination: root.join("downloads"),
+            options: DownloadOptions::default(),
+        }])?;
+        source.get_request().await?;
+
+        // nowhere to move the first file to
+        tokio::fs::remove_dir_all(watched.join(DONE_FOLDER)).await?;
+        tokio::fs::write(
+            watched.join("second.txt"),
+            "https://www.google.com/second.iso",
+        )
+        .await?;
+
+        let second = tokio::time::timeout(Duration::from_secs(5), source.get_request()).await??;
+        assert_eq!(second.url.as_str(), "https://www.google.com/second.iso");
+        assert!(watched.join("first.txt").exists());
+
+        tokio::fs::remove_dir_all(&root).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::{sync::Mutex, time::Instant};
+
+/// How often the downloader checks whether a window opened or closed, or the bandwidth limit
+/// changed
+pub const DEFAULT_SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The local time, which is what people mean when they say "overnight"
+pub fn local_now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+/// The part of the day a download is allowed to run in, e.g. 22:00 to 06:00. A window that ends
+/// before it starts runs past midnight, one that ends when it starts lasts all day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start == self.end {
+            true
+        } else if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// A bandwidth limit for some days of the week, between `start` and `end`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthRule {
+    /// Every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Bytes per second shared by every download, unlimited if not given
+    pub limit: Option<u64>,
+}
+
+impl BandwidthRule {
+    fn applies_at(&self, time: NaiveDateTime) -> bool {
+        let window = TimeWindow {
+            start: self.start,
+            end: self.end,
+        };
+        if !window.contains(time.time()) {
+            return false;
+        }
+
+        // the early hours of a window running past midnight belong to the day it started
+        let day = if window.start > window.end && time.time() < window.end {
+            time.weekday().pred()
+        } else {
+            time.weekday()
+        };
+
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+/// The global bandwidth limit over the week. The first rule that applies wins, outside of every
+/// rule the limit the downloader was given applies.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BandwidthSchedule {
+    pub rules: Vec<BandwidthRule>,
+}
+
+impl BandwidthSchedule {
+    /// The limit in bytes per second at `time`, `Some(None)` for a rule without a limit and
+    /// `None` if no rule applies
+    pub fn limit_at(&self, time: NaiveDateTime) -> Option<Option<u64>> {
+        self.rules
+            .iter()
+            .find(|rule| rule.applies_at(time))
+            .map(|rule| rule.limit)
+    }
+}
+
+/// Hands out bandwidth to every subdownload from a shared budget. Whoever goes over the budget
+/// sleeps until it has been paid back, so together the downloads stay under the limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<RateLimiterState>,
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    limit: Option<u64>,
+    /// Bytes that can go through right now, negative when we are behind
+    budget: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(RateLimiterState {
+                limit,
+                budget: 0.0,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn limit(&self) -> Option<u64> {
+        self.state.lock().await.limit
+    }
+
+    pub async fn set_limit(&self, limit: Option<u64>) {
+        let mut state = self.state.lock().await;
+        if state.limit != limit {
+            state.limit = limit;
+            // whatever debt there is was taken at the old rate
+            state.budget = 0.0;
+            state.refilled_at = Instant::now();
+        }
+    }
+
+    /// Account for `bytes` that just went through, waiting if they were more than we could afford
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().await;
+            let limit = match state.limit {
+                Some(limit) if limit > 0 => limit as f64,
+                _ => return,
+            };
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+            // a second worth of bytes at most, so an idle stretch doesn't turn into a burst
+            state.budget = (state.budget + elapsed * limit).min(limit);
+            state.refilled_at = now;
+            state.budget -= bytes as f64;
+
+            if state.budget >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.budget / limit)
+        };
+
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use pretty_assertions::assert_eq;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // the 6th of June 2022 is a Monday
+        NaiveDate::from_ymd_opt(2022, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn time(text: &str) -> NaiveTime {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn window_runs_past_midnight() {
+        let overnight = TimeWindow {
+            start: time("22:00"),
+            end: time("06:00"),
+        };
+
+        assert!(overnight.contains(time("23:30")));
+        assert!(overnight.contains(time("02:00")));
+        assert!(!overnight.contains(time("06:00")));
+        assert!(!overnight.contains(time("12:00")));
+
+        let office = TimeWindow {
+            start: time("09:00"),
+            end: time("17:00"),
+        };
+        assert!(office.contains(time("09:00")));
+        assert!(!office.contains(time("17:00")));
+
+        let all_day = TimeWindow {
+            start: time("00:00"),
+            end: time("00:00"),
+        };
+        assert!(all_day.contains(time("13:37")));
+    }
+
+    #[test]
+    fn first_matching_rule_sets_the_limit() -> color_eyre::Result<()> {
+        let schedule: BandwidthSchedule = serde_json::from_str(
+            r#"[
+                {"days": ["Fri"], "start": "22:00", "end": "02:00", "limit": 2048},
+                {"days": ["Sat", "Sun"], "start": "00:00", "end": "00:00", "limit": null},
+                {"start": "08:00", "end": "18:00", "limit": 1048576}
+            ]"#,
+        )?;
+
+        assert_eq!(schedule.limit_at(at(6, 9, 0)), Some(Some(1048576)));
+        assert_eq!(schedule.limit_at(at(6, 19, 0)), None);
+        assert_eq!(schedule.limit_at(at(10, 23, 0)), Some(Some(2048)));
+        // still Friday night as far as the rule is concerned
+        assert_eq!(schedule.limit_at(at(11, 1, 0)), Some(Some(2048)));
+        // the weekend is unlimited, rather than not covered
+        assert_eq!(schedule.limit_at(at(11, 9, 0)), Some(None));
+        assert_eq!(schedule.limit_at(at(9, 1, 0)), None);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter_keeps_under_the_limit() {
+        let limiter = RateLimiter::new(Some(1000));
+
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.consume(1000).await;
+        }
+
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_rate_limiter_never_waits() {
+        let limiter = RateLimiter::new(None);
+
+        let start = Instant::now();
+        limiter.consume(u64::MAX).await;
+
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -1,3 +1,11 @@
+table! {
+    feed_item (feed, item) {
+        feed -> Text,
+        item -> Text,
+        added_at -> BigInt,
+    }
+}
+
 table! {
     file_path (path) {
         path -> Text,
@@ -5,12 +13,38 @@ table! {
 }
 
 table! {
-    http_subdownload (id) {
+    http_download (id) {
         id -> Integer,
         url -> Text,
-        offset -> Integer,
-        total -> Integer,
         file_path -> Text,
+        state -> Text,
+        total_size -> Nullable<BigInt>,
+        created_at -> BigInt,
+        completed_at -> Nullable<BigInt>,
+        error -> Nullable<Text>,
+        options -> Text,
+        priority -> Integer,
+        started_at -> Nullable<BigInt>,
+        checksum -> Nullable<Text>,
+        origin -> Nullable<Text>,
+    }
+}
+
+table! {
+    http_subdownload (id) {
+        id -> Integer,
+        download_id -> Integer,
+        offset -> BigInt,
+        total -> BigInt,
+    }
+}
+
+table! {
+    monitored_url (url) {
+        url -> Text,
+        etag -> Nullable<Text>,
+        last_modified -> Nullable<Text>,
+        length -> Nullable<BigInt>,
     }
 }
 
@@ -20,11 +54,15 @@ table! {
     }
 }
 
-joinable!(http_subdownload -> file_path (file_path));
-joinable!(http_subdownload -> url (url));
+joinable!(http_download -> file_path (file_path));
+joinable!(http_download -> url (url));
+joinable!(http_subdownload -> http_download (download_id));
 
 allow_tables_to_appear_in_same_query!(
+    feed_item,
     file_path,
+    http_download,
     http_subdownload,
+    monitored_url,
     url,
 );
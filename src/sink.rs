@@ -0,0 +1,214 @@
+use async_trait::async_trait;
+use hyper::body::Bytes;
+use std::{
+    fmt::Debug,
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom},
+    sync::Mutex,
+};
+
+/// Where the subdownloads of a download put their data. Every subdownload writes to its own range
+/// of the sink, so implementations should let them write at the same time instead of queueing them
+/// up behind each other.
+#[async_trait]
+pub trait DownloadSink: Send + Sync + Debug + 'static {
+    /// Write all of `data` starting at `offset`
+    async fn write_at(&self, offset: u64, data: Bytes) -> io::Result<()>;
+
+    /// Make sure everything written so far is durable
+    async fn sync(&self) -> io::Result<()>;
+}
+
+/// A sink backed by a file, using positional writes so concurrent subdownloads never wait on each
+/// other or share a file cursor.
+#[derive(Debug)]
+pub struct FileSink {
+    file: Arc<File>,
+}
+
+impl FileSink {
+    pub fn new(file: File) -> Self {
+        Self {
+            file: Arc::new(file),
+        }
+    }
+
+    /// Open `path` for writing, keeping whatever is already in it so downloads can be resumed
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let path = path.to_path_buf();
+
+        let file = tokio::task::spawn_blocking(move || {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+        })
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+
+        Ok(Self::new(file))
+    }
+}
+
+#[async_trait]
+impl DownloadSink for FileSink {
+    async fn write_at(&self, offset: u64, data: Bytes) -> io::Result<()> {
+        let file = self.file.clone();
+
+        tokio::task::spawn_blocking(move || file.write_all_at(&data, offset))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+    }
+
+    async fn sync(&self) -> io::Result<()> {
+        let file = self.file.clone();
+
+        tokio::task::spawn_blocking(move || file.sync_all())
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+    }
+}
+
+/// Adapts anything we can seek and write to into a sink. Every write has to seek first, so the
+/// writes are serialized behind a lock; prefer `FileSink` for files.
+#[derive(Debug)]
+pub struct MutexSink<S> {
+    inner: Mutex<S>,
+}
+
+impl<S> MutexSink<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+}
+
+#[async_trait]
+impl<S> DownloadSink for MutexSink<S>
+where
+    S: AsyncSeek + AsyncWrite + Send + Unpin + Debug + 'static,
+{
+    async fn write_at(&self, offset: u64, data: Bytes) -> io::Result<()> {
+        let mut sink = self.inner.lock().await;
+
+        // make sure to seek to the correct position
+        sink.seek(SeekFrom::Start(offset)).await?;
+
+        // then we can write
+        sink.write_all(&data).await
+    }
+
+    async fn sync(&self) -> io::Result<()> {
+        self.inner.lock().await.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::try_join_all;
+    use pretty_assertions::assert_eq;
+    use std::{io::Cursor, path::PathBuf, time::Instant};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("sulfur-sink-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn file_sink_writes_out_of_order() -> color_eyre::Result<()> {
+        let path = temp_path("out-of-order");
+        let sink = FileSink::open(&path).await?;
+
+        sink.write_at(5, Bytes::from_static(b"world")).await?;
+        sink.write_at(0, Bytes::from_static(b"hello")).await?;
+        sink.sync().await?;
+
+        let written = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(written, b"helloworld");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mutex_sink_writes_out_of_order() -> color_eyre::Result<()> {
+        let sink = MutexSink::new(Cursor::new(Vec::new()));
+
+        sink.write_at(5, Bytes::from_static(b"world")).await?;
+        sink.write_at(0, Bytes::from_static(b"hello")).await?;
+
+        assert_eq!(sink.into_inner().into_inner(), b"helloworld");
+
+        Ok(())
+    }
+
+    /// Write `segments` ranges of `segment_size` bytes concurrently in 64 KiB chunks, returning the
+    /// throughput in MiB/s
+    async fn throughput<S: DownloadSink>(
+        sink: Arc<S>,
+        segments: u64,
+        segment_size: u64,
+    ) -> color_eyre::Result<f64> {
+        const CHUNK: u64 = 64 * 1024;
+        let chunk = Bytes::from(vec![0x5a; CHUNK as usize]);
+
+        let start = Instant::now();
+        try_join_all((0..segments).map(|segment| {
+            let sink = sink.clone();
+            let chunk = chunk.clone();
+            tokio::spawn(async move {
+                for offset in (0..segment_size).step_by(CHUNK as usize) {
+                    sink.write_at(segment * segment_size + offset, chunk.clone())
+                        .await?;
+                }
+                Ok::<_, io::Error>(())
+            })
+        }))
+        .await?
+        .into_iter()
+        .collect::<io::Result<()>>()?;
+        sink.sync().await?;
+
+        let mib = (segments * segment_size) as f64 / (1024.0 * 1024.0);
+        Ok(mib / start.elapsed().as_secs_f64())
+    }
+
+    /// Compares positional writes against the old seek-and-write behind a mutex with many
+    /// connections, run with `cargo test --release -- --ignored --nocapture sink_throughput`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn sink_throughput() -> color_eyre::Result<()> {
+        const SEGMENTS: u64 = 64;
+        const SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+        let path = temp_path("bench-mutex");
+        let file = tokio::fs::File::create(&path).await?;
+        let mutex = throughput(Arc::new(MutexSink::new(file)), SEGMENTS, SEGMENT_SIZE).await?;
+        std::fs::remove_file(&path)?;
+
+        let path = temp_path("bench-positional");
+        let positional =
+            throughput(Arc::new(FileSink::open(&path).await?), SEGMENTS, SEGMENT_SIZE).await?;
+        std::fs::remove_file(&path)?;
+
+        println!(
+            "{} segments of {} bytes: mutex {:.1} MiB/s, positional {:.1} MiB/s",
+            SEGMENTS, SEGMENT_SIZE, mutex, positional
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+use crate::store::{DownloadRecord, DownloadState};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a download can go without progress before it is taken as stalled rather than still
+/// going at its last speed
+const STALLED_AFTER: Duration = Duration::from_secs(5);
+
+/// How fast a download goes, from how far along it was the last few times we looked
+#[derive(Debug, Default)]
+pub struct Speed {
+    last: Option<(Instant, u64)>,
+    bytes_per_second: u64,
+}
+
+impl Speed {
+    /// Bytes per second now that `downloaded` bytes are on disk. The progress is only written
+    /// down every so often, so the last speed holds until it is again.
+    pub fn update(&mut self, now: Instant, downloaded: u64) -> u64 {
+        match self.last {
+            Some((then, before)) if before == downloaded => {
+                if now.duration_since(then) > STALLED_AFTER {
+                    self.bytes_per_second = 0;
+                }
+            }
+            Some((then, before)) => {
+                let elapsed = now.duration_since(then).as_secs_f64();
+                if elapsed > 0.0 {
+                    let moved = downloaded.saturating_sub(before);
+                    self.bytes_per_second = (moved as f64 / elapsed) as u64;
+                    self.last = Some((now, downloaded));
+                }
+            }
+            None => self.last = Some((now, downloaded)),
+        }
+
+        self.bytes_per_second
+    }
+}
+
+/// The speed of every running download, shared by everyone who asks about them
+#[derive(Debug, Default)]
+pub struct Speeds(Mutex<HashMap<i32, Speed>>);
+
+pub type SharedSpeeds = Arc<Speeds>;
+
+impl Speeds {
+    /// How fast the download goes with `downloaded` bytes on disk, nothing unless it is running
+    pub fn of(&self, record: &DownloadRecord, downloaded: Option<u64>) -> Option<u64> {
+        let mut speeds = self.0.lock().unwrap();
+
+        match (record.state, downloaded) {
+            (DownloadState::Running, Some(downloaded)) => Some(
+                speeds
+                    .entry(record.id)
+                    .or_default()
+                    .update(Instant::now(), downloaded),
+            ),
+            _ => {
+                speeds.remove(&record.id);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn speed_follows_the_progress() {
+        let start = Instant::now();
+        let mut speed = Speed::default();
+
+        assert_eq!(speed.update(start, 0), 0);
+        assert_eq!(speed.update(start + Duration::from_secs(2), 2048), 1024);
+        // nothing was written down in between
+        assert_eq!(speed.update(start + Duration::from_secs(3), 2048), 1024);
+        assert_eq!(speed.update(start + Duration::from_secs(4), 6144), 2048);
+        assert_eq!(speed.update(start + Duration::from_secs(10), 6144), 0);
+    }
+}
@@ -0,0 +1,1014 @@
+pub(crate) mod memory;
+pub(crate) mod sqlite;
+
+use crate::{
+    history::HistoryFilter,
+    http::DownloadContext,
+    request::{http::DownloadOptions, monitor::UrlVersion},
+};
+use ::url::Url as WgUrl;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    error::Error,
+    fmt::{Debug, Display, Formatter},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The errors a `DownloadStore` can run into
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound,
+    /// The backend couldn't give us a connection to work with
+    Connection(String),
+    Query(diesel::result::Error),
+    /// The store holds something that can't be turned back into a download
+    Corrupted(String),
+    /// The download can't be represented by this store
+    Unsupported(String),
+    /// The task running the blocking work died before it finished
+    Executor(String),
+    /// The schema couldn't be brought up to date
+    Migration(String),
+    /// A download can't go from one state to the other
+    InvalidTransition {
+        from: DownloadState,
+        to: DownloadState,
+    },
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            StoreError::NotFound => write!(f, "No such download in the store"),
+            StoreError::Connection(reason) => {
+                write!(f, "Failed to connect to the store: {}", reason)
+            }
+            StoreError::Query(e) => write!(f, "Store query failed: {}", e),
+            StoreError::Corrupted(reason) => write!(f, "The store is corrupted: {}", reason),
+            StoreError::Unsupported(reason) => write!(f, "Can't store the download: {}", reason),
+            StoreError::Executor(reason) => write!(f, "Store task failed: {}", reason),
+            StoreError::Migration(reason) => {
+                write!(f, "Failed to migrate the store: {}", reason)
+            }
+            StoreError::InvalidTransition { from, to } => {
+                write!(f, "A {} download can't become {}", from, to)
+            }
+        }
+    }
+}
+
+impl Error for StoreError {}
+
+impl From<diesel::result::Error> for StoreError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => StoreError::NotFound,
+            e => StoreError::Query(e),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for StoreError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        StoreError::Connection(format!("{}", e))
+    }
+}
+
+impl From<tokio::task::JoinError> for StoreError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        StoreError::Executor(format!("{}", e))
+    }
+}
+
+/// Where a download is in its life. A download is queued, probed for its size, run and possibly
+/// verified before it is completed; it can fail or be cancelled along the way. Paused downloads go
+/// back to wherever they can continue from, failed ones and the ones that have to start over are
+/// queued again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadState {
+    Queued,
+    Probing,
+    Running,
+    Paused,
+    Verifying,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl DownloadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadState::Queued => "queued",
+            DownloadState::Probing => "probing",
+            DownloadState::Running => "running",
+            DownloadState::Paused => "paused",
+            DownloadState::Verifying => "verifying",
+            DownloadState::Completed => "completed",
+            DownloadState::Failed => "failed",
+            DownloadState::Cancelled => "cancelled",
+        }
+    }
+
+    /// Whether the download is done for good, one way or another
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            DownloadState::Completed | DownloadState::Failed | DownloadState::Cancelled
+        )
+    }
+
+    pub fn can_transition_to(&self, next: DownloadState) -> bool {
+        use DownloadState::*;
+
+        matches!(
+            (self, next),
+            (Queued, Probing | Running | Paused | Cancelled)
+                | (Probing, Queued | Running | Paused | Failed | Cancelled)
+                | (
+                    Running,
+                    Queued | Verifying | Completed | Paused | Failed | Cancelled
+                )
+                | (Paused, Queued | Probing | Running | Cancelled)
+                | (Verifying, Queued | Completed | Failed | Cancelled)
+                | (Failed, Queued | Cancelled)
+        )
+    }
+}
+
+impl Display for DownloadState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for DownloadState {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use DownloadState::*;
+
+        [
+            Queued, Probing, Running, Paused, Verifying, Completed, Failed, Cancelled,
+        ]
+        .into_iter()
+        .find(|state| state.as_str() == s)
+        .ok_or_else(|| StoreError::Corrupted(format!("unknown download state {}", s)))
+    }
+}
+
+/// A download as a whole, the subdownloads doing the actual work belong to one of these
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DownloadRecord {
+    pub id: i32,
+    pub url: WgUrl,
+    pub file_path: PathBuf,
+    pub state: DownloadState,
+    /// Unknown until the download has been probed
+    pub total_size: Option<u64>,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+    /// Why the download failed, if it did
+    pub error: Option<String>,
+    pub options: DownloadOptions,
+    /// Queued downloads with a higher priority start first
+    pub priority: i32,
+    /// The last time the download started from scratch
+    pub started_at: Option<u64>,
+    /// The hex encoded sha-256 of the finished file
+    pub checksum: Option<String>,
+    /// Which request source asked for the download
+    pub origin: Option<String>,
+}
+
+impl DownloadRecord {
+    /// A download that has yet to be added to a store
+    pub fn new(
+        url: WgUrl,
+        file_path: PathBuf,
+        state: DownloadState,
+        options: DownloadOptions,
+    ) -> Self {
+        Self {
+            id: -1,
+            url,
+            file_path,
+            state,
+            total_size: None,
+            created_at: unix_now(),
+            completed_at: None,
+            error: None,
+            options,
+            priority: 0,
+            started_at: None,
+            checksum: None,
+            origin: None,
+        }
+    }
+
+    /// The order queued downloads start in: highest priority first, then the oldest
+    pub fn queue_order(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then(self.created_at.cmp(&other.created_at))
+            .then(self.id.cmp(&other.id))
+    }
+
+    /// Move the download to `state`, refusing transitions the state machine doesn't allow
+    pub fn transition_to(
+        &mut self,
+        state: DownloadState,
+        error: Option<String>,
+    ) -> Result<(), StoreError> {
+        if !self.state.can_transition_to(state) {
+            return Err(StoreError::InvalidTransition {
+                from: self.state,
+                to: state,
+            });
+        }
+
+        // resuming carries on the same attempt, probing starts a new one
+        if state == DownloadState::Probing
+            || (state == DownloadState::Running && self.started_at.is_none())
+        {
+            self.started_at = Some(unix_now());
+        }
+
+        self.state = state;
+        self.error = error;
+        // a failed download that is queued again isn't done anymore
+        self.completed_at = state.is_finished().then(unix_now);
+
+        Ok(())
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Where the state of every download is persisted, so they can be picked up again after a restart.
+///
+/// The store is used from async tasks, implementations must never block the runtime while waiting
+/// on their backend.
+#[async_trait]
+pub trait DownloadStore: Debug + Send + Sync {
+    /// Add a download together with its subdownloads, all or nothing. Returns the id of the
+    /// download and of every subdownload, in order.
+    async fn create_download(
+        &self,
+        download: &DownloadRecord,
+        segments: &[DownloadContext],
+    ) -> Result<(i32, Vec<i32>), StoreError>;
+    /// Add many downloads along with their subdownloads, all or nothing. Returns the id of every
+    /// download, in order.
+    async fn create_downloads(
+        &self,
+        downloads: &[(DownloadRecord, Vec<DownloadContext>)],
+    ) -> Result<Vec<i32>, StoreError>;
+    /// Give a probed download its size and subdownloads, all or nothing, and mark it running
+    async fn add_segments(
+        &self,
+        download_id: i32,
+        total_size: u64,
+        segments: &[DownloadContext],
+    ) -> Result<Vec<i32>, StoreError>;
+    /// Move a download to `state`, recording `error` if there is one. Completed and cancelled
+    /// downloads lose their subdownloads, there is nothing left to resume.
+    async fn set_state(
+        &self,
+        download_id: i32,
+        state: DownloadState,
+        error: Option<String>,
+    ) -> Result<(), StoreError>;
+    async fn download(&self, download_id: i32) -> Result<DownloadRecord, StoreError>;
+    async fn downloads(&self) -> Result<Vec<DownloadRecord>, StoreError>;
+    /// Every queued download, in the order they should start in
+    async fn queue(&self) -> Result<Vec<DownloadRecord>, StoreError>;
+    /// Give each download its priority, all or nothing
+    async fn set_priorities(&self, priorities: &[(i32, i32)]) -> Result<(), StoreError>;
+    async fn segments(&self, download_id: i32) -> Result<Vec<DownloadContext>, StoreError>;
+    // todo: perhaps it should take an id?
+    async fn update_download(&self, download: &DownloadContext) -> Result<(), StoreError>;
+    /// Update many downloads at once, all or nothing
+    async fn update_downloads(&self, downloads: &[DownloadContext]) -> Result<(), StoreError>;
+    async fn downloads_by_url(&self, wg_url: &WgUrl) -> Result<Vec<DownloadContext>, StoreError>;
+    /// Every subdownload still in the store, used to pick up where a previous run left off
+    async fn all_downloads(&self) -> Result<Vec<DownloadContext>, StoreError>;
+    /// Remove every download of `wg_url` along with their subdownloads
+    async fn remove_by_url(&self, wg_url: &WgUrl) -> Result<(), StoreError>;
+    /// Remove a single subdownload
+    async fn remove_by_id(&self, id: i32) -> Result<(), StoreError>;
+    /// Remove a download along with its subdownloads
+    async fn remove_download(&self, download_id: i32) -> Result<(), StoreError>;
+    async fn set_checksum(&self, download_id: i32, checksum: String) -> Result<(), StoreError>;
+    /// The finished downloads matching `filter`, the most recent first
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<DownloadRecord>, StoreError>;
+    /// Remove the finished downloads that finished before `finished_before` and all but the `keep`
+    /// most recent ones, returning how many were removed
+    async fn prune_history(
+        &self,
+        finished_before: Option<u64>,
+        keep: Option<usize>,
+    ) -> Result<usize, StoreError>;
+    /// Remember that `item` of the feed at `feed` was enqueued
+    async fn add_feed_item(&self, feed: &str, item: &str) -> Result<(), StoreError>;
+    /// Whether `item` of the feed at `feed` was enqueued before
+    async fn has_feed_item(&self, feed: &str, item: &str) -> Result<bool, StoreError>;
+    /// The version of the monitored `wg_url` that was last downloaded
+    async fn url_version(&self, wg_url: &WgUrl) -> Result<Option<UrlVersion>, StoreError>;
+    /// Remember `version` as the version of the monitored `wg_url` that was last downloaded
+    async fn set_url_version(&self, wg_url: &WgUrl, version: &UrlVersion)
+        -> Result<(), StoreError>;
+}
+
+pub type SharedDownloadStore = Arc<dyn DownloadStore>;
+
+/// The behaviour every `DownloadStore` must have. A backend runs the whole suite against itself
+/// with `download_store_tests!`.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pretty_assertions::{assert_eq, assert_ne};
+    use std::path::{Path, PathBuf};
+
+    /// Expands to one test per case of the suite, `$store` has to evaluate to a fresh, empty store
+    macro_rules! download_store_tests {
+        ($store:expr) => {
+            download_store_tests!(
+                $store;
+                create_gives_unique_ids,
+                create_keeps_the_metadata,
+                add_segments_starts_the_download,
+                update_changes_only_the_given_download,
+                update_many_at_once,
+                downloads_by_url_finds_only_that_url,
+                remove_by_url_removes_every_subdownload,
+                remove_by_id_removes_only_that_subdownload,
+                remove_download_removes_its_subdownloads,
+                removing_a_missing_download_is_fine,
+                offsets_past_4_gib_survive,
+                completing_keeps_the_download_but_not_its_subdownloads,
+                failing_records_the_error,
+                invalid_transitions_are_refused,
+                missing_downloads_are_not_found,
+                queue_is_ordered_by_priority_then_age,
+                set_priorities_is_all_or_nothing,
+                starting_records_when_the_download_started,
+                history_is_filtered_most_recent_first,
+                pruning_keeps_the_most_recent_history,
+                feed_items_are_remembered_per_feed,
+                url_versions_are_replaced
+            );
+        };
+        ($store:expr; $($case:ident),+) => {
+            $(
+                #[tokio::test]
+                async fn $case() -> color_eyre::Result<()> {
+                    crate::store::tests::$case(&$store).await
+                }
+            )+
+        };
+    }
+
+    pub(crate) use download_store_tests;
+
+    pub(crate) fn download(url: &str, offset: u64, total: u64) -> DownloadContext {
+        let file_path: Arc<Path> = Arc::from(PathBuf::from("/tmp/test.txt"));
+
+        DownloadContext {
+            id: -1,
+            download_id: -1,
+            url: Arc::new(WgUrl::parse(url).unwrap()),
+            offset,
+            total,
+            file_path,
+        }
+    }
+
+    pub(crate) fn record(url: &str, state: DownloadState) -> DownloadRecord {
+        DownloadRecord::new(
+            WgUrl::parse(url).unwrap(),
+            PathBuf::from("/tmp/test.txt"),
+            state,
+            DownloadOptions::default(),
+        )
+    }
+
+    /// A running download of `url` split into `ranges`, returning the subdownloads as stored
+    pub(crate) async fn running(
+        store: &dyn DownloadStore,
+        url: &str,
+        ranges: &[(u64, u64)],
+    ) -> Result<Vec<DownloadContext>, StoreError> {
+        let segments: Vec<_> = ranges
+            .iter()
+            .map(|(offset, total)| download(url, *offset, *total))
+            .collect();
+        let (download_id, ids) = store
+            .create_download(&record(url, DownloadState::Running), &segments)
+            .await?;
+
+        Ok(segments
+            .into_iter()
+            .zip(ids)
+            .map(|(segment, id)| DownloadContext {
+                id,
+                download_id,
+                ..segment
+            })
+            .collect())
+    }
+
+    async fn sorted(store: &dyn DownloadStore) -> Result<Vec<DownloadContext>, StoreError> {
+        let mut downloads = store.all_downloads().await?;
+        downloads.sort_by_key(|download| download.id);
+        Ok(downloads)
+    }
+
+    #[test]
+    fn states_round_trip_through_text() {
+        use DownloadState::*;
+
+        for state in [
+            Queued, Probing, Running, Paused, Verifying, Completed, Failed, Cancelled,
+        ] {
+            assert_eq!(state.as_str().parse::<DownloadState>().unwrap(), state);
+        }
+        assert!("done".parse::<DownloadState>().is_err());
+    }
+
+    #[test]
+    fn finished_downloads_stay_finished() {
+        use DownloadState::*;
+
+        assert!(!Completed.can_transition_to(Queued));
+        assert!(!Cancelled.can_transition_to(Running));
+        assert!(!Queued.can_transition_to(Completed));
+        assert!(Failed.can_transition_to(Queued));
+        assert!(Paused.can_transition_to(Running));
+        assert!(Running.can_transition_to(Queued));
+        // a download stopped by its window picks up where it left off
+        assert!(Queued.can_transition_to(Running));
+    }
+
+    pub(crate) async fn create_gives_unique_ids(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let first = running(store, "https://www.google.com", &[(0, 5000), (5000, 5000)]).await?;
+        let second = running(store, "https://www.google.com", &[(0, 5000)]).await?;
+
+        assert_ne!(first[0].id, first[1].id);
+        assert_ne!(first[1].id, second[0].id);
+        assert_eq!(first[0].download_id, first[1].download_id);
+        assert_ne!(first[0].download_id, second[0].download_id);
+        assert_eq!(store.all_downloads().await?.len(), 3);
+        assert_eq!(store.downloads().await?.len(), 2);
+
+        Ok(())
+    }
+
+    pub(crate) async fn create_keeps_the_metadata(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let mut queued = record("https://www.google.com", DownloadState::Queued);
+        queued.options.connections = Some(4);
+        queued.origin = Some("api".to_string());
+        let (download_id, ids) = store.create_download(&queued, &[]).await?;
+
+        assert!(ids.is_empty());
+        assert_eq!(
+            store.download(download_id).await?,
+            DownloadRecord {
+                id: download_id,
+                ..queued
+            }
+        );
+        assert!(store.segments(download_id).await?.is_empty());
+
+        Ok(())
+    }
+
+    pub(crate) async fn add_segments_starts_the_download(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let (download_id, _) = store
+            .create_download(
+                &record("https://www.google.com", DownloadState::Probing),
+                &[],
+            )
+            .await?;
+
+        let ids = store
+            .add_segments(
+                download_id,
+                10000,
+                &[
+                    download("https://www.google.com", 0, 5000),
+                    download("https://www.google.com", 5000, 5000),
+                ],
+            )
+            .await?;
+
+        let stored = store.download(download_id).await?;
+        assert_eq!(stored.state, DownloadState::Running);
+        assert_eq!(stored.total_size, Some(10000));
+
+        let mut segments = store.segments(download_id).await?;
+        segments.sort_by_key(|segment| segment.id);
+        assert_eq!(
+            segments
+                .iter()
+                .map(|segment| segment.id)
+                .collect::<Vec<_>>(),
+            ids
+        );
+        assert!(segments
+            .iter()
+            .all(|segment| segment.download_id == download_id));
+        assert_eq!(segments[1].offset, 5000);
+
+        Ok(())
+    }
+
+    pub(crate) async fn update_changes_only_the_given_download(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let mut downloads =
+            running(store, "https://www.google.com", &[(0, 5000), (5000, 5000)]).await?;
+
+        downloads[0].offset = 1000;
+        downloads[0].total = 4000;
+        store.update_download(&downloads[0]).await?;
+
+        let downloads = sorted(store).await?;
+        assert_eq!(downloads.len(), 2);
+        assert_eq!((downloads[0].offset, downloads[0].total), (1000, 4000));
+        assert_eq!((downloads[1].offset, downloads[1].total), (5000, 5000));
+
+        Ok(())
+    }
+
+    pub(crate) async fn update_many_at_once(store: &dyn DownloadStore) -> color_eyre::Result<()> {
+        let mut downloads =
+            running(store, "https://www.google.com", &[(0, 5000), (5000, 5000)]).await?;
+
+        downloads[0].offset = 1;
+        downloads[0].total = 4999;
+        downloads[1].offset = 5002;
+        downloads[1].total = 4998;
+        store.update_downloads(&downloads).await?;
+
+        let downloads = sorted(store).await?;
+        assert_eq!((downloads[0].offset, downloads[0].total), (1, 4999));
+        assert_eq!((downloads[1].offset, downloads[1].total), (5002, 4998));
+
+        Ok(())
+    }
+
+    pub(crate) async fn downloads_by_url_finds_only_that_url(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        running(store, "https://www.google.com", &[(0, 5000), (5000, 5000)]).await?;
+        let mut other = record("https://example.com", DownloadState::Running);
+        other.file_path = PathBuf::from("/tmp/other.txt");
+        store
+            .create_download(&other, &[download("https://example.com", 0, 10)])
+            .await?;
+
+        let found = store
+            .downloads_by_url(&WgUrl::parse("https://www.google.com")?)
+            .await?;
+
+        assert_eq!(found.len(), 2);
+        assert!(found
+            .iter()
+            .all(|download| download.url.as_str() == "https://www.google.com/"));
+
+        Ok(())
+    }
+
+    pub(crate) async fn remove_by_url_removes_every_subdownload(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let url = WgUrl::parse("https://www.google.com")?;
+        running(store, url.as_str(), &[(0, 5000), (5000, 5000)]).await?;
+        running(store, url.as_str(), &[(0, 5000)]).await?;
+
+        store.remove_by_url(&url).await?;
+
+        assert!(store.all_downloads().await?.is_empty());
+        assert!(store.downloads().await?.is_empty());
+
+        Ok(())
+    }
+
+    pub(crate) async fn remove_by_id_removes_only_that_subdownload(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let downloads = running(store, "https://www.google.com", &[(0, 5000), (0, 5000)]).await?;
+
+        store.remove_by_id(downloads[0].id).await?;
+
+        let remaining = store.all_downloads().await?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, downloads[1].id);
+        assert_eq!(store.downloads().await?.len(), 1);
+
+        Ok(())
+    }
+
+    pub(crate) async fn remove_download_removes_its_subdownloads(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let removed = running(store, "https://www.google.com", &[(0, 5000), (5000, 5000)]).await?;
+        let kept = running(store, "https://www.google.com", &[(0, 5000)]).await?;
+
+        store.remove_download(removed[0].download_id).await?;
+
+        assert_eq!(store.downloads().await?.len(), 1);
+        let remaining = store.all_downloads().await?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, kept[0].id);
+
+        Ok(())
+    }
+
+    pub(crate) async fn offsets_past_4_gib_survive(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        const GIB: u64 = 1024 * 1024 * 1024;
+
+        let mut big = running(store, "https://www.google.com", &[(5 * GIB, 3 * GIB)]).await?;
+
+        let stored = &store.all_downloads().await?[0];
+        assert_eq!((stored.offset, stored.total), (5 * GIB, 3 * GIB));
+
+        big[0].offset += 2 * GIB;
+        big[0].total -= 2 * GIB;
+        store.update_downloads(&big).await?;
+
+        let stored = &store.all_downloads().await?[0];
+        assert_eq!((stored.offset, stored.total), (7 * GIB, GIB));
+
+        let (download_id, _) = store
+            .create_download(&record("https://example.com", DownloadState::Probing), &[])
+            .await?;
+        store.add_segments(download_id, 8 * GIB, &[]).await?;
+        assert_eq!(store.download(download_id).await?.total_size, Some(8 * GIB));
+
+        Ok(())
+    }
+
+    pub(crate) async fn completing_keeps_the_download_but_not_its_subdownloads(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let downloads =
+            running(store, "https://www.google.com", &[(0, 5000), (5000, 5000)]).await?;
+        let download_id = downloads[0].download_id;
+
+        store
+            .set_state(download_id, DownloadState::Completed, None)
+            .await?;
+
+        let completed = store.download(download_id).await?;
+        assert_eq!(completed.state, DownloadState::Completed);
+        assert!(completed.completed_at.is_some());
+        assert!(store.segments(download_id).await?.is_empty());
+        assert!(store.all_downloads().await?.is_empty());
+
+        Ok(())
+    }
+
+    pub(crate) async fn failing_records_the_error(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let downloads = running(store, "https://www.google.com", &[(0, 5000)]).await?;
+        let download_id = downloads[0].download_id;
+
+        store
+            .set_state(
+                download_id,
+                DownloadState::Failed,
+                Some("connection reset".to_string()),
+            )
+            .await?;
+
+        let failed = store.download(download_id).await?;
+        assert_eq!(failed.state, DownloadState::Failed);
+        assert_eq!(failed.error.as_deref(), Some("connection reset"));
+        // the progress stays around in case the download is queued again
+        assert_eq!(store.segments(download_id).await?.len(), 1);
+
+        store
+            .set_state(download_id, DownloadState::Queued, None)
+            .await?;
+        assert_eq!(store.download(download_id).await?.error, None);
+
+        Ok(())
+    }
+
+    pub(crate) async fn invalid_transitions_are_refused(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let (download_id, _) = store
+            .create_download(
+                &record("https://www.google.com", DownloadState::Queued),
+                &[],
+            )
+            .await?;
+
+        let result = store
+            .set_state(download_id, DownloadState::Completed, None)
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(StoreError::InvalidTransition {
+                from: DownloadState::Queued,
+                to: DownloadState::Completed
+            })
+        ));
+        assert_eq!(
+            store.download(download_id).await?.state,
+            DownloadState::Queued
+        );
+
+        Ok(())
+    }
+
+    pub(crate) async fn missing_downloads_are_not_found(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        assert!(matches!(
+            store.download(12345).await,
+            Err(StoreError::NotFound)
+        ));
+        assert!(matches!(
+            store.set_state(12345, DownloadState::Cancelled, None).await,
+            Err(StoreError::NotFound)
+        ));
+        assert!(matches!(
+            store.add_segments(12345, 10, &[]).await,
+            Err(StoreError::NotFound)
+        ));
+
+        Ok(())
+    }
+
+    pub(crate) async fn removing_a_missing_download_is_fine(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        store.remove_by_id(12345).await?;
+        store.remove_download(12345).await?;
+        store
+            .remove_by_url(&WgUrl::parse("https://www.google.com")?)
+            .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn queue_is_ordered_by_priority_then_age(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let mut ids = vec![];
+        for (created_at, priority) in [(3, 0), (1, 0), (2, 5), (0, 0)] {
+            let mut queued = record("https://www.google.com", DownloadState::Queued);
+            queued.created_at = created_at;
+            queued.priority = priority;
+            ids.push(store.create_download(&queued, &[]).await?.0);
+        }
+        running(store, "https://www.google.com", &[(0, 5000)]).await?;
+
+        let queue: Vec<_> = store
+            .queue()
+            .await?
+            .iter()
+            .map(|queued| queued.id)
+            .collect();
+        assert_eq!(queue, vec![ids[2], ids[3], ids[1], ids[0]]);
+
+        store.set_priorities(&[(ids[0], 10)]).await?;
+        assert_eq!(store.queue().await?[0].id, ids[0]);
+
+        Ok(())
+    }
+
+    pub(crate) async fn set_priorities_is_all_or_nothing(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let (download_id, _) = store
+            .create_download(
+                &record("https://www.google.com", DownloadState::Queued),
+                &[],
+            )
+            .await?;
+
+        let result = store.set_priorities(&[(download_id, 3), (12345, 4)]).await;
+
+        assert!(matches!(result, Err(StoreError::NotFound)));
+        assert_eq!(store.download(download_id).await?.priority, 0);
+
+        Ok(())
+    }
+
+    /// A download of `url` that finished in `state` at `completed_at`
+    async fn finished(
+        store: &dyn DownloadStore,
+        url: &str,
+        state: DownloadState,
+        completed_at: u64,
+    ) -> Result<i32, StoreError> {
+        let finished = DownloadRecord {
+            state,
+            total_size: Some(5000),
+            started_at: Some(completed_at - 10),
+            completed_at: Some(completed_at),
+            ..record(url, DownloadState::Queued)
+        };
+        let (download_id, _) = store.create_download(&finished, &[]).await?;
+
+        Ok(download_id)
+    }
+
+    async fn history_ids(
+        store: &dyn DownloadStore,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<i32>, StoreError> {
+        Ok(store
+            .history(filter)
+            .await?
+            .iter()
+            .map(|record| record.id)
+            .collect())
+    }
+
+    pub(crate) async fn starting_records_when_the_download_started(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let (download_id, _) = store
+            .create_download(
+                &record("https://www.google.com", DownloadState::Queued),
+                &[],
+            )
+            .await?;
+        assert_eq!(store.download(download_id).await?.started_at, None);
+
+        store
+            .set_state(download_id, DownloadState::Probing, None)
+            .await?;
+        store.add_segments(download_id, 5000, &[]).await?;
+        store.set_checksum(download_id, "abc".to_string()).await?;
+        store
+            .set_state(download_id, DownloadState::Completed, None)
+            .await?;
+
+        let completed = store.download(download_id).await?;
+        assert!(completed.started_at.is_some());
+        assert!(completed.started_at <= completed.completed_at);
+        assert_eq!(completed.checksum.as_deref(), Some("abc"));
+
+        Ok(())
+    }
+
+    pub(crate) async fn history_is_filtered_most_recent_first(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let first = finished(
+            store,
+            "https://www.google.com",
+            DownloadState::Completed,
+            100,
+        )
+        .await?;
+        let second = finished(store, "https://www.bing.com", DownloadState::Failed, 200).await?;
+        let third = finished(
+            store,
+            "https://www.google.com",
+            DownloadState::Completed,
+            300,
+        )
+        .await?;
+        running(store, "https://www.google.com", &[(0, 5000)]).await?;
+
+        assert_eq!(
+            history_ids(store, &HistoryFilter::default()).await?,
+            vec![third, second, first]
+        );
+        assert_eq!(
+            history_ids(
+                store,
+                &HistoryFilter {
+                    state: Some(DownloadState::Completed),
+                    limit: Some(1),
+                    ..HistoryFilter::default()
+                }
+            )
+            .await?,
+            vec![third]
+        );
+        assert_eq!(
+            history_ids(
+                store,
+                &HistoryFilter {
+                    url: Some("google".to_string()),
+                    since: Some(100),
+                    until: Some(300),
+                    ..HistoryFilter::default()
+                }
+            )
+            .await?,
+            vec![first]
+        );
+
+        Ok(())
+    }
+
+    pub(crate) async fn pruning_keeps_the_most_recent_history(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let mut ids = vec![];
+        for completed_at in [100, 200, 300, 400] {
+            ids.push(
+                finished(
+                    store,
+                    "https://www.google.com",
+                    DownloadState::Completed,
+                    completed_at,
+                )
+                .await?,
+            );
+        }
+        let downloads = running(store, "https://www.google.com", &[(0, 5000)]).await?;
+
+        assert_eq!(store.prune_history(Some(200), None).await?, 1);
+        assert_eq!(store.prune_history(None, Some(2)).await?, 1);
+        assert_eq!(store.prune_history(None, Some(2)).await?, 0);
+
+        assert_eq!(
+            history_ids(store, &HistoryFilter::default()).await?,
+            vec![ids[3], ids[2]]
+        );
+        // only finished downloads are history
+        assert_eq!(store.segments(downloads[0].download_id).await?.len(), 1);
+
+        Ok(())
+    }
+
+    pub(crate) async fn feed_items_are_remembered_per_feed(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let podcast = "https://www.google.com/podcast.xml";
+        let releases = "https://www.google.com/releases.atom";
+
+        assert!(!store.has_feed_item(podcast, "episode-1").await?);
+        store.add_feed_item(podcast, "episode-1").await?;
+        // remembering twice is fine
+        store.add_feed_item(podcast, "episode-1").await?;
+
+        assert!(store.has_feed_item(podcast, "episode-1").await?);
+        assert!(!store.has_feed_item(podcast, "episode-2").await?);
+        assert!(!store.has_feed_item(releases, "episode-1").await?);
+
+        Ok(())
+    }
+
+    pub(crate) async fn url_versions_are_replaced(
+        store: &dyn DownloadStore,
+    ) -> color_eyre::Result<()> {
+        let nightly = WgUrl::parse("https://www.google.com/nightly.tar.gz")?;
+        let first = UrlVersion {
+            etag: Some("\"1\"".to_string()),
+            last_modified: None,
+            length: Some(5000),
+        };
+        let second = UrlVersion {
+            etag: None,
+            last_modified: Some("Tue, 05 Jul 2022 10:00:00 GMT".to_string()),
+            length: Some(1 << 33),
+        };
+
+        assert_eq!(store.url_version(&nightly).await?, None);
+        store.set_url_version(&nightly, &first).await?;
+        assert_eq!(store.url_version(&nightly).await?, Some(first));
+        store.set_url_version(&nightly, &second).await?;
+        assert_eq!(store.url_version(&nightly).await?, Some(second));
+
+        let other = WgUrl::parse("https://www.bing.com")?;
+        assert_eq!(store.url_version(&other).await?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+use super::{DownloadRecord, DownloadState, DownloadStore, StoreError};
+use crate::{
+    history::{is_pruned, HistoryFilter},
+    http::DownloadContext,
+    request::monitor::UrlVersion,
+};
+use ::url::Url as WgUrl;
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// A store that keeps everything in memory, for downloads that don't need to survive a restart
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    last_download_id: i32,
+    last_id: i32,
+    records: BTreeMap<i32, DownloadRecord>,
+    downloads: BTreeMap<i32, DownloadContext>,
+    /// Every (feed, item) enqueued so far
+    feed_items: BTreeSet<(String, String)>,
+    url_versions: HashMap<WgUrl, UrlVersion>,
+}
+
+impl MemoryState {
+    /// Store `segments` as subdownloads of `record`, taking the url and file path from it
+    fn insert_segments(
+        &mut self,
+        record: &DownloadRecord,
+        segments: &[DownloadContext],
+    ) -> Vec<i32> {
+        let url = Arc::new(record.url.clone());
+        let file_path: Arc<Path> = Arc::from(record.file_path.clone());
+
+        segments
+            .iter()
+            .map(|segment| {
+                self.last_id += 1;
+                let id = self.last_id;
+                self.downloads.insert(
+                    id,
+                    DownloadContext {
+                        id,
+                        download_id: record.id,
+                        url: url.clone(),
+                        file_path: file_path.clone(),
+                        ..segment.clone()
+                    },
+                );
+
+                id
+            })
+            .collect()
+    }
+
+    fn remove_segments(&mut self, download_id: i32) {
+        self.downloads
+            .retain(|_, download| download.download_id != download_id);
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl DownloadStore for MemoryStore {
+    async fn create_download(
+        &self,
+        download: &DownloadRecord,
+        segments: &[DownloadContext],
+    ) -> Result<(i32, Vec<i32>), StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        state.last_download_id += 1;
+        let record = DownloadRecord {
+            id: state.last_download_id,
+            ..download.clone()
+        };
+        let ids = state.insert_segments(&record, segments);
+        state.records.insert(record.id, record.clone());
+
+        Ok((record.id, ids))
+    }
+
+    async fn create_downloads(
+        &self,
+        downloads: &[(DownloadRecord, Vec<DownloadContext>)],
+    ) -> Result<Vec<i32>, StoreError> {
+        // creating a download in memory can't fail, so this is all or nothing as well
+        let mut ids = vec![];
+        for (download, segments) in downloads {
+            ids.push(self.create_download(download, segments).await?.0);
+        }
+
+        Ok(ids)
+    }
+
+    async fn add_segments(
+        &self,
+        download_id: i32,
+        total_size: u64,
+        segments: &[DownloadContext],
+    ) -> Result<Vec<i32>, StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut record = state
+            .records
+            .get(&download_id)
+            .cloned()
+            .ok_or(StoreError::NotFound)?;
+        record.transition_to(DownloadState::Running, None)?;
+        record.total_size = Some(total_size);
+
+        let ids = state.insert_segments(&record, segments);
+        state.records.insert(download_id, record);
+
+        Ok(ids)
+    }
+
+    async fn set_state(
+        &self,
+        download_id: i32,
+        download_state: DownloadState,
+        error: Option<String>,
+    ) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        let record = state
+            .records
+            .get_mut(&download_id)
+            .ok_or(StoreError::NotFound)?;
+        record.transition_to(download_state, error)?;
+
+        if matches!(
+            download_state,
+            DownloadState::Completed | DownloadState::Cancelled
+        ) {
+            state.remove_segments(download_id);
+        }
+
+        Ok(())
+    }
+
+    async fn download(&self, download_id: i32) -> Result<DownloadRecord, StoreError> {
+        self.state
+            .lock()
+            .unwrap()
+            .records
+            .get(&download_id)
+            .cloned()
+            .ok_or(StoreError::NotFound)
+    }
+
+    async fn downloads(&self) -> Result<Vec<DownloadRecord>, StoreError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .records
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn queue(&self) -> Result<Vec<DownloadRecord>, StoreError> {
+        let mut queue: Vec<_> = self
+            .state
+            .lock()
+            .unwrap()
+            .records
+            .values()
+            .filter(|record| record.state == DownloadState::Queued)
+            .cloned()
+            .collect();
+        queue.sort_by(DownloadRecord::queue_order);
+
+        Ok(queue)
+    }
+
+    async fn set_priorities(&self, priorities: &[(i32, i32)]) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        if priorities
+            .iter()
+            .any(|(download_id, _)| !state.records.contains_key(download_id))
+        {
+            return Err(StoreError::NotFound);
+        }
+
+        for (download_id, priority) in priorities {
+            if let Some(record) = state.records.get_mut(download_id) {
+                record.priority = *priority;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn segments(&self, download_id: i32) -> Result<Vec<DownloadContext>, StoreError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .downloads
+            .values()
+            .filter(|download| download.download_id == download_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn update_download(&self, download: &DownloadContext) -> Result<(), StoreError> {
+        self.update_downloads(std::slice::from_ref(download)).await
+    }
+
+    async fn update_downloads(&self, downloads: &[DownloadContext]) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        for download in downloads {
+            if let Some(stored) = state.downloads.get_mut(&download.id) {
+                stored.offset = download.offset;
+                stored.total = download.total;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn downloads_by_url(&self, wg_url: &WgUrl) -> Result<Vec<DownloadContext>, StoreError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .downloads
+            .values()
+            .filter(|download| *download.url == *wg_url)
+            .cloned()
+            .collect())
+    }
+
+    async fn all_downloads(&self) -> Result<Vec<DownloadContext>, StoreError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .downloads
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn remove_by_url(&self, wg_url: &WgUrl) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        state.records.retain(|_, record| record.url != *wg_url);
+        state
+            .downloads
+            .retain(|_, download| *download.url != *wg_url);
+
+        Ok(())
+    }
+
+    async fn remove_by_id(&self, id: i32) -> Result<(), StoreError> {
+        self.state.lock().unwrap().downloads.remove(&id);
+
+        Ok(())
+    }
+
+    async fn remove_download(&self, download_id: i32) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        state.records.remove(&download_id);
+        state.remove_segments(download_id);
+
+        Ok(())
+    }
+
+    async fn set_checksum(&self, download_id: i32, checksum: String) -> Result<(), StoreError> {
+        self.state
+            .lock()
+            .unwrap()
+            .records
+            .get_mut(&download_id)
+            .ok_or(StoreError::NotFound)?
+            .checksum = Some(checksum);
+
+        Ok(())
+    }
+
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<DownloadRecord>, StoreError> {
+        let state = self.state.lock().unwrap();
+
+        Ok(history_of(&state)
+            .filter(|record| filter.matches(record))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    async fn prune_history(
+        &self,
+        finished_before: Option<u64>,
+        keep: Option<usize>,
+    ) -> Result<usize, StoreError> {
+        let mut state = self.state.lock().unwrap();
+
+        let pruned: Vec<_> = history_of(&state)
+            .enumerate()
+            .filter(|(position, record)| {
+                let finished_at = record.completed_at.unwrap_or_default();
+                is_pruned(*position, finished_at, finished_before, keep)
+            })
+            .map(|(_, record)| record.id)
+            .collect();
+
+        for download_id in &pruned {
+            state.records.remove(download_id);
+            state.remove_segments(*download_id);
+        }
+
+        Ok(pruned.len())
+    }
+
+    async fn add_feed_item(&self, feed: &str, item: &str) -> Result<(), StoreError> {
+        self.state
+            .lock()
+            .unwrap()
+            .feed_items
+            .insert((feed.to_string(), item.to_string()));
+
+        Ok(())
+    }
+
+    async fn has_feed_item(&self, feed: &str, item: &str) -> Result<bool, StoreError> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .feed_items
+            .contains(&(feed.to_string(), item.to_string())))
+    }
+
+    async fn url_version(&self, wg_url: &WgUrl) -> Result<Option<UrlVersion>, StoreError> {
+        Ok(self.state.lock().unwrap().url_versions.get(wg_url).cloned())
+    }
+
+    async fn set_url_version(
+        &self,
+        wg_url: &WgUrl,
+        version: &UrlVersion,
+    ) -> Result<(), StoreError> {
+        self.state
+            .lock()
+            .unwrap()
+            .url_versions
+            .insert(wg_url.clone(), version.clone());
+
+        Ok(())
+    }
+}
+
+/// Every finished download, the most recent first
+fn history_of(state: &MemoryState) -> impl Iterator<Item = &DownloadRecord> {
+    let everything = HistoryFilter::default();
+    let mut history: Vec<_> = state
+        .records
+        .values()
+        .filter(|record| everything.matches(record))
+        .collect();
+    history.sort_by(|a, b| b.completed_at.cmp(&a.completed_at).then(b.id.cmp(&a.id)));
+
+    history.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::download_store_tests;
+
+    download_store_tests!(MemoryStore::new());
+}
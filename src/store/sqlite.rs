@@ -0,0 +1,996 @@
+use super::{DownloadRecord, DownloadState, DownloadStore, StoreError};
+use crate::{
+    history::{is_pruned, HistoryFilter},
+    http::DownloadContext,
+    request::monitor::UrlVersion,
+    schema::*,
+    util::{instr, last_insert_rowid, EnableForeignKeys},
+};
+use ::url::Url as WgUrl;
+use async_trait::async_trait;
+use diesel::{
+    r2d2,
+    r2d2::{ConnectionManager, Pool},
+    ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl, SqliteConnection,
+};
+use std::{
+    error::Error,
+    fmt::{Debug, Display, Formatter},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+embed_migrations!("migrations");
+
+#[derive(Debug, Queryable, Insertable, Identifiable, Associations)]
+#[table_name = "file_path"]
+#[primary_key(path)]
+pub(crate) struct PathTable {
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct PathConversionError {
+    invalid_path: bool,
+    not_file: bool,
+}
+
+impl Display for PathConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let path_err = if self.invalid_path {
+            "Invalid path"
+        } else {
+            " "
+        };
+        let file_err = if self.not_file { "Not a file" } else { " " };
+        let err_msg = [path_err, file_err].join(" and ");
+        write!(f, "{}", err_msg.trim())
+    }
+}
+
+impl Error for PathConversionError {}
+
+impl TryInto<PathBuf> for PathTable {
+    type Error = PathConversionError;
+
+    fn try_into(self) -> Result<PathBuf, Self::Error> {
+        let mut path = PathBuf::new();
+        path.push(self.path);
+
+        let conversion_err = PathConversionError {
+            invalid_path: !path.exists(),
+            not_file: !path.is_file(),
+        };
+
+        if !conversion_err.not_file && !conversion_err.invalid_path {
+            Ok(path)
+        } else {
+            Err(conversion_err)
+        }
+    }
+}
+
+impl From<&PathBuf> for PathTable {
+    fn from(path: &PathBuf) -> Self {
+        PathTable {
+            path: path.to_str().unwrap().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Insertable, Identifiable, Associations)]
+#[table_name = "url"]
+#[primary_key(full_text)]
+pub(crate) struct UrlTable {
+    pub full_text: String,
+}
+
+impl From<&WgUrl> for UrlTable {
+    fn from(url: &WgUrl) -> Self {
+        UrlTable {
+            full_text: url.to_string(),
+        }
+    }
+}
+
+impl TryInto<WgUrl> for UrlTable {
+    type Error = ::url::ParseError;
+
+    fn try_into(self) -> Result<WgUrl, Self::Error> {
+        WgUrl::parse(&self.full_text)
+    }
+}
+
+#[derive(Debug, Queryable)]
+pub(crate) struct DownloadTable {
+    pub id: i32,
+    pub url: String,
+    pub file_path: String,
+    pub state: String,
+    pub total_size: Option<i64>,
+    pub created_at: i64,
+    pub completed_at: Option<i64>,
+    pub error: Option<String>,
+    pub options: String,
+    pub priority: i32,
+    pub started_at: Option<i64>,
+    pub checksum: Option<String>,
+    pub origin: Option<String>,
+}
+
+impl TryFrom<DownloadTable> for DownloadRecord {
+    type Error = StoreError;
+
+    fn try_from(row: DownloadTable) -> Result<Self, Self::Error> {
+        let options = serde_json::from_str(&row.options).map_err(|e| {
+            StoreError::Corrupted(format!("invalid options {}: {}", row.options, e))
+        })?;
+
+        Ok(DownloadRecord {
+            id: row.id,
+            url: parse_url(&row.url)?,
+            file_path: PathBuf::from(row.file_path),
+            state: row.state.parse()?,
+            total_size: row.total_size.map(from_sql_integer).transpose()?,
+            created_at: from_sql_integer(row.created_at)?,
+            completed_at: row.completed_at.map(from_sql_integer).transpose()?,
+            error: row.error,
+            options,
+            priority: row.priority,
+            started_at: row.started_at.map(from_sql_integer).transpose()?,
+            checksum: row.checksum,
+            origin: row.origin,
+        })
+    }
+}
+
+#[derive(Debug, Queryable, Identifiable, AsChangeset)]
+#[table_name = "http_subdownload"]
+pub(crate) struct SubDownloadTable {
+    pub id: i32,
+    pub download_id: i32,
+    pub offset: i64,
+    pub total: i64,
+}
+
+impl TryFrom<&DownloadContext> for SubDownloadTable {
+    type Error = StoreError;
+
+    fn try_from(download: &DownloadContext) -> Result<Self, Self::Error> {
+        Ok(SubDownloadTable {
+            id: download.id,
+            download_id: download.download_id,
+            offset: to_sql_integer(download.offset)?,
+            total: to_sql_integer(download.total)?,
+        })
+    }
+}
+
+/// A subdownload together with the url and file path of the download it belongs to
+#[derive(Debug, Queryable)]
+pub(crate) struct SegmentRow {
+    pub id: i32,
+    pub download_id: i32,
+    pub offset: i64,
+    pub total: i64,
+    pub url: String,
+    pub file_path: String,
+}
+
+impl TryFrom<SegmentRow> for DownloadContext {
+    type Error = StoreError;
+
+    fn try_from(row: SegmentRow) -> Result<Self, Self::Error> {
+        Ok(DownloadContext {
+            id: row.id,
+            download_id: row.download_id,
+            url: Arc::new(parse_url(&row.url)?),
+            offset: from_sql_integer(row.offset)?,
+            total: from_sql_integer(row.total)?,
+            file_path: Arc::from(PathBuf::from(row.file_path)),
+        })
+    }
+}
+
+fn parse_url(text: &str) -> Result<WgUrl, StoreError> {
+    WgUrl::parse(text).map_err(|e| StoreError::Corrupted(format!("invalid url {}: {}", text, e)))
+}
+
+fn from_sql_integer(value: i64) -> Result<u64, StoreError> {
+    u64::try_from(value)
+        .map_err(|_| StoreError::Corrupted(format!("negative offset or size {}", value)))
+}
+
+/// SQLite integers are signed, refuse anything that would come back as a different number rather
+/// than wrapping around
+fn to_sql_integer(value: u64) -> Result<i64, StoreError> {
+    i64::try_from(value)
+        .map_err(|_| StoreError::Unsupported(format!("{} doesn't fit in an SQLite integer", value)))
+}
+
+/// SQLite only stores text, so a path that isn't valid unicode can't be kept
+fn path_text(path: &Path) -> Result<String, StoreError> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| StoreError::Unsupported(format!("{:?} is not valid unicode", path)))
+}
+
+pub struct SqliteStore {
+    pool: Pool<ConnectionManager<SqliteConnection>>,
+}
+
+#[allow(unused_variables)]
+impl Debug for SqliteStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FIXED FFS")
+    }
+}
+
+impl SqliteStore {
+    pub fn new(database_url: &str) -> Result<Self, r2d2::PoolError> {
+        Self::with_max_connections(database_url, 32)
+    }
+
+    /// A store that lives only as long as it does. Every connection to `:memory:` is a database of
+    /// its own, so the pool holds exactly one.
+    pub fn in_memory() -> Result<Self, r2d2::PoolError> {
+        Self::with_max_connections(":memory:", 1)
+    }
+
+    /// The store in the database at `database_url`, created if there isn't one yet, with its
+    /// schema brought up to date
+    pub fn open(database_url: &str) -> Result<Self, StoreError> {
+        let store = Self::new(database_url)?;
+        store.migrate()?;
+
+        Ok(store)
+    }
+
+    /// Run the migrations the database hasn't seen yet
+    pub fn migrate(&self) -> Result<(), StoreError> {
+        embedded_migrations::run(&self.pool.get()?)
+            .map_err(|e| StoreError::Migration(format!("{}", e)))
+    }
+
+    fn with_max_connections(database_url: &str, max_size: u32) -> Result<Self, r2d2::PoolError> {
+        let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+        let pool = Pool::builder()
+            .max_size(max_size)
+            .connection_customizer(Box::new(EnableForeignKeys::new()))
+            .build(manager)?;
+
+        Ok(Self { pool })
+    }
+
+    /// Run `f` with a connection from the pool on the blocking thread pool, so waiting for SQLite
+    /// never holds up the async tasks calling the store
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SqliteConnection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            f(&conn)
+        })
+        .await?
+    }
+}
+
+/// How many ids go into a single `IN (...)`, older SQLite builds bind at most 999 parameters
+const MAX_BOUND_IDS: usize = 500;
+
+/// What a `SegmentRow` is selected from
+const SEGMENT_COLUMNS: (
+    http_subdownload::id,
+    http_subdownload::download_id,
+    http_subdownload::offset,
+    http_subdownload::total,
+    http_download::url,
+    http_download::file_path,
+) = (
+    http_subdownload::id,
+    http_subdownload::download_id,
+    http_subdownload::offset,
+    http_subdownload::total,
+    http_download::url,
+    http_download::file_path,
+);
+
+/// Offset and total of every segment, ready to be written
+fn segment_ranges(segments: &[DownloadContext]) -> Result<Vec<(i64, i64)>, StoreError> {
+    segments
+        .iter()
+        .map(|segment| {
+            Ok((
+                to_sql_integer(segment.offset)?,
+                to_sql_integer(segment.total)?,
+            ))
+        })
+        .collect()
+}
+
+fn insert_segments(
+    conn: &SqliteConnection,
+    download: i32,
+    ranges: &[(i64, i64)],
+) -> Result<Vec<i32>, StoreError> {
+    use crate::schema::http_subdownload::dsl::*;
+
+    ranges
+        .iter()
+        .map(|(segment_offset, segment_total)| {
+            diesel::insert_into(http_subdownload)
+                .values((
+                    download_id.eq(download),
+                    offset.eq(segment_offset),
+                    total.eq(segment_total),
+                ))
+                .execute(conn)?;
+
+            Ok(diesel::select(last_insert_rowid).first(conn)?)
+        })
+        .collect()
+}
+
+/// Add `download` along with its subdownloads, returning their ids. Meant to run in a transaction.
+fn insert_download(
+    conn: &SqliteConnection,
+    download: &DownloadRecord,
+    segments: &[DownloadContext],
+) -> Result<(i32, Vec<i32>), StoreError> {
+    let download_url = download.url.to_string();
+    let download_path = path_text(&download.file_path)?;
+    let size = download.total_size.map(to_sql_integer).transpose()?;
+    let created = to_sql_integer(download.created_at)?;
+    let completed = download.completed_at.map(to_sql_integer).transpose()?;
+    let download_options = serde_json::to_string(&download.options)
+        .map_err(|e| StoreError::Unsupported(format!("{}", e)))?;
+    let started = download.started_at.map(to_sql_integer).transpose()?;
+    let ranges = segment_ranges(segments)?;
+
+    // the url and the file path may already be known through other downloads, the download only
+    // needs them to exist
+    diesel::insert_or_ignore_into(crate::schema::url::dsl::url)
+        .values(UrlTable {
+            full_text: download_url.clone(),
+        })
+        .execute(conn)?;
+    diesel::insert_or_ignore_into(crate::schema::file_path::dsl::file_path)
+        .values(PathTable {
+            path: download_path.clone(),
+        })
+        .execute(conn)?;
+
+    let download_id = {
+        use crate::schema::http_download::dsl::*;
+
+        diesel::insert_into(http_download)
+            .values((
+                url.eq(&download_url),
+                file_path.eq(&download_path),
+                state.eq(download.state.as_str()),
+                total_size.eq(size),
+                created_at.eq(created),
+                completed_at.eq(completed),
+                error.eq(&download.error),
+                options.eq(&download_options),
+                priority.eq(download.priority),
+                started_at.eq(started),
+                checksum.eq(&download.checksum),
+                origin.eq(&download.origin),
+            ))
+            .execute(conn)?;
+
+        diesel::select(last_insert_rowid).first(conn)?
+    };
+
+    let ids = insert_segments(conn, download_id, &ranges)?;
+
+    Ok((download_id, ids))
+}
+
+fn load_record(conn: &SqliteConnection, download_id: i32) -> Result<DownloadRecord, StoreError> {
+    http_download::table
+        .find(download_id)
+        .first::<DownloadTable>(conn)?
+        .try_into()
+}
+
+/// Write the state of `record` back, along with what changes with it
+fn save_state(conn: &SqliteConnection, record: &DownloadRecord) -> Result<(), StoreError> {
+    use crate::schema::http_download::dsl::*;
+
+    let finished_at = record.completed_at.map(to_sql_integer).transpose()?;
+    let size = record.total_size.map(to_sql_integer).transpose()?;
+    let started = record.started_at.map(to_sql_integer).transpose()?;
+
+    diesel::update(http_download.find(record.id))
+        .set((
+            state.eq(record.state.as_str()),
+            error.eq(&record.error),
+            completed_at.eq(finished_at),
+            total_size.eq(size),
+            started_at.eq(started),
+        ))
+        .execute(conn)?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl DownloadStore for SqliteStore {
+    async fn create_download(
+        &self,
+        download: &DownloadRecord,
+        segments: &[DownloadContext],
+    ) -> Result<(i32, Vec<i32>), StoreError> {
+        let download = download.clone();
+        let segments = segments.to_vec();
+
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| insert_download(conn, &download, &segments))
+        })
+        .await
+    }
+
+    async fn create_downloads(
+        &self,
+        downloads: &[(DownloadRecord, Vec<DownloadContext>)],
+    ) -> Result<Vec<i32>, StoreError> {
+        let downloads = downloads.to_vec();
+
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                downloads
+                    .iter()
+                    .map(|(download, segments)| Ok(insert_download(conn, download, segments)?.0))
+                    .collect()
+            })
+        })
+        .await
+    }
+
+    async fn add_segments(
+        &self,
+        download_id: i32,
+        total_size: u64,
+        segments: &[DownloadContext],
+    ) -> Result<Vec<i32>, StoreError> {
+        let ranges = segment_ranges(segments)?;
+        to_sql_integer(total_size)?;
+
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                let mut record = load_record(conn, download_id)?;
+                record.transition_to(DownloadState::Running, None)?;
+                record.total_size = Some(total_size);
+                save_state(conn, &record)?;
+
+                insert_segments(conn, download_id, &ranges)
+            })
+        })
+        .await
+    }
+
+    async fn set_state(
+        &self,
+        download_id: i32,
+        state: DownloadState,
+        error: Option<String>,
+    ) -> Result<(), StoreError> {
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                let mut record = load_record(conn, download_id)?;
+                record.transition_to(state, error)?;
+                save_state(conn, &record)?;
+
+                if matches!(state, DownloadState::Completed | DownloadState::Cancelled) {
+                    diesel::delete(
+                        http_subdownload::table
+                            .filter(http_subdownload::download_id.eq(download_id)),
+                    )
+                    .execute(conn)?;
+                }
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn download(&self, download_id: i32) -> Result<DownloadRecord, StoreError> {
+        self.with_connection(move |conn| load_record(conn, download_id))
+            .await
+    }
+
+    async fn downloads(&self) -> Result<Vec<DownloadRecord>, StoreError> {
+        self.with_connection(|conn| {
+            http_download::table
+                .load::<DownloadTable>(conn)?
+                .into_iter()
+                .map(DownloadRecord::try_from)
+                .collect()
+        })
+        .await
+    }
+
+    async fn queue(&self) -> Result<Vec<DownloadRecord>, StoreError> {
+        self.with_connection(|conn| {
+            use crate::schema::http_download::dsl::*;
+
+            http_download
+                .filter(state.eq(DownloadState::Queued.as_str()))
+                .order((priority.desc(), created_at.asc(), id.asc()))
+                .load::<DownloadTable>(conn)?
+                .into_iter()
+                .map(DownloadRecord::try_from)
+                .collect()
+        })
+        .await
+    }
+
+    async fn set_priorities(&self, priorities: &[(i32, i32)]) -> Result<(), StoreError> {
+        let priorities = priorities.to_vec();
+
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                use crate::schema::http_download::dsl::*;
+
+                for (download_id, download_priority) in priorities {
+                    let updated = diesel::update(http_download.find(download_id))
+                        .set(priority.eq(download_priority))
+                        .execute(conn)?;
+
+                    if updated == 0 {
+                        return Err(StoreError::NotFound);
+                    }
+                }
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn segments(&self, download_id: i32) -> Result<Vec<DownloadContext>, StoreError> {
+        self.with_connection(move |conn| {
+            http_subdownload::table
+                .inner_join(http_download::table)
+                .filter(http_subdownload::download_id.eq(download_id))
+                .select(SEGMENT_COLUMNS)
+                .load::<SegmentRow>(conn)?
+                .into_iter()
+                .map(DownloadContext::try_from)
+                .collect()
+        })
+        .await
+    }
+
+    async fn update_download(&self, download: &DownloadContext) -> Result<(), StoreError> {
+        // construct the table equivalent of download (the subdownload struct itself cannot be
+        // directly used with diesel, and update it
+        let sql_form = SubDownloadTable::try_from(download)?;
+
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                use crate::schema::http_subdownload::dsl::*;
+                diesel::update(http_subdownload.find(sql_form.id))
+                    .set(&sql_form)
+                    .execute(conn)?;
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn update_downloads(&self, downloads: &[DownloadContext]) -> Result<(), StoreError> {
+        let progress = downloads
+            .iter()
+            .map(|download| {
+                Ok((
+                    download.id,
+                    to_sql_integer(download.offset)?,
+                    to_sql_integer(download.total)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                use crate::schema::http_subdownload::dsl::*;
+
+                for (segment_id, download_offset, download_total) in progress {
+                    diesel::update(http_subdownload.find(segment_id))
+                        .set((offset.eq(download_offset), total.eq(download_total)))
+                        .execute(conn)?;
+                }
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn downloads_by_url(&self, wg_url: &WgUrl) -> Result<Vec<DownloadContext>, StoreError> {
+        let wg_url = wg_url.to_string();
+
+        self.with_connection(move |conn| {
+            http_subdownload::table
+                .inner_join(http_download::table)
+                .filter(http_download::url.eq(wg_url))
+                .select(SEGMENT_COLUMNS)
+                .load::<SegmentRow>(conn)?
+                .into_iter()
+                .map(DownloadContext::try_from)
+                .collect()
+        })
+        .await
+    }
+
+    async fn all_downloads(&self) -> Result<Vec<DownloadContext>, StoreError> {
+        self.with_connection(|conn| {
+            http_subdownload::table
+                .inner_join(http_download::table)
+                .select(SEGMENT_COLUMNS)
+                .load::<SegmentRow>(conn)?
+                .into_iter()
+                .map(DownloadContext::try_from)
+                .collect()
+        })
+        .await
+    }
+
+    async fn remove_by_url(&self, wg_url: &WgUrl) -> Result<(), StoreError> {
+        let wg_url = wg_url.to_string();
+
+        self.with_connection(move |conn| {
+            use crate::schema::http_download::dsl::*;
+
+            // delete cascade takes the subdownloads with it, and the trigger cleans up the url and
+            // file path once nothing refers to them
+            let matching_rows = http_download.filter(url.eq(wg_url));
+            conn.exclusive_transaction(|| {
+                diesel::delete(matching_rows).execute(conn)?;
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn remove_by_id(&self, id: i32) -> Result<(), StoreError> {
+        let identification = id;
+
+        self.with_connection(move |conn| {
+            use crate::schema::http_subdownload::dsl::*;
+
+            // delete the subdownload by id
+            let matching_rows = http_subdownload.filter(id.eq(identification));
+            conn.exclusive_transaction(|| {
+                diesel::delete(matching_rows).execute(conn)?;
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn remove_download(&self, download_id: i32) -> Result<(), StoreError> {
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                diesel::delete(http_download::table.find(download_id)).execute(conn)?;
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn set_checksum(&self, download_id: i32, checksum: String) -> Result<(), StoreError> {
+        self.with_connection(move |conn| {
+            let updated = diesel::update(http_download::table.find(download_id))
+                .set(http_download::checksum.eq(checksum))
+                .execute(conn)?;
+
+            match updated {
+                0 => Err(StoreError::NotFound),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<DownloadRecord>, StoreError> {
+        let filter = filter.clone();
+        let since = filter.since.map(to_sql_integer).transpose()?;
+        let until = filter.until.map(to_sql_integer).transpose()?;
+        let limit = filter
+            .limit
+            .map(|limit| i64::try_from(limit).unwrap_or(i64::MAX));
+
+        self.with_connection(move |conn| {
+            use crate::schema::http_download::dsl::*;
+
+            let states: Vec<_> = HistoryFilter::STATES
+                .iter()
+                .filter(|finished| filter.state.map_or(true, |wanted| wanted == **finished))
+                .map(DownloadState::as_str)
+                .collect();
+
+            let mut query = http_download
+                .filter(state.eq_any(states))
+                .filter(completed_at.is_not_null())
+                .order((completed_at.desc(), id.desc()))
+                .into_boxed();
+            if let Some(part) = filter.url {
+                query = query.filter(instr(url, part).gt(0));
+            }
+            if let Some(since) = since {
+                query = query.filter(completed_at.ge(since));
+            }
+            if let Some(until) = until {
+                query = query.filter(completed_at.lt(until));
+            }
+            if let Some(limit) = limit {
+                query = query.limit(limit);
+            }
+
+            query
+                .load::<DownloadTable>(conn)?
+                .into_iter()
+                .map(DownloadRecord::try_from)
+                .collect()
+        })
+        .await
+    }
+
+    async fn prune_history(
+        &self,
+        finished_before: Option<u64>,
+        keep: Option<usize>,
+    ) -> Result<usize, StoreError> {
+        self.with_connection(move |conn| {
+            conn.exclusive_transaction(|| {
+                use crate::schema::http_download::dsl::*;
+
+                let states: Vec<_> = HistoryFilter::STATES
+                    .iter()
+                    .map(DownloadState::as_str)
+                    .collect();
+                let history = http_download
+                    .filter(state.eq_any(states))
+                    .filter(completed_at.is_not_null())
+                    .order((completed_at.desc(), id.desc()))
+                    .select((id, completed_at))
+                    .load::<(i32, Option<i64>)>(conn)?;
+
+                let mut pruned = vec![];
+                for (position, (download_id, finished_at)) in history.into_iter().enumerate() {
+                    let finished_at = from_sql_integer(finished_at.unwrap_or_default())?;
+                    if is_pruned(position, finished_at, finished_before, keep) {
+                        pruned.push(download_id);
+                    }
+                }
+
+                // the subdownloads of failed downloads go with them, and the trigger cleans up
+                // the url and file path
+                let mut deleted = 0;
+                for batch in pruned.chunks(MAX_BOUND_IDS) {
+                    deleted +=
+                        diesel::delete(http_download.filter(id.eq_any(batch))).execute(conn)?;
+                }
+
+                Ok(deleted)
+            })
+        })
+        .await
+    }
+
+    async fn add_feed_item(&self, feed: &str, item: &str) -> Result<(), StoreError> {
+        let feed_url = feed.to_string();
+        let feed_item_id = item.to_string();
+        let now = to_sql_integer(super::unix_now())?;
+
+        self.with_connection(move |conn| {
+            use crate::schema::feed_item::dsl::*;
+
+            diesel::insert_or_ignore_into(feed_item)
+                .values((feed.eq(feed_url), item.eq(feed_item_id), added_at.eq(now)))
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn has_feed_item(&self, feed: &str, item: &str) -> Result<bool, StoreError> {
+        let feed_url = feed.to_string();
+        let feed_item_id = item.to_string();
+
+        self.with_connection(move |conn| {
+            use crate::schema::feed_item::dsl::*;
+
+            let count: i64 = feed_item
+                .filter(feed.eq(feed_url))
+                .filter(item.eq(feed_item_id))
+                .count()
+                .get_result(conn)?;
+
+            Ok(count > 0)
+        })
+        .await
+    }
+
+    async fn url_version(&self, wg_url: &WgUrl) -> Result<Option<UrlVersion>, StoreError> {
+        let wg_url = wg_url.to_string();
+
+        self.with_connection(move |conn| {
+            use crate::schema::monitored_url::dsl::*;
+
+            let version = monitored_url
+                .find(wg_url)
+                .select((etag, last_modified, length))
+                .first::<(Option<String>, Option<String>, Option<i64>)>(conn)
+                .optional()?;
+
+            version
+                .map(|(version_etag, version_last_modified, version_length)| {
+                    Ok(UrlVersion {
+                        etag: version_etag,
+                        last_modified: version_last_modified,
+                        length: version_length.map(from_sql_integer).transpose()?,
+                    })
+                })
+                .transpose()
+        })
+        .await
+    }
+
+    async fn set_url_version(
+        &self,
+        wg_url: &WgUrl,
+        version: &UrlVersion,
+    ) -> Result<(), StoreError> {
+        let wg_url = wg_url.to_string();
+        let version_length = version.length.map(to_sql_integer).transpose()?;
+        let version = version.clone();
+
+        self.with_connection(move |conn| {
+            use crate::schema::monitored_url::dsl::*;
+
+            diesel::replace_into(monitored_url)
+                .values((
+                    url.eq(wg_url),
+                    etag.eq(version.etag),
+                    last_modified.eq(version.last_modified),
+                    length.eq(version_length),
+                ))
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::store::tests::{download_store_tests, record, running};
+
+    pub(crate) fn init_db() -> color_eyre::Result<SqliteStore> {
+        let store = SqliteStore::in_memory()?;
+        store.migrate()?;
+
+        Ok(store)
+    }
+
+    download_store_tests!(init_db()?);
+
+    #[tokio::test]
+    async fn opening_a_new_database_creates_its_schema() -> color_eyre::Result<()> {
+        let path = std::env::temp_dir().join("sulfur-open-test.sqlite");
+        let _ = std::fs::remove_file(&path);
+
+        let store = SqliteStore::open(&path.to_string_lossy())?;
+        let created = record("https://www.example.com/a.iso", DownloadState::Queued);
+        let (id, _) = store.create_download(&created, &[]).await?;
+        drop(store);
+
+        // the second time around there's nothing left to migrate and nothing is lost
+        let store = SqliteStore::open(&path.to_string_lossy())?;
+        assert_eq!(store.download(id).await?.url, created.url);
+
+        drop(store);
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pruning_more_history_than_sqlite_binds_at_once() -> color_eyre::Result<()> {
+        let store = init_db()?;
+        let finished = DownloadRecord {
+            completed_at: Some(100),
+            ..record("https://www.example.com/a.iso", DownloadState::Completed)
+        };
+        store.create_download(&finished, &[]).await?;
+
+        // past the 999 parameters older SQLite builds bind at most
+        store
+            .with_connection(|conn| {
+                diesel::sql_query(
+                    "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 1500)
+                     INSERT INTO http_download
+                         (url, file_path, state, total_size, created_at, completed_at, options,
+                          priority)
+                     SELECT url, file_path, state, total_size, created_at, completed_at, options,
+                         priority
+                     FROM http_download, n",
+                )
+                .execute(conn)?;
+                Ok(())
+            })
+            .await?;
+
+        assert_eq!(store.prune_history(None, Some(0)).await?, 1501);
+        assert!(store.downloads().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_remove_by_url_cleans_itself() -> color_eyre::Result<()> {
+        let _ = color_eyre::install();
+
+        let store = init_db()?;
+
+        let url = WgUrl::parse("https://www.google.com")?;
+
+        running(&store, url.as_str(), &[(0, 5000)]).await?;
+        running(&store, url.as_str(), &[(0, 5000)]).await?;
+        store.remove_by_url(&url).await?;
+        {
+            use crate::schema::http_subdownload::dsl::*;
+
+            let result = http_subdownload.load::<SubDownloadTable>(&store.pool.get()?);
+            assert!(result.is_ok());
+            assert!(result.unwrap().is_empty());
+
+            let result = http_download::table.load::<DownloadTable>(&store.pool.get()?);
+            assert!(result.is_ok());
+            assert!(result.unwrap().is_empty());
+
+            let result = crate::schema::url::dsl::url.load::<UrlTable>(&store.pool.get()?);
+            assert!(result.is_ok());
+            assert!(result.unwrap().is_empty());
+        }
+
+        {
+            use crate::schema::file_path::dsl::*;
+
+            let result = file_path.load::<PathTable>(&store.pool.get()?);
+            assert!(result.is_ok());
+            assert!(result.unwrap().is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sqlite_store_shares_url_between_file_paths() -> color_eyre::Result<()> {
+        let store = init_db()?;
+
+        let first = record("https://www.google.com", DownloadState::Running);
+        let mut second = record("https://www.google.com", DownloadState::Running);
+        second.file_path = PathBuf::from("/tmp/other.txt");
+
+        store.create_download(&first, &[]).await?;
+        store.create_download(&second, &[]).await?;
+
+        assert_eq!(store.downloads().await?.len(), 2);
+
+        Ok(())
+    }
+}
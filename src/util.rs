@@ -7,6 +7,11 @@ pub struct Never {}
 
 no_arg_sql_function!(last_insert_rowid, diesel::sql_types::Integer);
 
+sql_function! {
+    /// Where `needle` starts in `haystack`, counting from 1, or 0 if it isn't there
+    fn instr(haystack: diesel::sql_types::Text, needle: diesel::sql_types::Text) -> diesel::sql_types::Integer;
+}
+
 
 #[derive(Debug)]
 pub struct EnableForeignKeys;
@@ -19,7 +24,11 @@ impl EnableForeignKeys {
 
 impl CustomizeConnection<SqliteConnection, r2d2::Error> for EnableForeignKeys {
     fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
-        conn.execute("PRAGMA foreign_keys = ON;").expect("Failed to enable foreign keys");
+        conn.execute("PRAGMA foreign_keys = ON;")
+            .map_err(r2d2::Error::QueryError)?;
+        // the API and the downloads write at the same time, wait for each other rather than fail
+        conn.execute("PRAGMA busy_timeout = 5000;")
+            .map_err(r2d2::Error::QueryError)?;
         Ok(())
     }
 }